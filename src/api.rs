@@ -0,0 +1,229 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::backend;
+use super::jobs::Jobs;
+use super::prune;
+use super::web;
+use chrono::{Local, TimeZone};
+use serde_json::{json, Value};
+use std::io::Read;
+use tiny_http::{Method, Request, Response, ResponseBox};
+
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+const DEFAULT_API_MESSAGE: &str = "Created through the API";
+
+type ApiResult = Result<(u16, Value), (u16, String)>;
+
+/// Answers a request below /api/, `path` being the rest of the url
+pub fn handle(conf: &Config, jobs: &Jobs, request: &mut Request, path: &str) -> ResponseBox {
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+    let result = match (request.method(), components.as_slice()) {
+        (Method::Get, ["commits"]) => Commit::try_get_commits(conf)
+            .map(|commits| (200, Value::Array(commits.iter().rev().map(commit_summary).collect())))
+            .map_err(|message| (500, message)),
+        (Method::Get, ["commits", name]) => commit_details(conf, name),
+        (Method::Get, ["status"]) => status(conf).map(|status| (200, status)).map_err(|message| (500, message)),
+        (Method::Get, ["jobs"]) => Ok((200, json!(jobs.get_jobs()))),
+        (Method::Post, ["jobs", "create"]) => start_create(conf, jobs, request),
+        (Method::Get, ["jobs", id]) => {
+            let job = id.parse::<u64>().ok().and_then(|id| jobs.get(id));
+            match job {
+                Some(job) => Ok((200, json!(job))),
+                None => Err((404, format!("Cannot find job: {}", id))),
+            }
+        },
+        (Method::Get, ["prune-plan"]) => prune_plan(conf),
+        _ => Err((404, "Unknown API endpoint".to_string())),
+    };
+
+    let (code, value) = result.unwrap_or_else(|(code, message)| (code, json!({ "error": message })));
+    Response::from_string(value.to_string())
+        .with_status_code(code)
+        .with_header(web::header("Content-Type", "application/json"))
+        .boxed()
+}
+
+fn commit_summary(commit: &Commit) -> Value {
+    json!({
+        "name": commit.get_name(),
+        "date": Local.timestamp_opt(commit.get_timestamp(), 0).unwrap().to_rfc3339(),
+        "message": commit.get_message(),
+        "tags": commit.get_tags(),
+        "pinned": commit.is_pinned(),
+    })
+}
+
+fn commit_details(conf: &Config, name: &str) -> ApiResult {
+    let commit = match name {
+        "latest" => Commit::try_get_latest(conf),
+        _ => match Commit::try_find(conf, name) {
+            Ok(None) => Commit::try_find_by_tag(conf, name),
+            found => found,
+        },
+    };
+    let commit = commit.map_err(|message| (500, message))?.ok_or_else(|| (404, format!("Cannot find commit: {}", name)))?;
+
+    let mut details = commit_summary(&commit);
+    let metadata = commit.get_metadata();
+    details["format"] = json!(if commit.is_chunked() { "chunked" } else { "rsync" });
+    details["parent"] = json!(metadata.as_ref().and_then(|metadata| metadata.parent.clone()));
+    details["host"] = json!(metadata.map(|metadata| metadata.host));
+    details["stats"] = json!(commit.get_stats());
+    Ok((200, details))
+}
+
+/// Changes of the live backup directory since the latest commit
+fn status(conf: &Config) -> Result<Value, String> {
+    let changes: Vec<Value> = backend::try_get_changes(conf)?.iter()
+        .map(|change| json!({ "path": change.get_file_name(), "change": change.get_mod_string() }))
+        .collect();
+    Ok(json!({
+        "latest": Commit::try_get_latest(conf)?.map(|commit| commit.get_name()),
+        "clean": changes.is_empty(),
+        "changes": changes,
+    }))
+}
+
+/// What `prune` would keep and delete, newest first
+fn prune_plan(conf: &Config) -> ApiResult {
+    let retention = conf.get_retention().ok_or_else(|| (404, "No [retention] policy configured".to_string()))?;
+    let commits: Vec<Value> = prune::try_plan(conf).map_err(|message| (500, message))?.iter()
+        .map(|decision| {
+            let mut summary = commit_summary(&decision.commit);
+            summary["keep"] = json!(!decision.reasons.is_empty());
+            summary["reasons"] = json!(decision.reasons);
+            summary
+        })
+        .collect();
+    Ok((200, json!({
+        "retention": {
+            "keep_last": retention.keep_last,
+            "keep_daily": retention.keep_daily,
+            "keep_weekly": retention.keep_weekly,
+            "keep_monthly": retention.keep_monthly,
+        },
+        "commits": commits,
+    })))
+}
+
+fn start_create(conf: &Config, jobs: &Jobs, request: &mut Request) -> ApiResult {
+    let mut body = String::new();
+    if request.as_reader().take(MAX_BODY_SIZE).read_to_string(&mut body).is_err() {
+        return Err((400, "Unable to read request body".to_string()));
+    }
+
+    let message = if body.trim().is_empty() {
+        None
+    } else {
+        let body: Value = serde_json::from_str(&body).map_err(|err| (400, format!("Invalid json: {}", err)))?;
+        match body.get("message") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(message)) => Some(message.clone()),
+            Some(_) => return Err((400, "'message' must be a string".to_string())),
+        }
+    };
+
+    if jobs.is_busy() {
+        return Err((409, "A backup is already running".to_string()));
+    }
+    let message = message.unwrap_or_else(|| DEFAULT_API_MESSAGE.to_string());
+    let id = jobs.start_create(conf, &message).map_err(|message| (500, message))?;
+    Ok((202, json!({ "id": id, "url": format!("/api/jobs/{}", id) })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::chunks;
+    use super::super::commit_log::CommitLog;
+    use std::fs;
+    use tempdir::TempDir;
+    use tiny_http::TestRequest;
+
+    fn make_conf(dir: &TempDir, settings: &str) -> Config {
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join("repo").join("backups")).unwrap();
+        Config::from_toml(&format!(
+            "backup_dir = '{}'\nrepo_dir = '{}'\nformat = 'chunked'\nprogress = 'none'\n{}",
+            dir.path().join("src").display(), dir.path().join("repo").display(), settings,
+        ), &[])
+    }
+
+    fn call(conf: &Config, jobs: &Jobs, method: Method, path: &str, body: &'static str) -> (u16, Value) {
+        let mut request = TestRequest::new().with_method(method).with_path(&format!("/api/{}", path)).with_body(body).into();
+        let response = handle(conf, jobs, &mut request, path);
+        let code = response.status_code().0;
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body).unwrap();
+        (code, serde_json::from_str(&body).unwrap())
+    }
+
+    #[test]
+    fn create_needs_a_string_message() {
+        let dir = TempDir::new("api").unwrap();
+        let conf = make_conf(&dir, "");
+        let jobs = Jobs::default();
+
+        let (code, body) = call(&conf, &jobs, Method::Post, "jobs/create", r#"{"message": 5}"#);
+        assert_eq!(code, 400);
+        assert_eq!(body["error"], "'message' must be a string");
+        assert_eq!(call(&conf, &jobs, Method::Post, "jobs/create", "{").0, 400);
+        assert!(jobs.get_jobs().is_empty());
+    }
+
+    #[test]
+    fn create_is_refused_while_a_job_runs() {
+        let dir = TempDir::new("api").unwrap();
+        let conf = make_conf(&dir, "");
+        let jobs = Jobs::default();
+        let id = jobs.start_fake("first");
+
+        let (code, body) = call(&conf, &jobs, Method::Post, "jobs/create", r#"{"message": "second"}"#);
+        assert_eq!(code, 409);
+        assert_eq!(body["error"], "A backup is already running");
+        assert_eq!(jobs.get_jobs().len(), 1);
+
+        let (code, body) = call(&conf, &jobs, Method::Get, &format!("jobs/{}", id), "");
+        assert_eq!(code, 200);
+        assert_eq!(body["state"], "running");
+        assert_eq!(body["message"], "first");
+    }
+
+    #[test]
+    fn unknown_jobs_and_endpoints_are_not_found() {
+        let dir = TempDir::new("api").unwrap();
+        let conf = make_conf(&dir, "");
+        let jobs = Jobs::default();
+
+        assert_eq!(call(&conf, &jobs, Method::Get, "jobs/7", "").0, 404);
+        assert_eq!(call(&conf, &jobs, Method::Get, "jobs/seven", "").0, 404);
+        assert_eq!(call(&conf, &jobs, Method::Get, "commits/missing", "").0, 404);
+        assert_eq!(call(&conf, &jobs, Method::Get, "nothing/here", "").0, 404);
+        assert_eq!(call(&conf, &jobs, Method::Delete, "commits", "").0, 404);
+        assert_eq!(call(&conf, &jobs, Method::Get, "prune-plan", "").0, 404);
+    }
+
+    #[test]
+    fn prune_plan_follows_the_retention_policy() {
+        let dir = TempDir::new("api").unwrap();
+        let conf = make_conf(&dir, "[retention]\nkeep_last = 1");
+        for name in &["first", "second"] {
+            // Commits are ordered by their timestamp in seconds
+            if *name == "second" {
+                std::thread::sleep(std::time::Duration::from_millis(1100));
+            }
+            fs::write(dir.path().join("src").join("notes.txt"), name).unwrap();
+            let mut log = CommitLog::new(&conf, name);
+            chunks::make_commit(&conf, &dir.path().join("src"), name.to_string(), String::new(), &mut log, false).unwrap();
+        }
+
+        let (code, body) = call(&conf, &Jobs::default(), Method::Get, "prune-plan", "");
+        assert_eq!(code, 200);
+        assert_eq!(body["retention"]["keep_last"], 1);
+        let commits = body["commits"].as_array().unwrap();
+        let plan: Vec<(&str, bool)> = commits.iter()
+            .map(|commit| (commit["name"].as_str().unwrap(), commit["keep"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(plan, vec![("second", true), ("first", false)]);
+    }
+}
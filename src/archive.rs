@@ -0,0 +1,110 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::backend;
+use super::chunks;
+use super::rsync::DATA_FOLDER_NAME;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempdir::TempDir;
+
+const ZSTD_EXTENSIONS: &[&str] = &["zst", "tzst"];
+
+pub fn export(conf: &Config, commit: &Commit, path: String, output: &Path, verbose: bool) {
+    let member = path.trim_matches('/');
+    let member = if member.is_empty() { "." } else { member };
+
+    let temp_dir = make_staging_dir(conf, "resilient-export");
+
+    let source_dir = if commit.is_chunked() {
+        if let Err(message) = chunks::restore_to(conf, commit, path.clone(), temp_dir.path(), false) {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        temp_dir.path().to_path_buf()
+    } else {
+        commit.get_folder().join(DATA_FOLDER_NAME)
+    };
+
+    if !source_dir.join(member).exists() {
+        eprintln!("Cannot find path '{}' in commit: {}", path, commit.get_name());
+        std::process::exit(1);
+    }
+
+    let mut tar_command = Command::new("tar");
+    tar_command.arg("--create");
+    tar_command.arg("--xattrs");
+    tar_command.arg("--acls");
+    if verbose {
+        tar_command.arg("--verbose");
+    }
+    if is_zstd(output) {
+        tar_command.arg("--zstd");
+    }
+    tar_command.arg("--file").arg(output);
+    tar_command.arg("--directory").arg(&source_dir);
+    tar_command.arg(member);
+    run_tar(tar_command);
+}
+
+pub fn import(conf: &Config, archive: &Path, name: String, message: String, verbose: bool) -> Commit {
+    if !archive.is_file() {
+        eprintln!("Cannot find archive: {}", archive.display());
+        std::process::exit(1);
+    }
+
+    let temp_dir = make_staging_dir(conf, "resilient-import");
+
+    let mut tar_command = Command::new("tar");
+    tar_command.arg("--extract");
+    tar_command.arg("--xattrs");
+    tar_command.arg("--xattrs-include=*");
+    tar_command.arg("--acls");
+    tar_command.arg("--preserve-permissions");
+    if is_zstd(archive) {
+        tar_command.arg("--zstd");
+    }
+    tar_command.arg("--file").arg(archive);
+    tar_command.arg("--directory").arg(temp_dir.path());
+    run_tar(tar_command);
+
+    backend::make_commit_from(conf, temp_dir.path(), name, message, verbose)
+}
+
+pub fn default_import_message(archive: &Path) -> String {
+    let archive_path = archive.canonicalize().unwrap_or_else(|_| PathBuf::from(archive));
+    format!("Imported from {}", archive_path.display())
+}
+
+/// A temporary directory inside the repository, which has room for a whole commit where /tmp
+/// often doesn't
+fn make_staging_dir(conf: &Config, prefix: &str) -> TempDir {
+    let repo_dir = conf.get_repo_dir();
+    let temp_dir = fs::create_dir_all(repo_dir).and_then(|_| TempDir::new_in(repo_dir, prefix));
+    if temp_dir.is_err() {
+        eprintln!("Unable to allocate temporary directory in: {}", repo_dir.display());
+        std::process::exit(1);
+    }
+    temp_dir.unwrap()
+}
+
+fn is_zstd(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => ZSTD_EXTENSIONS.contains(&extension),
+        None => false,
+    }
+}
+
+fn run_tar(mut tar_command: Command) {
+    let tar_command = tar_command.status();
+    if tar_command.is_err() {
+        eprintln!("Unable to spawn tar command");
+        std::process::exit(1);
+    }
+
+    let tar_output = tar_command.unwrap();
+    if !tar_output.success() {
+        eprintln!("tar errored out with code: {}", tar_output.code().unwrap_or(-1));
+        std::process::exit(1);
+    }
+}
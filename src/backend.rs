@@ -0,0 +1,297 @@
+use super::config::{Config, RepoFormat};
+use super::commit::Commit;
+use super::excludes::Excludes;
+use super::status::Change;
+use super::rsync;
+use super::chunks;
+use super::hooks::{self, Hook};
+use super::commit_log::{self, CommitLog};
+use super::notify::{self, Event, Notification};
+use super::metrics::{self, Outcome};
+use super::lock;
+use chrono::{Local, TimeZone};
+use super::rsync::DATA_FOLDER_NAME;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use tempdir::TempDir;
+
+pub fn make_commit(conf: &Config, name: String, message: String, verbose: bool) -> Commit {
+    make_commit_from(conf, conf.get_backup_location(), name, message, verbose)
+}
+
+pub fn make_commit_from(conf: &Config, source: &Path, name: String, message: String, verbose: bool) -> Commit {
+    let _lock = lock::acquire(conf);
+    let started = Instant::now();
+    let source_arg = source.to_string_lossy().to_string();
+    let env = [("RESILIENT_SOURCE", source_arg.as_str())];
+    let mut log = CommitLog::new(conf, &name);
+
+    let commit_folder = conf.get_backups_folder().join(&name);
+    if commit_folder.exists() {
+        abort_commit(conf, started, &mut log, &env, format!("Commit with name '{}' already exists", name));
+    }
+    log.event(&format!("Creating commit from {}", source.display()));
+    if let Err(message) = hooks::run(conf, Hook::PreCreate, &mut log, "pending", &env) {
+        abort_commit(conf, started, &mut log, &env, message);
+    }
+
+    let commit_res = match conf.get_format() {
+        RepoFormat::Rsync => rsync::make_commit(conf, source, name.clone(), message, &mut log, verbose),
+        RepoFormat::Chunked => chunks::make_commit(conf, source, name.clone(), message, &mut log, verbose),
+    };
+
+    match commit_res {
+        Ok(commit) => {
+            if let Some(stats) = commit.get_stats() {
+                log.event(&format!("Committed {} changed file(s), {} byte(s) in {:.1}s",
+                    stats.files_changed, stats.bytes_changed, stats.duration_seconds));
+            }
+            if let Err(message) = hooks::run(conf, Hook::PostCreate, &mut log, "success", &env) {
+                eprintln!("{}", message);
+            }
+            log.write_to(&commit);
+            notify::send(conf, &Notification {
+                event: Event::CreateSuccess,
+                commit: &name,
+                subject: format!("resilient: created commit {}", name),
+                body: commit.get_message().trim_end().to_string(),
+            });
+            metrics::write(conf, Outcome::Success(&commit), started.elapsed().as_secs_f64());
+            commit
+        },
+        Err(message) => {
+            // Don't leave a half written commit behind for the next one to link against
+            if commit_folder.exists() && fs::remove_dir_all(&commit_folder).is_err() {
+                eprintln!("Unable to remove partial commit: {}", commit_folder.display());
+            }
+            abort_commit(conf, started, &mut log, &env, message);
+        }
+    }
+}
+
+fn abort_commit(conf: &Config, started: Instant, log: &mut CommitLog, env: &[(&str, &str)], message: String) -> ! {
+    eprintln!("{}", message);
+    log.event(&format!("Failed: {}", message));
+
+    let mut failure_env = env.to_vec();
+    failure_env.push(("RESILIENT_ERROR", &message));
+    if let Err(hook_message) = hooks::run(conf, Hook::OnFailure, log, "failure", &failure_env) {
+        eprintln!("{}", hook_message);
+    }
+
+    let name = log.get_commit();
+
+    notify::send(conf, &Notification {
+        event: Event::CreateFailure,
+        commit: name,
+        subject: format!("resilient: commit {} failed", name),
+        body: message.clone(),
+    });
+    metrics::write(conf, Outcome::Failure(&message), started.elapsed().as_secs_f64());
+    std::process::exit(1);
+}
+
+pub fn resolve_commit(conf: &Config, commit: Option<String>) -> Commit {
+    match commit {
+        Some(commit) if commit != "latest" => {
+            let found_commit = Commit::find(conf, &commit).or_else(|| Commit::find_by_tag(conf, &commit));
+            if found_commit.is_none() {
+                eprintln!("Cannot find commit: {}", commit);
+                std::process::exit(1);
+            }
+            found_commit.unwrap()
+        },
+        _ => {
+            let latest = Commit::get_latest(conf);
+            if latest.is_none() {
+                eprintln!("No Commits to restore from");
+                std::process::exit(1);
+            }
+            latest.unwrap()
+        }
+    }
+}
+
+pub fn get_changes(conf: &Config) -> Vec<Change> {
+    or_exit(try_get_changes(conf))
+}
+
+pub fn try_get_changes(conf: &Config) -> Result<Vec<Change>, String> {
+    get_changes_from(conf, conf.get_backup_location(), Commit::try_get_latest(conf)?.as_ref(), conf.get_excludes())
+}
+
+fn get_changes_from(conf: &Config, source: &Path, previous: Option<&Commit>, excludes: &Excludes) -> Result<Vec<Change>, String> {
+    let chunked = match previous {
+        Some(previous) => previous.is_chunked(),
+        None => conf.get_format() == RepoFormat::Chunked,
+    };
+
+    if chunked {
+        chunks::get_changes(conf, source, previous, excludes)
+    } else {
+        rsync::get_changes(source, previous, excludes)
+    }
+}
+
+/// Lists what changed in a commit relative to the one before it
+pub fn get_commit_changes(conf: &Config, commit: &Commit) -> Vec<Change> {
+    if !commit.is_chunked() {
+        return or_exit(get_commit_changes_in(conf, commit, &commit.get_folder().join(DATA_FOLDER_NAME)));
+    }
+
+    // Chunked commits only exist as trees, so lay them out on disk to compare against
+    let temp_dir = TempDir::new("resilient-show");
+    if temp_dir.is_err() {
+        eprintln!("Unable to allocate temporary directory");
+        std::process::exit(1);
+    }
+    let temp_dir = temp_dir.unwrap();
+    if let Err(message) = chunks::restore_to(conf, commit, String::new(), temp_dir.path(), false) {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+    or_exit(get_commit_changes_in(conf, commit, temp_dir.path()))
+}
+
+/// Like get_commit_changes, for a commit whose files are already laid out in `data_dir`
+pub fn get_commit_changes_in(conf: &Config, commit: &Commit, data_dir: &Path) -> Result<Vec<Change>, String> {
+    // Both sides are commits, which only ever hold what the excludes let through when they were made
+    let previous = get_parent(conf, commit)?;
+    get_changes_from(conf, data_dir, previous.as_ref(), &Excludes::default())
+}
+
+/// The commit this one was made on top of, as recorded in its metadata. Commits from before
+/// metadata was recorded, or whose parent has been deleted, use the one before them by time.
+fn get_parent(conf: &Config, commit: &Commit) -> Result<Option<Commit>, String> {
+    if let Some(metadata) = commit.get_metadata() {
+        match metadata.parent {
+            None => return Ok(None),
+            Some(parent) => {
+                if let Some(parent) = Commit::try_find(conf, &parent)? {
+                    return Ok(Some(parent));
+                }
+            },
+        }
+    }
+
+    let commits = Commit::try_get_commits(conf)?;
+    let index = commits.iter()
+        .position(|other| other.get_name() == commit.get_name())
+        .ok_or_else(|| format!("Cannot find commit: {}", commit.get_name()))?;
+    Ok(if index > 0 { commits.into_iter().nth(index - 1) } else { None })
+}
+
+pub fn print_status(conf: &Config) {
+    for change in get_changes(conf) {
+        println!("\t{}", change.get_mod_string());
+    }
+}
+
+pub fn restore(conf: &Config, commit: &Commit, path: String, verbose: bool) {
+    let env = [("RESILIENT_PATH", path.as_str())];
+    let mut log = CommitLog::new(conf, &commit.get_name());
+    log.event(&format!("Restoring '{}' to {}", path, conf.get_backup_location().display()));
+    if let Err(message) = hooks::run(conf, Hook::PreRestore, &mut log, "pending", &env) {
+        abort_restore(conf, commit, &mut log, &env, message);
+    }
+
+    let restore_res = if commit.is_chunked() {
+        chunks::restore(conf, commit, path.clone(), verbose)
+    } else {
+        rsync::restore(conf, commit, path.clone(), verbose)
+    };
+    if let Err(message) = restore_res {
+        abort_restore(conf, commit, &mut log, &env, message);
+    }
+
+    log.event("Restore finished");
+    if let Err(message) = hooks::run(conf, Hook::PostRestore, &mut log, "success", &env) {
+        eprintln!("{}", message);
+    }
+    log.write_to(commit);
+}
+
+fn abort_restore(conf: &Config, commit: &Commit, log: &mut CommitLog, env: &[(&str, &str)], message: String) -> ! {
+    eprintln!("{}", message);
+    log.event(&format!("Restore failed: {}", message));
+
+    let mut failure_env = env.to_vec();
+    failure_env.push(("RESILIENT_ERROR", &message));
+    if let Err(hook_message) = hooks::run(conf, Hook::OnFailure, log, "failure", &failure_env) {
+        eprintln!("{}", hook_message);
+    }
+
+    log.write_to(commit);
+    std::process::exit(1);
+}
+
+pub fn print_log(conf: &Config) {
+    for commit in Commit::get_commits(conf).iter().rev() {
+        let date = Local.timestamp_opt(commit.get_timestamp(), 0).unwrap();
+        let tags = commit.get_tags();
+        if tags.is_empty() {
+            println!("commit {}", commit.get_name());
+        } else {
+            println!("commit {} ({})", commit.get_name(), tags.join(", "));
+        }
+        println!("Date: {}", date.format("%Y-%m-%d %H:%M:%S"));
+        println!();
+        for message_line in commit.get_message().lines() {
+            println!("    {}", message_line);
+        }
+        println!();
+    }
+}
+
+pub fn show(conf: &Config, commit: &Commit, changes: bool, log: bool) {
+    let date = Local.timestamp_opt(commit.get_timestamp(), 0).unwrap();
+    let metadata = commit.get_metadata();
+    println!("commit {}", commit.get_name());
+    println!("Date: {}", date.format("%Y-%m-%d %H:%M:%S"));
+    println!("Format: {}", if commit.is_chunked() { "chunked" } else { "rsync" });
+    let tags = commit.get_tags();
+    if !tags.is_empty() {
+        println!("Tags: {}", tags.join(", "));
+    }
+    if commit.is_pinned() {
+        println!("Pinned: yes");
+    }
+    if let Some(metadata) = &metadata {
+        println!("Parent: {}", metadata.parent.as_deref().unwrap_or("none"));
+        println!("Host: {}", metadata.host);
+    }
+    if let Some(stats) = commit.get_stats() {
+        println!("Files: {} ({} bytes)", stats.files_total, stats.bytes_total);
+        println!("Changed: {} ({} bytes)", stats.files_changed, stats.bytes_changed);
+        println!("Duration: {:.1}s", stats.duration_seconds);
+    }
+    println!();
+    for message_line in commit.get_message().lines() {
+        println!("    {}", message_line);
+    }
+    println!();
+
+    if changes {
+        for change in get_commit_changes(conf, commit) {
+            println!("\t{}", change.get_mod_string());
+        }
+    }
+
+    if log {
+        match commit_log::read(commit) {
+            Some(contents) => print!("{}", contents),
+            None => println!("No log recorded for commit: {}", commit.get_name()),
+        }
+    }
+}
+
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
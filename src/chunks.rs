@@ -0,0 +1,1160 @@
+use super::config::{Config, Compression};
+use super::commit::{Commit, CommitStats};
+use super::commit_log::CommitLog;
+use super::excludes::Excludes;
+use super::progress::Progress;
+use super::throttle::{self, RateLimiter};
+use super::status::{Change, FileType, ModList};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use fastcdc::v2020::StreamCDC;
+use filetime::FileTime;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::Metadata;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+const MAX_TMP_NAME_PREFIX: usize = 200;
+
+const COMPRESSED_SUFFIX: &str = "zst";
+const ZSTD_MAX_HEADER_SIZE: u64 = 18;
+const PRECOMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz", "lz4", "lzma",
+    "m4a", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus", "png", "pptx", "rar", "tbz2",
+    "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl Entry {
+    fn get_file_type(&self) -> FileType {
+        match self.kind {
+            EntryKind::File => FileType::File,
+            EntryKind::Directory => FileType::Directory,
+            EntryKind::Symlink => FileType::Symlink(self.target.clone().unwrap_or_default()),
+        }
+    }
+
+    fn display_name(&self, prefix: &str) -> String {
+        if self.kind == EntryKind::Directory {
+            format!("{}{}/", prefix, self.name)
+        } else {
+            format!("{}{}", prefix, self.name)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Tree {
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+}
+
+impl Tree {
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+}
+
+pub struct Store {
+    objects: PathBuf,
+    compression: Compression,
+}
+
+impl Store {
+    pub fn open(conf: &Config) -> Store {
+        or_exit(Store::try_open(conf))
+    }
+
+    pub fn try_open(conf: &Config) -> Result<Store, String> {
+        let objects = conf.get_objects_folder();
+        fs::create_dir_all(&objects)
+            .map_err(|_| format!("Unable to create object store at location: {}", objects.display()))?;
+
+        Ok(Store {
+            objects,
+            compression: conf.get_compression(),
+        })
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.objects.join(&hash[..2]).join(&hash[2..])
+    }
+
+    fn compressed_object_path(&self, hash: &str) -> PathBuf {
+        self.object_path(hash).with_extension(COMPRESSED_SUFFIX)
+    }
+
+    fn write_object(&self, data: &[u8], compressible: bool) -> Result<String, String> {
+        let hash = hex::encode(Sha256::digest(data));
+        if self.has_object(&hash) {
+            return Ok(hash);
+        }
+        let mut object_path = self.object_path(&hash);
+        let compressed_object_path = self.compressed_object_path(&hash);
+
+        let mut compressed = None;
+        if let Compression::Zstd(level) = self.compression {
+            if compressible {
+                let compress_res = zstd::bulk::compress(data, level)
+                    .map_err(|_| format!("Unable to compress object: {}", hash))?;
+                if compress_res.len() < data.len() {
+                    object_path = compressed_object_path;
+                    compressed = Some(compress_res);
+                }
+            }
+        }
+        let data = compressed.as_deref().unwrap_or(data);
+
+        let object_dir = object_path.parent().unwrap();
+        fs::create_dir_all(object_dir)
+            .map_err(|_| format!("Unable to create object folder: {}", object_dir.display()))?;
+
+        let tmp_path = object_path.with_extension("tmp");
+        fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, &object_path))
+            .map_err(|_| format!("Unable to write object: {}", object_path.display()))?;
+
+        Ok(hash)
+    }
+
+    pub fn read_object(&self, hash: &str) -> Result<Vec<u8>, String> {
+        let compressed_object_path = self.compressed_object_path(hash);
+        if compressed_object_path.exists() {
+            return fs::read(&compressed_object_path)
+                .and_then(|contents| zstd::decode_all(contents.as_slice()))
+                .map_err(|_| format!("Unable to read compressed object: {}", hash));
+        }
+
+        fs::read(self.object_path(hash)).map_err(|_| format!("Unable to read object: {}", hash))
+    }
+
+    pub fn object_size(&self, hash: &str) -> Result<u64, String> {
+        let compressed_object_path = self.compressed_object_path(hash);
+        if compressed_object_path.exists() {
+            let mut header = Vec::new();
+            let read_res = fs::File::open(&compressed_object_path)
+                .and_then(|file| file.take(ZSTD_MAX_HEADER_SIZE).read_to_end(&mut header));
+            let content_size = read_res.ok().and_then(|_| zstd::zstd_safe::get_frame_content_size(&header).ok().flatten());
+            return content_size.ok_or_else(|| format!("Unable to read size of compressed object: {}", hash));
+        }
+
+        fs::metadata(self.object_path(hash))
+            .map(|metadata| metadata.len())
+            .map_err(|_| format!("Unable to read object: {}", hash))
+    }
+
+    fn has_object(&self, hash: &str) -> bool {
+        self.object_path(hash).exists() || self.compressed_object_path(hash).exists()
+    }
+
+    fn copy_object_from(&self, source: &Store, hash: &str) {
+        if self.has_object(hash) {
+            return;
+        }
+
+        let (source_path, object_path) = if source.compressed_object_path(hash).exists() {
+            (source.compressed_object_path(hash), self.compressed_object_path(hash))
+        } else {
+            (source.object_path(hash), self.object_path(hash))
+        };
+
+        let object_dir = object_path.parent().unwrap();
+        let tmp_path = object_path.with_extension("tmp");
+        let copy_res = fs::create_dir_all(object_dir)
+            .and_then(|_| fs::copy(&source_path, &tmp_path))
+            .and_then(|_| fs::rename(&tmp_path, &object_path));
+        if copy_res.is_err() {
+            eprintln!("Unable to copy object: {}", hash);
+            std::process::exit(1);
+        }
+    }
+
+    pub fn copy_tree_from(&self, source: &Store, hash: &str) {
+        // Trees are copied after everything they reference, so an existing tree is always complete
+        if self.has_object(hash) {
+            return;
+        }
+
+        let tree = or_exit(source.read_tree(hash));
+        for entry in &tree.entries {
+            match entry.kind {
+                EntryKind::File => {
+                    for chunk in &entry.chunks {
+                        self.copy_object_from(source, chunk);
+                    }
+                },
+                EntryKind::Directory => {
+                    self.copy_tree_from(source, entry.tree.as_ref().unwrap());
+                },
+                EntryKind::Symlink => {},
+            }
+        }
+        self.copy_object_from(source, hash);
+    }
+
+    fn mark_reachable(&self, hash: &str, reachable: &mut HashSet<String>) {
+        if !reachable.insert(hash.to_string()) {
+            return;
+        }
+
+        let tree = or_exit(self.read_tree(hash));
+        for entry in &tree.entries {
+            match entry.kind {
+                EntryKind::File => {
+                    reachable.extend(entry.chunks.iter().cloned());
+                },
+                EntryKind::Directory => {
+                    self.mark_reachable(entry.tree.as_ref().unwrap(), reachable);
+                },
+                EntryKind::Symlink => {},
+            }
+        }
+    }
+
+    fn write_tree(&self, tree: &Tree) -> Result<String, String> {
+        let serialized = toml::to_string(tree).map_err(|_| "Unable to serialize tree".to_string())?;
+        self.write_object(serialized.as_bytes(), true)
+    }
+
+    pub fn read_tree(&self, hash: &str) -> Result<Tree, String> {
+        let contents = String::from_utf8(self.read_object(hash)?)
+            .map_err(|_| format!("Tree object '{}' isn't valid utf-8", hash))?;
+        toml::from_str(&contents).map_err(|_| format!("Unable to parse tree object: {}", hash))
+    }
+
+    /// Stores the file's chunks, calling `on_chunk` with the size of each one as it goes
+    fn store_file(&self, path: &Path, mut on_chunk: impl FnMut(u64)) -> Result<Vec<String>, String> {
+        let file = fs::File::open(path).map_err(|_| format!("Unable to open file: {}", path.display()))?;
+
+        let compressible = !is_precompressed(path);
+        let mut chunks = Vec::new();
+        let chunker = StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        for chunk in chunker {
+            let chunk = chunk.map_err(|_| format!("Unable to read file: {}", path.display()))?;
+            chunks.push(self.write_object(&chunk.data, compressible)?);
+            on_chunk(chunk.data.len() as u64);
+        }
+        Ok(chunks)
+    }
+}
+
+pub fn make_commit(conf: &Config, source: &Path, name: String, message: String, log: &mut CommitLog, verbose: bool) -> Result<Commit, String> {
+    let backups_dir = conf.get_backups_folder();
+    let new_backup_folder = backups_dir.join(&name);
+    if new_backup_folder.exists() {
+        return Err(format!("Commit with name '{}' already exists", name));
+    }
+
+    let started = Instant::now();
+    fs::create_dir_all(&new_backup_folder)
+        .map_err(|_| format!("Unable to make new backup folder at location: {}", new_backup_folder.display()))?;
+    let new_commit = Commit::new(new_backup_folder, message);
+
+    let store = Store::open(conf);
+    let latest = Commit::get_latest(conf);
+    let previous_tree = match &latest {
+        Some(latest) if latest.is_chunked() => Some(store.read_tree(&latest.get_tree())?),
+        _ => None,
+    };
+
+    // The previous commit is the best guess at how much there is to go through
+    let mut progress = Progress::new(conf, verbose);
+    let previous_stats = latest.as_ref().and_then(|latest| latest.get_stats());
+    progress.set_totals(
+        previous_stats.as_ref().map(|stats| stats.files_total),
+        previous_stats.as_ref().map(|stats| stats.bytes_total),
+    );
+
+    // Everything runs in this process, so the bandwidth limit is kept by pacing reads of changed files
+    let limits = throttle::get_current_limits(conf);
+    let _priorities = if limits.is_empty() {
+        None
+    } else {
+        log.event(&format!("Throttling: {}", limits.describe()));
+        Some(throttle::throttle_current_thread(limits)?)
+    };
+
+    let mut snapshot = Snapshot {
+        store: &store,
+        stats: CommitStats::default(),
+        log,
+        progress,
+        limiter: limits.get_bwlimit().map(RateLimiter::new),
+        excludes: conf.get_excludes(),
+        verbose,
+    };
+    let root = snapshot_dir(&mut snapshot, source, previous_tree.as_ref(), "");
+    snapshot.progress.finish();
+    let root = root?;
+    let mut stats = snapshot.stats;
+    stats.duration_seconds = started.elapsed().as_secs_f64();
+
+    new_commit.write_tree_file(&root)?;
+    new_commit.write_stats_file(&stats)?;
+    new_commit.write_metadata_file(latest.as_ref())?;
+    new_commit.write_commit_file()?;
+    Commit::write_latest(conf, &new_commit)?;
+    Ok(new_commit)
+}
+
+/// Lists how `source` differs from a commit, leaving out excluded paths as the next commit would
+pub fn get_changes(conf: &Config, source: &Path, latest_commit: Option<&Commit>, excludes: &Excludes) -> Result<Vec<Change>, String> {
+    let store = Store::try_open(conf)?;
+    let tree = match latest_commit {
+        Some(latest_commit) => store.read_tree(&latest_commit.try_get_tree()?)?,
+        None => Tree::default(),
+    };
+
+    let mut changes = Vec::new();
+    let diff = Diff {
+        store: &store,
+        excludes,
+    };
+    diff_dir(&diff, source, &tree, "", &mut changes)?;
+    Ok(changes)
+}
+
+/// Removes every object no remaining chunked commit references, returning the bytes freed
+pub fn collect_garbage(conf: &Config, verbose: bool) -> u64 {
+    let store = Store::open(conf);
+    let mut reachable = HashSet::new();
+    for commit in Commit::get_commits(conf).iter().filter(|commit| commit.is_chunked()) {
+        store.mark_reachable(&commit.get_tree(), &mut reachable);
+    }
+
+    let mut freed = 0;
+    for (prefix, prefix_path, metadata) in or_exit(read_dir_sorted(&store.objects)) {
+        if !metadata.is_dir() {
+            continue;
+        }
+
+        for (object_name, object_path, metadata) in or_exit(read_dir_sorted(&prefix_path)) {
+            let hash = format!("{}{}", prefix, object_name.trim_end_matches(&format!(".{}", COMPRESSED_SUFFIX)));
+            if reachable.contains(&hash) {
+                continue;
+            }
+
+            if verbose {
+                println!("deleting object {}", hash);
+            }
+            if fs::remove_file(&object_path).is_err() {
+                eprintln!("Unable to delete object: {}", object_path.display());
+                std::process::exit(1);
+            }
+            freed += metadata.len();
+        }
+        let _ = fs::remove_dir(&prefix_path);
+    }
+    freed
+}
+
+pub fn restore(conf: &Config, commit: &Commit, path: String, verbose: bool) -> Result<(), String> {
+    // The commit's stats only describe the whole tree, so restoring a part of it has no totals
+    let mut progress = Progress::new(conf, verbose);
+    if path.split('/').all(|component| component.is_empty()) {
+        let stats = commit.get_stats();
+        progress.set_totals(
+            stats.as_ref().map(|stats| stats.files_total),
+            stats.as_ref().map(|stats| stats.bytes_total),
+        );
+    }
+
+    // Like rsync, a whole tree restore leaves excluded paths in the backup directory alone
+    let no_excludes = Excludes::default();
+    let excludes = if path.split('/').all(|component| component.is_empty()) {
+        conf.get_excludes()
+    } else {
+        &no_excludes
+    };
+
+    let store = Store::try_open(conf)?;
+    let mut restore = Restore {
+        store: &store,
+        progress: Some(progress),
+        files_done: 0,
+        bytes_done: 0,
+        excludes,
+        verbose,
+    };
+    let result = restore_path(&mut restore, commit, &path, conf.get_backup_location());
+    if let Some(progress) = &mut restore.progress {
+        progress.finish();
+    }
+    result
+}
+
+/// Restores `path` of a commit into `backup_location` without reporting progress, e.g. into a
+/// temporary directory
+pub fn restore_to(conf: &Config, commit: &Commit, path: String, backup_location: &Path, verbose: bool) -> Result<(), String> {
+    let store = Store::try_open(conf)?;
+    let excludes = Excludes::default();
+    let mut restore = Restore {
+        store: &store,
+        progress: None,
+        files_done: 0,
+        bytes_done: 0,
+        excludes: &excludes,
+        verbose,
+    };
+    restore_path(&mut restore, commit, &path, backup_location)
+}
+
+fn restore_path(restore: &mut Restore, commit: &Commit, path: &str, backup_location: &Path) -> Result<(), String> {
+    let store = restore.store;
+    let mut tree = store.read_tree(&commit.try_get_tree()?)?;
+
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+    if components.is_empty() {
+        return restore_tree(restore, &tree, backup_location, "");
+    }
+
+    let mut prefix = String::new();
+    for (index, component) in components.iter().enumerate() {
+        let entry = tree.find(component)
+            .ok_or_else(|| format!("Cannot find path '{}' in commit: {}", path, commit.get_name()))?
+            .clone();
+
+        if index == components.len() - 1 {
+            let dest = backup_location.join(components.join("/"));
+            fs::create_dir_all(dest.parent().unwrap())
+                .map_err(|_| format!("Unable to create directory: {}", dest.parent().unwrap().display()))?;
+            restore_entry(restore, &entry, &dest, &prefix)?;
+        } else if entry.kind == EntryKind::Directory {
+            tree = store.read_tree(entry.tree.as_ref().unwrap())?;
+            prefix = entry.display_name(&prefix);
+        } else {
+            return Err(format!("Path '{}' isn't a directory in commit: {}", prefix + component, commit.get_name()));
+        }
+    }
+    Ok(())
+}
+
+/// Looks up `path` in a chunked commit, the root being a directory entry for the whole tree
+pub fn find_entry(store: &Store, commit: &Commit, path: &str) -> Result<Entry, String> {
+    let mut entry = Entry {
+        name: String::new(),
+        kind: EntryKind::Directory,
+        mode: 0o755,
+        uid: 0,
+        gid: 0,
+        mtime: commit.get_timestamp(),
+        mtime_nsec: 0,
+        size: 0,
+        chunks: Vec::new(),
+        tree: Some(commit.try_get_tree()?),
+        target: None,
+    };
+
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        if entry.kind != EntryKind::Directory {
+            return Err(format!("Path '{}' isn't a directory in commit: {}", path, commit.get_name()));
+        }
+        let tree = store.read_tree(entry.tree.as_ref().unwrap())?;
+        entry = tree.find(component)
+            .ok_or_else(|| format!("Cannot find path '{}' in commit: {}", path, commit.get_name()))?
+            .clone();
+    }
+    Ok(entry)
+}
+
+fn is_precompressed(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => PRECOMPRESSED_EXTENSIONS.contains(&extension.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_dir_sorted(dir: &Path) -> Result<Vec<(String, PathBuf, Metadata)>, String> {
+    let dir_entries = dir.read_dir().map_err(|_| format!("Unable to read directory: {}", dir.display()))?;
+
+    let mut children = Vec::new();
+    for dir_entry in dir_entries {
+        if dir_entry.is_err() {
+            continue;
+        }
+
+        // Trees can't hold these, and leaving them out would quietly lose them on restore
+        let child_path = dir_entry.unwrap().path();
+        let child_name = child_path.file_name().unwrap().to_str()
+            .ok_or_else(|| format!("Cannot store a file name that isn't UTF-8 in the chunked format: {}", child_path.display()))?
+            .to_string();
+
+        let metadata = fs::symlink_metadata(&child_path)
+            .map_err(|_| format!("Unable to stat file: {}", child_path.display()))?;
+
+        let file_type = metadata.file_type();
+        if !file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink() {
+            return Err(format!("Cannot store a device, fifo or socket in the chunked format: {}", child_path.display()));
+        }
+
+        children.push((child_name, child_path, metadata));
+    }
+
+    children.sort_by(|c1, c2| c1.0.cmp(&c2.0));
+    Ok(children)
+}
+
+fn read_link_target(path: &Path) -> Result<String, String> {
+    fs::read_link(path)
+        .map(|target| target.to_string_lossy().to_string())
+        .map_err(|_| format!("Unable to read symlink: {}", path.display()))
+}
+
+fn make_entry(name: String, path: &Path, metadata: &Metadata) -> Result<Entry, String> {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_symlink() {
+        EntryKind::Symlink
+    } else {
+        EntryKind::File
+    };
+
+    let target = if kind == EntryKind::Symlink {
+        Some(read_link_target(path)?)
+    } else {
+        None
+    };
+
+    Ok(Entry {
+        name,
+        kind,
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime(),
+        mtime_nsec: metadata.mtime_nsec() as u32,
+        size: if kind == EntryKind::File { metadata.len() } else { 0 },
+        chunks: Vec::new(),
+        tree: None,
+        target,
+    })
+}
+
+/// State carried through snapshot_dir while a commit is made
+struct Snapshot<'a> {
+    store: &'a Store,
+    stats: CommitStats,
+    log: &'a mut CommitLog,
+    progress: Progress,
+    limiter: Option<RateLimiter>,
+    excludes: &'a Excludes,
+    verbose: bool,
+}
+
+fn snapshot_dir(snapshot: &mut Snapshot, dir: &Path, previous: Option<&Tree>, prefix: &str) -> Result<String, String> {
+    let store = snapshot.store;
+    let mut tree = Tree::default();
+
+    for (child_name, child_path, metadata) in read_dir_sorted(dir)? {
+        if snapshot.excludes.is_excluded(&format!("{}{}", prefix, child_name), metadata.is_dir()) {
+            continue;
+        }
+
+        let mut entry = make_entry(child_name, &child_path, &metadata)?;
+        let previous_entry = previous.and_then(|previous| previous.find(&entry.name));
+
+        match entry.kind {
+            EntryKind::Directory => {
+                let previous_tree = match previous_entry {
+                    Some(previous_entry) if previous_entry.kind == EntryKind::Directory => {
+                        Some(store.read_tree(previous_entry.tree.as_ref().unwrap())?)
+                    },
+                    _ => None,
+                };
+                let child_prefix = entry.display_name(prefix);
+                entry.tree = Some(snapshot_dir(snapshot, &child_path, previous_tree.as_ref(), &child_prefix)?);
+            },
+            EntryKind::File => {
+                let unchanged = match previous_entry {
+                    Some(previous_entry) => {
+                        previous_entry.kind == EntryKind::File
+                            && previous_entry.size == entry.size
+                            && previous_entry.mtime == entry.mtime
+                            && previous_entry.mtime_nsec == entry.mtime_nsec
+                    },
+                    None => false,
+                };
+
+                let stats = &mut snapshot.stats;
+                if unchanged {
+                    entry.chunks = previous_entry.unwrap().chunks.clone();
+                } else {
+                    let display_name = entry.display_name(prefix);
+                    if snapshot.verbose {
+                        println!("{}", display_name);
+                    }
+                    snapshot.log.output(&display_name);
+                    let progress = &mut snapshot.progress;
+                    let limiter = &mut snapshot.limiter;
+                    let mut stored = 0;
+                    entry.chunks = store.store_file(&child_path, |chunk_size| {
+                        stored += chunk_size;
+                        progress.update(stats.files_total, stats.bytes_total + stored);
+                        if let Some(limiter) = limiter {
+                            limiter.consume(chunk_size);
+                        }
+                    })?;
+                    stats.files_changed += 1;
+                    stats.bytes_changed += entry.size;
+                }
+                stats.files_total += 1;
+                stats.bytes_total += entry.size;
+                snapshot.progress.update(stats.files_total, stats.bytes_total);
+            },
+            EntryKind::Symlink => {},
+        }
+
+        tree.entries.push(entry);
+    }
+
+    store.write_tree(&tree)
+}
+
+/// State carried through diff_dir while the backup directory is compared to a commit
+struct Diff<'a> {
+    store: &'a Store,
+    excludes: &'a Excludes,
+}
+
+fn diff_dir(diff: &Diff, dir: &Path, tree: &Tree, prefix: &str, changes: &mut Vec<Change>) -> Result<(), String> {
+    let store = diff.store;
+    let children = read_dir_children(diff, dir, prefix)?;
+    let live_names: HashMap<&str, ()> = children.iter().map(|child| (child.0.as_str(), ())).collect();
+
+    for stored in &tree.entries {
+        if !live_names.contains_key(stored.name.as_str()) {
+            push_deletions(store, stored, prefix, changes)?;
+        }
+    }
+
+    for (child_name, child_path, metadata) in &children {
+        let live = make_entry(child_name.clone(), child_path, metadata)?;
+        let stored = tree.find(child_name);
+
+        match stored {
+            None => {
+                push_creations(diff, &live, child_path, prefix, changes)?;
+            },
+            Some(stored) if stored.kind != live.kind => {
+                push_deletions(store, stored, prefix, changes)?;
+                push_creations(diff, &live, child_path, prefix, changes)?;
+            },
+            Some(stored) => {
+                let modlist = ModList {
+                    checksum: live.target != stored.target,
+                    size: live.size != stored.size,
+                    mod_time: live.kind != EntryKind::Symlink
+                        && (live.mtime != stored.mtime || live.mtime_nsec != stored.mtime_nsec),
+                    perms: live.kind != EntryKind::Symlink && live.mode != stored.mode,
+                    owner: live.uid != stored.uid,
+                    group: live.gid != stored.gid,
+                    ..ModList::default()
+                };
+                if modlist.has_changes() {
+                    changes.push(Change::update(live.display_name(prefix), live.get_file_type(), modlist));
+                }
+
+                if live.kind == EntryKind::Directory {
+                    let subtree = store.read_tree(stored.tree.as_ref().unwrap())?;
+                    diff_dir(diff, child_path, &subtree, &live.display_name(prefix), changes)?;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn push_deletions(store: &Store, entry: &Entry, prefix: &str, changes: &mut Vec<Change>) -> Result<(), String> {
+    if entry.kind == EntryKind::Directory {
+        let subtree = store.read_tree(entry.tree.as_ref().unwrap())?;
+        for child in &subtree.entries {
+            push_deletions(store, child, &entry.display_name(prefix), changes)?;
+        }
+    }
+    changes.push(Change::Deleting(entry.display_name(prefix)));
+    Ok(())
+}
+
+fn push_creations(diff: &Diff, entry: &Entry, path: &Path, prefix: &str, changes: &mut Vec<Change>) -> Result<(), String> {
+    changes.push(Change::creation(entry.display_name(prefix), entry.get_file_type()));
+    if entry.kind == EntryKind::Directory {
+        let child_prefix = entry.display_name(prefix);
+        for (child_name, child_path, metadata) in read_dir_children(diff, path, &child_prefix)? {
+            let child = make_entry(child_name, &child_path, &metadata)?;
+            push_creations(diff, &child, &child_path, &child_prefix, changes)?;
+        }
+    }
+    Ok(())
+}
+
+/// The children of `dir` a commit would store, so excluded ones show up as deleted
+fn read_dir_children(diff: &Diff, dir: &Path, prefix: &str) -> Result<Vec<(String, PathBuf, Metadata)>, String> {
+    let mut children = read_dir_sorted(dir)?;
+    children.retain(|(child_name, _, metadata)| !diff.excludes.is_excluded(&format!("{}{}", prefix, child_name), metadata.is_dir()));
+    Ok(children)
+}
+
+/// State carried through restore_tree while a commit is written out
+struct Restore<'a> {
+    store: &'a Store,
+    progress: Option<Progress>,
+    files_done: u64,
+    bytes_done: u64,
+    excludes: &'a Excludes,
+    verbose: bool,
+}
+
+impl Restore<'_> {
+    fn report(&mut self, bytes_written: u64) {
+        if let Some(progress) = &mut self.progress {
+            progress.update(self.files_done, self.bytes_done + bytes_written);
+        }
+    }
+}
+
+fn restore_tree(restore: &mut Restore, tree: &Tree, dest: &Path, prefix: &str) -> Result<(), String> {
+    // Everything not in the tree goes, including what a commit could never have stored
+    let dir_entries = dest.read_dir().map_err(|_| format!("Unable to read directory: {}", dest.display()))?;
+    for dir_entry in dir_entries.flatten() {
+        let child_path = dir_entry.path();
+        let file_name = dir_entry.file_name();
+        if file_name.to_str().and_then(|name| tree.find(name)).is_some() {
+            continue;
+        }
+
+        let metadata = fs::symlink_metadata(&child_path)
+            .map_err(|_| format!("Unable to stat file: {}", child_path.display()))?;
+        if restore.excludes.is_excluded(&format!("{}{}", prefix, file_name.to_string_lossy()), metadata.is_dir()) {
+            continue;
+        }
+        if restore.verbose {
+            println!("deleting {}{}", prefix, file_name.to_string_lossy());
+        }
+        let remove_res = if metadata.is_dir() {
+            fs::remove_dir_all(&child_path)
+        } else {
+            fs::remove_file(&child_path)
+        };
+        remove_res.map_err(|_| format!("Unable to remove: {}", child_path.display()))?;
+    }
+
+    for entry in &tree.entries {
+        restore_entry(restore, entry, &dest.join(&entry.name), prefix)?;
+    }
+    Ok(())
+}
+
+fn restore_entry(restore: &mut Restore, entry: &Entry, dest: &Path, prefix: &str) -> Result<(), String> {
+    let existing = fs::symlink_metadata(dest).ok();
+    if let Some(existing) = &existing {
+        let same_kind = match entry.kind {
+            EntryKind::File => existing.file_type().is_file(),
+            EntryKind::Directory => existing.file_type().is_dir(),
+            EntryKind::Symlink => false,
+        };
+
+        if !same_kind {
+            let remove_res = if existing.file_type().is_dir() {
+                fs::remove_dir_all(dest)
+            } else {
+                fs::remove_file(dest)
+            };
+            remove_res.map_err(|_| format!("Unable to remove: {}", dest.display()))?;
+        }
+    }
+
+    match entry.kind {
+        EntryKind::Directory => {
+            fs::create_dir_all(dest).map_err(|_| format!("Unable to create directory: {}", dest.display()))?;
+            let subtree = restore.store.read_tree(entry.tree.as_ref().unwrap())?;
+            restore_tree(restore, &subtree, dest, &entry.display_name(prefix))?;
+        },
+        EntryKind::File => {
+            let unchanged = match &existing {
+                Some(existing) => {
+                    existing.is_file()
+                        && existing.len() == entry.size
+                        && existing.mtime() == entry.mtime
+                        && existing.mtime_nsec() as u32 == entry.mtime_nsec
+                },
+                None => false,
+            };
+
+            if !unchanged {
+                if restore.verbose {
+                    println!("{}", entry.display_name(prefix));
+                }
+                write_file(restore, entry, dest)?;
+            }
+            restore.files_done += 1;
+            restore.bytes_done += entry.size;
+            restore.report(0);
+        },
+        EntryKind::Symlink => {
+            if restore.verbose {
+                println!("{} -> {}", entry.display_name(prefix), entry.target.as_ref().unwrap());
+            }
+            std::os::unix::fs::symlink(entry.target.as_ref().unwrap(), dest)
+                .map_err(|_| format!("Unable to create symlink: {}", dest.display()))?;
+        },
+    }
+
+    apply_metadata(entry, dest)
+}
+
+/// Writes the file next to `dest` and renames it into place like rsync does, so a file hard
+/// linked elsewhere or read-only is replaced rather than overwritten, and a failed restore
+/// never leaves it half written
+fn write_file(restore: &mut Restore, entry: &Entry, dest: &Path) -> Result<(), String> {
+    // Shortened like rsync does, so the temporary name stays within the file name limit
+    let mut short_name = entry.name.as_str();
+    while short_name.len() > MAX_TMP_NAME_PREFIX {
+        short_name = &short_name[..short_name.char_indices().last().unwrap().0];
+    }
+    let tmp_dest = dest.with_file_name(format!(".{}.resilient-tmp", short_name));
+    let _ = fs::remove_file(&tmp_dest);
+    let write_res = write_chunks(restore, entry, &tmp_dest)
+        .and_then(|_| fs::rename(&tmp_dest, dest).map_err(|_| format!("Unable to replace file: {}", dest.display())));
+    if write_res.is_err() {
+        let _ = fs::remove_file(&tmp_dest);
+    }
+    write_res
+}
+
+fn write_chunks(restore: &mut Restore, entry: &Entry, dest: &Path) -> Result<(), String> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(dest)
+        .map_err(|_| format!("Unable to create file: {}", dest.display()))?;
+    let mut written = 0;
+    for chunk in &entry.chunks {
+        let data = restore.store.read_object(chunk)?;
+        file.write_all(&data)
+            .map_err(|_| format!("Unable to write file: {}", dest.display()))?;
+        written += data.len() as u64;
+        restore.report(written);
+    }
+    Ok(())
+}
+
+fn apply_metadata(entry: &Entry, dest: &Path) -> Result<(), String> {
+    // Ownership can only be restored when running as root, rsync ignores this failure as well
+    let _ = std::os::unix::fs::lchown(dest, Some(entry.uid), Some(entry.gid));
+
+    if entry.kind != EntryKind::Symlink {
+        fs::set_permissions(dest, fs::Permissions::from_mode(entry.mode))
+            .map_err(|_| format!("Unable to set permissions on: {}", dest.display()))?;
+    }
+
+    let mtime = FileTime::from_unix_time(entry.mtime, entry.mtime_nsec);
+    filetime::set_symlink_file_times(dest, mtime, mtime)
+        .map_err(|_| format!("Unable to set modification time on: {}", dest.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempdir::TempDir;
+
+    struct TestRepo {
+        dir: TempDir,
+        conf: Config,
+    }
+
+    impl TestRepo {
+        fn new(settings: &str) -> TestRepo {
+            let dir = TempDir::new("chunks").unwrap();
+            fs::create_dir(dir.path().join("src")).unwrap();
+            let conf = TestRepo::make_conf(&dir, settings);
+            TestRepo { dir, conf }
+        }
+
+        fn make_conf(dir: &TempDir, settings: &str) -> Config {
+            Config::from_toml(&format!(
+                "backup_dir = '{}'\nrepo_dir = '{}'\nformat = 'chunked'\nprogress = 'none'\n{}",
+                dir.path().join("src").display(), dir.path().join("repo").display(), settings,
+            ), &[])
+        }
+
+        fn reconfigure(&mut self, settings: &str) {
+            self.conf = TestRepo::make_conf(&self.dir, settings);
+        }
+
+        fn src(&self) -> PathBuf {
+            self.dir.path().join("src")
+        }
+
+        fn commit(&self, name: &str) -> Commit {
+            let mut log = CommitLog::new(&self.conf, name);
+            make_commit(&self.conf, &self.src(), name.to_string(), String::new(), &mut log, false).unwrap()
+        }
+
+        fn restore(&self, commit: &Commit, dest: &str) -> PathBuf {
+            let dest = self.dir.path().join(dest);
+            fs::create_dir_all(&dest).unwrap();
+            restore_to(&self.conf, commit, String::new(), &dest, false).unwrap();
+            dest
+        }
+
+        fn chunks_of(&self, commit: &Commit, path: &str) -> Vec<String> {
+            find_entry(&Store::open(&self.conf), commit, path).unwrap().chunks
+        }
+
+        fn objects(&self) -> HashSet<PathBuf> {
+            let mut objects = HashSet::new();
+            for prefix in fs::read_dir(self.conf.get_objects_folder()).unwrap() {
+                for object in fs::read_dir(prefix.unwrap().path()).unwrap() {
+                    objects.insert(object.unwrap().path());
+                }
+            }
+            objects
+        }
+    }
+
+    /// Bytes that don't repeat or compress, long enough to be split into several chunks
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    fn write_file_with_mode(path: &Path, contents: &[u8], mode: u32) {
+        fs::write(path, contents).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn fill_source(src: &Path) {
+        write_file_with_mode(&src.join("notes.txt"), b"hello\n", 0o644);
+        write_file_with_mode(&src.join("big.bin"), &random_bytes(1024 * 1024, 1), 0o600);
+        write_file_with_mode(&src.join("empty"), b"", 0o640);
+        fs::create_dir(src.join("scripts")).unwrap();
+        write_file_with_mode(&src.join("scripts").join("run.sh"), b"#!/bin/sh\necho hi\n", 0o755);
+        symlink("../notes.txt", src.join("scripts").join("notes")).unwrap();
+        fs::set_permissions(src.join("scripts"), fs::Permissions::from_mode(0o750)).unwrap();
+    }
+
+    fn assert_same_tree(expected: &Path, actual: &Path) {
+        let mut expected_names: Vec<_> = fs::read_dir(expected).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        let mut actual_names: Vec<_> = fs::read_dir(actual).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        expected_names.sort();
+        actual_names.sort();
+        assert_eq!(expected_names, actual_names, "entries of {}", actual.display());
+
+        for name in expected_names {
+            let (expected, actual) = (expected.join(&name), actual.join(&name));
+            let expected_metadata = fs::symlink_metadata(&expected).unwrap();
+            let actual_metadata = fs::symlink_metadata(&actual).unwrap();
+            assert_eq!(expected_metadata.file_type(), actual_metadata.file_type(), "type of {}", actual.display());
+            assert_eq!(expected_metadata.mtime(), actual_metadata.mtime(), "mtime of {}", actual.display());
+
+            if expected_metadata.file_type().is_symlink() {
+                assert_eq!(fs::read_link(&expected).unwrap(), fs::read_link(&actual).unwrap());
+                continue;
+            }
+            assert_eq!(expected_metadata.mode(), actual_metadata.mode(), "mode of {}", actual.display());
+            if expected_metadata.is_dir() {
+                assert_same_tree(&expected, &actual);
+            } else {
+                assert!(fs::read(&expected).unwrap() == fs::read(&actual).unwrap(), "contents of {}", actual.display());
+            }
+        }
+    }
+
+    #[test]
+    fn commits_restore_to_the_same_tree() {
+        let repo = TestRepo::new("");
+        fill_source(&repo.src());
+        let commit = repo.commit("first");
+
+        let restored = repo.restore(&commit, "restored");
+        assert_same_tree(&repo.src(), &restored);
+        assert!(repo.chunks_of(&commit, "big.bin").len() > 1, "a large file is split into chunks");
+    }
+
+    #[test]
+    fn restores_replace_what_changed_since() {
+        let repo = TestRepo::new("");
+        fill_source(&repo.src());
+        let commit = repo.commit("first");
+
+        let restored = repo.restore(&commit, "restored");
+        fs::write(restored.join("notes.txt"), b"edited").unwrap();
+        fs::write(restored.join("scripts").join("extra"), b"not in the commit").unwrap();
+        fs::remove_file(restored.join("empty")).unwrap();
+        fs::set_permissions(restored.join("big.bin"), fs::Permissions::from_mode(0o400)).unwrap();
+
+        repo.restore(&commit, "restored");
+        assert_same_tree(&repo.src(), &restored);
+    }
+
+    #[test]
+    fn restores_dont_write_through_hard_links() {
+        let repo = TestRepo::new("");
+        fs::write(repo.src().join("notes.txt"), b"from the commit").unwrap();
+        let commit = repo.commit("first");
+
+        let restored = repo.dir.path().join("restored");
+        fs::create_dir(&restored).unwrap();
+        let outside = repo.dir.path().join("outside");
+        fs::write(&outside, b"elsewhere").unwrap();
+        fs::hard_link(&outside, restored.join("notes.txt")).unwrap();
+
+        repo.restore(&commit, "restored");
+        assert_eq!(fs::read(restored.join("notes.txt")).unwrap(), b"from the commit");
+        assert_eq!(fs::read(&outside).unwrap(), b"elsewhere");
+    }
+
+    #[test]
+    fn unchanged_files_reuse_their_chunks() {
+        let repo = TestRepo::new("");
+        fill_source(&repo.src());
+        let first = repo.commit("first");
+        let objects = repo.objects();
+
+        let unchanged = repo.commit("unchanged");
+        assert_eq!(repo.objects(), objects, "nothing new is stored without changes");
+        assert_eq!(unchanged.get_tree(), first.get_tree());
+
+        fs::write(repo.src().join("notes.txt"), b"changed\n").unwrap();
+        let changed = repo.commit("changed");
+        assert_eq!(repo.chunks_of(&changed, "big.bin"), repo.chunks_of(&first, "big.bin"));
+        assert_ne!(repo.chunks_of(&changed, "notes.txt"), repo.chunks_of(&first, "notes.txt"));
+        assert_eq!(changed.get_stats().unwrap().files_changed, 1);
+    }
+
+    #[test]
+    fn garbage_collection_keeps_referenced_objects() {
+        let repo = TestRepo::new("");
+        fill_source(&repo.src());
+        let first = repo.commit("first");
+        let first_only = repo.chunks_of(&first, "big.bin");
+
+        write_file_with_mode(&repo.src().join("big.bin"), &random_bytes(512 * 1024, 2), 0o600);
+        let second = repo.commit("second");
+        let orphan = Store::open(&repo.conf).write_object(b"referenced by nothing", false).unwrap();
+
+        assert_eq!(collect_garbage(&repo.conf, false), "referenced by nothing".len() as u64);
+        let store = Store::open(&repo.conf);
+        assert!(!store.has_object(&orphan));
+        assert!(first_only.iter().all(|chunk| store.has_object(chunk)), "chunks of older commits are kept");
+
+        fs::remove_dir_all(first.get_folder()).unwrap();
+        assert!(collect_garbage(&repo.conf, false) > 0);
+        assert!(first_only.iter().all(|chunk| !store.has_object(chunk)), "chunks of deleted commits go");
+        assert_same_tree(&repo.src(), &repo.restore(&second, "restored"));
+    }
+
+    #[test]
+    fn compressed_objects_restore_the_same_bytes() {
+        let repo = TestRepo::new("compression = 'zstd'");
+        fill_source(&repo.src());
+        fs::write(repo.src().join("log.txt"), "the same line over and over\n".repeat(10000)).unwrap();
+        fs::write(repo.src().join("photo.jpg"), "compressible, but stored as is\n".repeat(1000)).unwrap();
+        let commit = repo.commit("first");
+
+        let store = Store::open(&repo.conf);
+        let is_compressed = |chunk: &String| store.compressed_object_path(chunk).exists();
+        assert!(repo.chunks_of(&commit, "log.txt").iter().all(is_compressed));
+        assert!(!repo.chunks_of(&commit, "photo.jpg").iter().any(is_compressed), "precompressed formats are skipped");
+        assert!(!repo.chunks_of(&commit, "big.bin").iter().any(is_compressed), "incompressible data is stored as is");
+        let sizes: u64 = repo.chunks_of(&commit, "log.txt").iter().map(|chunk| store.object_size(chunk).unwrap()).sum();
+        assert_eq!(sizes, 28 * 10000, "sizes are those of the uncompressed data");
+
+        assert_same_tree(&repo.src(), &repo.restore(&commit, "restored"));
+    }
+
+    #[test]
+    fn compressed_and_plain_objects_mix() {
+        let mut repo = TestRepo::new("");
+        fs::write(repo.src().join("plain.txt"), "stored before compression was on\n".repeat(1000)).unwrap();
+        let plain = repo.commit("plain");
+
+        repo.reconfigure("compression = 'zstd'");
+        fs::write(repo.src().join("compressed.txt"), "stored after compression was on\n".repeat(1000)).unwrap();
+        let mixed = repo.commit("mixed");
+        let store = Store::open(&repo.conf);
+        assert_eq!(repo.chunks_of(&mixed, "plain.txt"), repo.chunks_of(&plain, "plain.txt"), "plain objects are still deduplicated");
+        assert!(!store.compressed_object_path(&repo.chunks_of(&mixed, "plain.txt")[0]).exists());
+        assert!(store.compressed_object_path(&repo.chunks_of(&mixed, "compressed.txt")[0]).exists());
+
+        // The same content written again under compression is found in either form
+        fs::remove_dir_all(repo.src()).unwrap();
+        fs::create_dir(repo.src()).unwrap();
+        fs::write(repo.src().join("copy.txt"), "stored before compression was on\n".repeat(1000)).unwrap();
+        let objects = repo.objects();
+        let copy = repo.commit("copy");
+        assert_eq!(repo.chunks_of(&copy, "copy.txt"), repo.chunks_of(&plain, "plain.txt"));
+        assert_eq!(repo.objects().len(), objects.len() + 1, "only the new tree is stored");
+
+        fs::remove_dir_all(plain.get_folder()).unwrap();
+        fs::remove_dir_all(mixed.get_folder()).unwrap();
+        collect_garbage(&repo.conf, false);
+        assert!(store.has_object(&repo.chunks_of(&copy, "copy.txt")[0]));
+        assert_same_tree(&repo.src(), &repo.restore(&copy, "restored"));
+    }
+
+    #[test]
+    fn excluded_paths_are_left_out_and_left_alone() {
+        let repo = TestRepo::new("excludes = ['*.tmp', '/scripts/']");
+        fill_source(&repo.src());
+        fs::write(repo.src().join("build.tmp"), b"scratch").unwrap();
+        let commit = repo.commit("first");
+
+        let store = Store::open(&repo.conf);
+        assert!(find_entry(&store, &commit, "build.tmp").is_err());
+        assert!(find_entry(&store, &commit, "scripts").is_err());
+        assert!(find_entry(&store, &commit, "notes.txt").is_ok());
+        fs::write(repo.src().join("other.tmp"), b"more scratch").unwrap();
+        assert!(get_changes(&repo.conf, &repo.src(), Some(&commit), repo.conf.get_excludes()).unwrap().is_empty());
+
+        // A whole tree restore only replaces what the commit could have stored
+        fs::write(repo.src().join("notes.txt"), b"edited").unwrap();
+        restore(&repo.conf, &commit, String::new(), false).unwrap();
+        assert_eq!(fs::read(repo.src().join("notes.txt")).unwrap(), b"hello\n");
+        assert!(repo.src().join("build.tmp").exists());
+        assert!(repo.src().join("scripts").join("run.sh").exists());
+    }
+}
@@ -1,135 +1,322 @@
 use chrono::offset::Utc;
+use clap::{Args, Parser, Subcommand};
+use clap_complete::Shell;
+use std::path::PathBuf;
 
+/// A simple incremental backup solution for home users
+#[derive(Parser)]
+#[command(name = "resilient", version, propagate_version = true)]
+pub struct Cli {
+    /// Use this config file instead of searching for one
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Override a config key, e.g. --set compression=zstd or --set schedule.interval=1h
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Use the settings of a [profile.<name>] config section
+    #[arg(long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Use this repository instead of the configured repo_dir
+    #[arg(long, global = true, value_name = "PATH")]
+    pub repo: Option<PathBuf>,
+
+    /// Limit the bandwidth of syncs, in KiB/s or with a K, M or G suffix. 0 lifts a configured limit
+    #[arg(long, global = true, value_name = "RATE")]
+    pub bwlimit: Option<String>,
+
+    /// I/O scheduling class of syncs: idle, best-effort or realtime, optionally followed by :0 to :7
+    #[arg(long, global = true, value_name = "CLASS")]
+    pub ionice: Option<String>,
+
+    /// CPU niceness of syncs, from -20 to 19
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    pub nice: Option<i32>,
+
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print every file as it is processed
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
 pub enum Command {
+    /// Make a new commit of the backup directory
     Create(CreateOptions),
+    /// List changes since the latest commit
     Status,
+    /// List all commits, newest first
+    Log,
+    /// Restore files from a commit into the backup directory
     Restore(RestoreOptions),
+    /// Show a single commit
+    Show(ShowOptions),
+    /// Delete commits from the repository
+    Delete(DeleteOptions),
+    /// Delete the commits the [retention] policy doesn't keep
+    Prune(PruneOptions),
+    /// Add a tag to a commit
+    Tag(TagOptions),
+    /// Remove a tag
+    Untag(UntagOptions),
+    /// Protect a commit from pruning and deletion
+    Pin(PinOptions),
+    /// Remove the protection added by pin
+    Unpin(PinOptions),
+    /// Replace the message of a commit
+    AmendMessage(AmendMessageOptions),
+    /// Mount all commits read-only with FUSE
+    Mount(MountOptions),
+    /// Export a commit to a tar archive
+    Export(ExportOptions),
+    /// Import a tar archive as a new commit
+    Import(ImportOptions),
+    /// Copy every commit missing from another repository into it
+    Replicate(ReplicateOptions),
+    /// Browse commits and restore files interactively
+    Tui,
+    /// Serve a password protected web interface for browsing and downloading commits
+    Serve(ServeOptions),
+    /// Make commits according to the [schedule] config section
+    Daemon(DaemonOptions),
+    /// Install a systemd service and timer running create
+    InstallSystemd(SystemdOptions),
+    /// Remove the systemd service and timer
+    UninstallSystemd(UninstallSystemdOptions),
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Print shell completions
+    Completions(CompletionsOptions),
 }
 
-impl Command {
-    pub fn parse_options(args: &[String]) -> Command {
-        if args.len() < 2 {
-            eprintln!("No command provided");
-            std::process::exit(1);
-        }
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration and where each value came from
+    Show,
+    /// Validate the configuration and report every problem found
+    Check,
+}
 
-        match args[1].as_str() {
-            "create" => {
-                let create_options = CreateOptions::parse_options(&args[2..]);
-                Command::Create(create_options)
-            },
-            "status" => {
-                Command::Status
-            },
-            "restore" => {
-                let restore_options = RestoreOptions::parse_options(&args[2..]);
-                Command::Restore(restore_options)
-            }
-            _ => {
-                eprintln!("Unknown command: {}", args[1]);
-                std::process::exit(1);
-            },
-        }
+fn default_commit_name() -> String {
+    let now = Utc::now();
+    let formatted_time = now.format("%Y-%m-%d_%H-%M-%S");
+    format!("{}", formatted_time)
+}
+
+/// Splits a `[commit:]path` spec
+fn parse_pathspec(pathspec: &str) -> (Option<String>, String) {
+    match pathspec.split_once(':') {
+        Some((commit, path)) => (Some(commit.to_string()), path.to_string()),
+        None => (None, pathspec.to_string()),
     }
 }
 
+#[derive(Args)]
 pub struct CreateOptions {
-    pub verbose: bool,
+    /// Name of the commit, defaults to the current time
+    #[arg(short, long, default_value_t = default_commit_name())]
     pub name: String,
+
+    /// Commit message
+    #[arg(short, long)]
     pub message: String,
+
+    /// Only commit when something changed since the latest commit
+    #[arg(long, conflicts_with = "always")]
+    if_changed: bool,
+
+    /// Commit even if create_if_changed is set in the config
+    #[arg(long)]
+    always: bool,
+
+    /// Create a commit for every profile in the config, one after another
+    #[arg(long)]
+    pub all_profiles: bool,
 }
 
 impl CreateOptions {
-    fn parse_options(args: &[String]) -> CreateOptions {
-        let mut index = 0;
-        let arglen = args.len();
-        let mut verbose = false;
-
-        let now = Utc::now();
-        let formatted_time = now.format("%Y-%m-%d_%H-%M-%S");
-        let mut name = format!("{}", formatted_time);
-
-        let mut message = String::new();
-
-        while index < arglen {
-            match args[index].as_str() {
-                "-v" => {
-                    verbose = true;
-                },
-                "-n" => {
-                    if index + 1 >= arglen {
-                        eprintln!("No name provided to -n arg");
-                        std::process::exit(1);
-                    }
-                    name = args[index + 1].clone();
-                    index += 1;
-                },
-                "-m" => {
-                    if index + 1 >= arglen {
-                        eprintln!("No message provided to -m arg");
-                        std::process::exit(1);
-                    }
-                    message = args[index + 1].clone();
-                    index += 1;
-                }
-                _ => {
-                    eprintln!("Unknown argument: {}", args[index]);
-                    std::process::exit(1);
-                }
-            }
-            index += 1;
+    pub fn if_changed(&self) -> Option<bool> {
+        if self.if_changed {
+            Some(true)
+        } else if self.always {
+            Some(false)
+        } else {
+            None
         }
+    }
+}
 
-        if message.is_empty() {
-            eprintln!("Please provide a message via the '-m' argument");
-            std::process::exit(1);
-        }
+#[derive(Args)]
+pub struct RestoreOptions {
+    /// What to restore, as [commit:]path. Restores everything from the latest commit if omitted
+    #[arg(value_name = "[COMMIT:]PATH")]
+    pathspec: Option<String>,
+}
 
-        CreateOptions {
-            verbose,
-            name,
-            message
-        }
+impl RestoreOptions {
+    pub fn commit_and_path(&self) -> (Option<String>, String) {
+        parse_pathspec(self.pathspec.as_deref().unwrap_or(""))
     }
 }
 
-pub struct RestoreOptions {
-    pub verbose: bool,
+#[derive(Args)]
+pub struct ShowOptions {
+    /// Commit or tag, defaults to the latest commit
     pub commit: Option<String>,
-    pub path: String
+
+    /// List what changed relative to the previous commit
+    #[arg(long)]
+    pub changes: bool,
+
+    /// Print the log recorded while making the commit
+    #[arg(long)]
+    pub log: bool,
 }
 
-impl RestoreOptions {
-    fn parse_options(args: &[String]) -> RestoreOptions {
-        let mut index = 0;
-        let arglen = args.len();
-        let mut verbose = false;
-
-        let mut path = String::new();
-        let mut commit = None;
-
-        while index < arglen {
-            match args[index].as_str() {
-                "-v" => {
-                    verbose = true;
-                },
-                pathspec => {
-                    let split_path: Vec<&str> = pathspec.split(":").collect();
-                    if split_path.len() == 1 {
-                        path = split_path[0].to_string();
-                    } else {
-                        commit = Some(split_path[0].to_string());
-                        path = split_path[1].to_string();
-                    }
-                }
-            }
-            index += 1;
-        }
+#[derive(Args)]
+pub struct DeleteOptions {
+    /// Commits or tags to delete
+    #[arg(required = true)]
+    pub commits: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct PruneOptions {
+    /// Only list what would be kept and pruned
+    #[arg(long)]
+    pub dry_run: bool,
+}
 
-        RestoreOptions {
-            verbose,
-            commit,
-            path
+#[derive(Args)]
+pub struct TagOptions {
+    /// Commit or tag to add the tag to
+    pub commit: String,
+
+    /// Tag to add
+    pub label: String,
+}
+
+#[derive(Args)]
+pub struct UntagOptions {
+    /// Tag to remove
+    pub label: String,
+}
+
+#[derive(Args)]
+pub struct PinOptions {
+    /// Commit or tag
+    pub commit: String,
+}
+
+#[derive(Args)]
+pub struct AmendMessageOptions {
+    /// Commit or tag
+    pub commit: String,
+
+    /// New commit message
+    #[arg(short, long)]
+    pub message: String,
+}
+
+#[derive(Args)]
+pub struct MountOptions {
+    /// Directory to mount on
+    pub mountpoint: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ExportOptions {
+    /// What to export, as commit[:path]
+    #[arg(value_name = "COMMIT[:PATH]")]
+    commitspec: String,
+
+    /// Archive to write, compressed with zstd when ending in .zst or .tzst
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+impl ExportOptions {
+    pub fn commit_and_path(&self) -> (String, String) {
+        match self.commitspec.split_once(':') {
+            Some((commit, path)) => (commit.to_string(), path.to_string()),
+            None => (self.commitspec.clone(), String::new()),
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Args)]
+pub struct ImportOptions {
+    /// Tar archive to import
+    pub archive: PathBuf,
+
+    /// Name of the commit, defaults to the current time
+    #[arg(short, long, default_value_t = default_commit_name())]
+    pub name: String,
+
+    /// Commit message, defaults to naming the archive
+    #[arg(short, long)]
+    pub message: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ReplicateOptions {
+    /// Repository to replicate into
+    #[arg(long = "to", value_name = "REPO")]
+    pub target: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ServeOptions {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+}
+
+#[derive(Args)]
+pub struct DaemonOptions {
+    /// Run a single scheduled commit and exit
+    #[arg(long)]
+    pub once: bool,
+}
+
+#[derive(Args)]
+pub struct SystemdOptions {
+    /// Install as a user unit instead of a system unit
+    #[arg(long)]
+    pub user: bool,
+
+    /// Directory to write units to
+    #[arg(long)]
+    pub unit_dir: Option<PathBuf>,
+
+    /// systemd calendar expression for the timer
+    #[arg(long, default_value = "daily")]
+    pub on_calendar: String,
+}
+
+#[derive(Args)]
+pub struct UninstallSystemdOptions {
+    /// Remove user units instead of system units
+    #[arg(long)]
+    pub user: bool,
+
+    /// Directory to remove units from
+    #[arg(long)]
+    pub unit_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct CompletionsOptions {
+    /// Shell to generate completions for
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
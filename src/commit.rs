@@ -1,12 +1,28 @@
-use std::path::Path;
+use std::path::{Component, Path};
 use std::path::PathBuf;
 use std::fs;
 use super::config::Config;
 use std::cmp::Ordering;
 use chrono::offset::Utc;
+use serde::{Deserialize, Serialize};
 
 const COMMIT_FILE_NAME: &str = "info.commit";
 const LATEST_FILE_NAME: &str = "latest.commit";
+const TREE_FILE_NAME: &str = "tree.commit";
+const STATS_FILE_NAME: &str = "stats.commit";
+const METADATA_FILE_NAME: &str = "metadata.commit";
+const TAGS_FILE_NAME: &str = "tags.commit";
+const PINNED_FILE_NAME: &str = "pinned.commit";
+
+/// Summary of what a commit captured, gathered while it was made
+#[derive(Default, Serialize, Deserialize)]
+pub struct CommitStats {
+    pub files_total: u64,
+    pub bytes_total: u64,
+    pub files_changed: u64,
+    pub bytes_changed: u64,
+    pub duration_seconds: f64,
+}
 
 pub struct Commit {
     timestamp: i64,
@@ -14,6 +30,13 @@ pub struct Commit {
     folder: PathBuf
 }
 
+/// Where a commit came from
+#[derive(Default, Serialize, Deserialize)]
+pub struct CommitMetadata {
+    pub parent: Option<String>,
+    pub host: String,
+}
+
 impl Commit {
     pub fn new(folder: PathBuf, message: String) -> Commit {
         let date_time = Utc::now();
@@ -25,103 +48,221 @@ impl Commit {
     }
 
     pub fn get_commits(conf: &Config) -> Vec<Commit> {
+        or_exit(Commit::try_get_commits(conf))
+    }
+
+    pub fn try_get_commits(conf: &Config) -> Result<Vec<Commit>, String> {
         let mut commits = Vec::new();
 
         let repo_dir = conf.get_backups_folder();
-        let backups = repo_dir.read_dir();
-        if backups.is_err() {
-            eprintln!("Unable to read backups from repo");
-            std::process::exit(1);
-        }
-
-        for folder in backups.unwrap() {
+        let backups = repo_dir.read_dir().map_err(|_| "Unable to read backups from repo".to_string())?;
+        for folder in backups {
             if folder.is_err() {
                 continue;
             }
 
+            // info.commit is written last, so a folder without one is a commit still being made
             let folder_entry = folder.unwrap();
             let folder_path = folder_entry.path();
-            if folder_path.is_dir() {
-                commits.push(Commit::parse_commit(&folder_path));
+            if folder_path.is_dir() && folder_path.join(COMMIT_FILE_NAME).exists() {
+                commits.push(Commit::try_parse_commit(&folder_path)?);
             }
         }
 
         commits.sort_by(sort_commits);
-        commits
+        Ok(commits)
     }
 
     pub fn get_latest(conf: &Config) -> Option<Commit> {
-        let latest_file = conf.get_backups_folder().join(LATEST_FILE_NAME);
+        or_exit(Commit::try_get_latest(conf))
+    }
+
+    pub fn try_get_latest(conf: &Config) -> Result<Option<Commit>, String> {
+        let latest_file = Commit::get_latest_file(conf);
         if !latest_file.exists() {
-            return None;
+            return Ok(None);
         }
 
-        let latest_contents = fs::read_to_string(&latest_file);
-        if latest_contents.is_err() {
-            eprintln!("Unable to read latest commit");
-            std::process::exit(1);
+        let latest_contents = fs::read_to_string(&latest_file).map_err(|_| "Unable to read latest commit".to_string())?;
+        Commit::try_parse_commit(&PathBuf::from(latest_contents)).map(Some)
+    }
+
+    /// The file naming the latest commit, rewritten whenever a commit is made
+    pub fn get_latest_file(conf: &Config) -> PathBuf {
+        conf.get_backups_folder().join(LATEST_FILE_NAME)
+    }
+
+    pub fn find(conf: &Config, name: &str) -> Option<Commit> {
+        or_exit(Commit::try_find(conf, name))
+    }
+
+    /// Like find, but reports a commit that can't be read instead of exiting. `name` must be
+    /// a plain directory name, anything else is never a commit.
+    pub fn try_find(conf: &Config, name: &str) -> Result<Option<Commit>, String> {
+        let mut components = Path::new(name).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            return Ok(None);
         }
 
-        Some(Commit::parse_commit(&PathBuf::from(latest_contents.unwrap())))
+        let commit_dir = conf.get_backups_folder().join(name);
+        if !commit_dir.is_dir() || !commit_dir.join(COMMIT_FILE_NAME).exists() {
+            return Ok(None);
+        }
+
+        Commit::try_parse_commit(&commit_dir).map(Some)
+    }
+
+    pub fn find_by_tag(conf: &Config, tag: &str) -> Option<Commit> {
+        or_exit(Commit::try_find_by_tag(conf, tag))
+    }
+
+    pub fn try_find_by_tag(conf: &Config, tag: &str) -> Result<Option<Commit>, String> {
+        let commits = Commit::try_get_commits(conf)?;
+        Ok(commits.into_iter().find(|commit| commit.get_tags().iter().any(|other| other == tag)))
     }
 
     pub fn get_folder(&self) -> &Path {
         &self.folder
     }
 
-    pub fn write_commit_file(&self) {
+    pub fn get_name(&self) -> String {
+        self.folder.file_name().unwrap().to_string_lossy().to_string()
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn set_message(&mut self, message: String) -> Result<(), String> {
+        self.message = message;
+        self.write_commit_file()
+    }
+
+    pub fn get_tags(&self) -> Vec<String> {
+        match fs::read_to_string(self.folder.join(TAGS_FILE_NAME)) {
+            Ok(contents) => contents.lines().filter(|line| !line.is_empty()).map(|line| line.to_string()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn write_tags_file(&self, tags: &[String]) -> Result<(), String> {
+        let tags_file = self.folder.join(TAGS_FILE_NAME);
+        if tags.is_empty() {
+            if tags_file.exists() {
+                fs::remove_file(&tags_file).map_err(|_| "Unable to remove tags.commit file".to_string())?;
+            }
+            return Ok(());
+        }
+
+        let mut contents = String::new();
+        for tag in tags {
+            contents += tag;
+            contents += "\n";
+        }
+        fs::write(&tags_file, contents).map_err(|_| "Unable to write tags.commit file".to_string())
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.folder.join(PINNED_FILE_NAME).exists()
+    }
+
+    pub fn set_pinned(&self, pinned: bool) -> Result<(), String> {
+        let pinned_file = self.folder.join(PINNED_FILE_NAME);
+        if pinned && !pinned_file.exists() {
+            fs::write(&pinned_file, "").map_err(|_| "Unable to write pinned.commit file".to_string())
+        } else if !pinned && pinned_file.exists() {
+            fs::remove_file(&pinned_file).map_err(|_| "Unable to remove pinned.commit file".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.folder.join(TREE_FILE_NAME).exists()
+    }
+
+    pub fn get_tree(&self) -> String {
+        or_exit(self.try_get_tree())
+    }
+
+    pub fn try_get_tree(&self) -> Result<String, String> {
+        let tree_file = self.folder.join(TREE_FILE_NAME);
+        let tree_contents = fs::read_to_string(&tree_file)
+            .map_err(|_| format!("Unable to read tree file: {}", tree_file.display()))?;
+        Ok(tree_contents.trim().to_string())
+    }
+
+    pub fn get_stats(&self) -> Option<CommitStats> {
+        let contents = fs::read_to_string(self.folder.join(STATS_FILE_NAME)).ok()?;
+        toml::from_str(&contents).ok()
+    }
+
+    pub fn write_stats_file(&self, stats: &CommitStats) -> Result<(), String> {
+        let contents = toml::to_string(stats).map_err(|_| "Unable to serialize commit stats".to_string())?;
+        fs::write(self.folder.join(STATS_FILE_NAME), contents)
+            .map_err(|_| "Unable to write stats.commit file".to_string())
+    }
+
+    pub fn get_metadata(&self) -> Option<CommitMetadata> {
+        let contents = fs::read_to_string(self.folder.join(METADATA_FILE_NAME)).ok()?;
+        toml::from_str(&contents).ok()
+    }
+
+    pub fn write_metadata_file(&self, parent: Option<&Commit>) -> Result<(), String> {
+        let metadata = CommitMetadata {
+            parent: parent.map(|parent| parent.get_name()),
+            host: get_hostname(),
+        };
+        let contents = toml::to_string(&metadata).map_err(|_| "Unable to serialize commit metadata".to_string())?;
+        fs::write(self.folder.join(METADATA_FILE_NAME), contents)
+            .map_err(|_| "Unable to write metadata.commit file".to_string())
+    }
+
+    pub fn write_tree_file(&self, tree: &str) -> Result<(), String> {
+        fs::write(self.folder.join(TREE_FILE_NAME), tree)
+            .map_err(|_| "Unable to write tree.commit file".to_string())
+    }
+
+    pub fn write_commit_file(&self) -> Result<(), String> {
         let contents = format!("{}\n{}", self.timestamp, self.message);
 
         if !self.folder.exists() {
-            eprintln!("Backup folder: '{}' doesn't exist", self.folder.display());
-            std::process::exit(1);
+            return Err(format!("Backup folder: '{}' doesn't exist", self.folder.display()));
         }
 
-        let write_res = fs::write(&self.folder.join(COMMIT_FILE_NAME), &contents);
-        if write_res.is_err() {
-            eprintln!("Unable to write info.commit file");
-            std::process::exit(1);
-        }
+        fs::write(self.folder.join(COMMIT_FILE_NAME), &contents)
+            .map_err(|_| "Unable to write info.commit file".to_string())
     }
 
-    pub fn write_latest(conf: &Config, commit: &Commit) {
+    pub fn write_latest(conf: &Config, commit: &Commit) -> Result<(), String> {
         let contents = format!("{}", commit.folder.display());
-        let latest_file = conf.get_backups_folder().join(LATEST_FILE_NAME);
+        let latest_file = Commit::get_latest_file(conf);
 
-        let write_res = fs::write(&latest_file, &contents);
-        if write_res.is_err() {
-            println!("Unable to write latest commit");
-            std::process::exit(1);
-        }
+        fs::write(&latest_file, &contents)
+            .map_err(|_| "Unable to write latest commit".to_string())
     }
 
-    fn parse_commit(path: &Path) -> Commit {
+    fn try_parse_commit(path: &Path) -> Result<Commit, String> {
         let commit_file = path.join(COMMIT_FILE_NAME);
         if !commit_file.exists() {
-            eprintln!("No commit file found for commit: {}", path.display());
-            std::process::exit(1);
+            return Err(format!("No commit file found for commit: {}", path.display()));
         }
 
-        let commit_contents = fs::read_to_string(&commit_file);
-        if commit_contents.is_err() {
-            eprintln!("Unable to read commit file: {}", commit_file.display());
-            std::process::exit(1);
-        }
-
-        let commit_contents = commit_contents.unwrap();
+        let commit_contents = fs::read_to_string(&commit_file)
+            .map_err(|_| format!("Unable to read commit file: {}", commit_file.display()))?;
         let file_lines:Vec<&str> = commit_contents.lines().collect();
 
         if file_lines.is_empty() {
-            eprintln!("No content in commit file: {}", commit_file.display());
-            std::process::exit(1);
+            return Err(format!("No content in commit file: {}", commit_file.display()));
         }
 
         let timestamp_str = file_lines[0];
-        let timestamp = timestamp_str.parse::<i64>();
-        if timestamp.is_err() {
-            eprintln!("Unable to parse '{}' as a timestamp", timestamp_str);
-            std::process::exit(1);
-        }
+        let timestamp = timestamp_str.parse::<i64>()
+            .map_err(|_| format!("Unable to parse '{}' as a timestamp", timestamp_str))?;
 
         let message_strings = file_lines[1..].iter();
         let mut message = String::new();
@@ -130,14 +271,35 @@ impl Commit {
             message += "\n";
         }
 
-        Commit {
-            timestamp: timestamp.unwrap(),
+        Ok(Commit {
+            timestamp,
             message,
             folder: path.to_path_buf()
+        })
+    }
+}
+
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
         }
     }
 }
 
+fn get_hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let res = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if res != 0 {
+        return String::new();
+    }
+
+    let len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).to_string()
+}
+
 fn sort_commits(c1: &Commit, c2: &Commit) -> Ordering {
     if c1.timestamp < c2.timestamp {
         Ordering::Less
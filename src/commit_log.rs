@@ -0,0 +1,91 @@
+use super::config::Config;
+use super::commit::Commit;
+use chrono::Local;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const COMMIT_LOG_FILE_NAME: &str = "commit.log";
+const REPO_LOG_FILE_NAME: &str = "resilient.log";
+const REPO_LOG_MAX_SIZE: u64 = 1024 * 1024;
+const REPO_LOG_KEEP: u32 = 5;
+
+/// Record of everything that happened while making or restoring a commit. Events also go
+/// to the repository log straight away so failed runs, which leave no commit behind, still
+/// leave a trace.
+pub struct CommitLog {
+    repo_log: PathBuf,
+    commit: String,
+    entries: String,
+}
+
+impl CommitLog {
+    pub fn new(conf: &Config, commit: &str) -> CommitLog {
+        CommitLog {
+            repo_log: conf.get_repo_dir().join(REPO_LOG_FILE_NAME),
+            commit: commit.to_string(),
+            entries: String::new(),
+        }
+    }
+
+    pub fn get_commit(&self) -> &str {
+        &self.commit
+    }
+
+    pub fn event(&mut self, message: &str) {
+        let now = Local::now().format("%Y-%m-%d %H:%M:%S");
+        self.entries += &format!("[{}] {}\n", now, message);
+        append_repo_log(&self.repo_log, &format!("[{}] {}: {}\n", now, self.commit, message));
+    }
+
+    pub fn output(&mut self, output: &str) {
+        self.entries += output;
+        if !output.is_empty() && !output.ends_with('\n') {
+            self.entries += "\n";
+        }
+    }
+
+    pub fn write_to(&self, commit: &Commit) {
+        if self.entries.is_empty() {
+            return;
+        }
+
+        let log_file = commit.get_folder().join(COMMIT_LOG_FILE_NAME);
+        let write_res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file)
+            .and_then(|mut file| file.write_all(self.entries.as_bytes()));
+        if write_res.is_err() {
+            eprintln!("Unable to write commit log: {}", log_file.display());
+        }
+    }
+}
+
+pub fn read(commit: &Commit) -> Option<String> {
+    fs::read_to_string(commit.get_folder().join(COMMIT_LOG_FILE_NAME)).ok()
+}
+
+fn append_repo_log(repo_log: &Path, line: &str) {
+    let needs_rotation = fs::metadata(repo_log).map(|metadata| metadata.len() >= REPO_LOG_MAX_SIZE).unwrap_or(false);
+    if needs_rotation {
+        for index in (1..REPO_LOG_KEEP).rev() {
+            let _ = fs::rename(rotated_path(repo_log, index), rotated_path(repo_log, index + 1));
+        }
+        let _ = fs::rename(repo_log, rotated_path(repo_log, 1));
+    }
+
+    // Logging must never be the reason a backup fails
+    let _ = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(repo_log)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+}
+
+fn rotated_path(repo_log: &Path, index: u32) -> PathBuf {
+    let mut rotated = repo_log.as_os_str().to_os_string();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
@@ -2,86 +2,1078 @@ use std::path::PathBuf;
 use std::path::Path;
 use toml::Value;
 use toml::value::Table;
-use dirs;
+use super::excludes::Excludes;
+use super::schedule::Trigger;
+use super::notify::{self, Event};
+use super::progress::ProgressMode;
+use super::throttle::{self, IoClass, Limits, ThrottleConfig, ThrottleWindow};
+use chrono::NaiveTime;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
+use std::time::Duration;
 
 const CONFIG_FILE_VAR_NAME: &str = "RESILIENT_CONFIG_PATH";
 const CONFIG_FILE_CONFIG_PATH: &str = "resilient/resilient.conf";
+const SYSTEM_CONFIG_PATH: &str = "/etc/resilient/resilient.conf";
+const ENV_OVERRIDE_PREFIX: &str = "RESILIENT_";
 
 const BACKUP_DIR_KEY: &str = "backup_dir";
 const REPO_DIR_KEY: &str = "repo_dir";
+const FORMAT_KEY: &str = "format";
+const COMPRESSION_KEY: &str = "compression";
+const COMPRESSION_LEVEL_KEY: &str = "compression_level";
+const METRICS_FILE_KEY: &str = "metrics_file";
+const CREATE_IF_CHANGED_KEY: &str = "create_if_changed";
+const PROGRESS_KEY: &str = "progress";
+const PROGRESS_INTERVAL_KEY: &str = "progress_interval";
+const EXCLUDES_KEY: &str = "excludes";
+
+const DEFAULT_FORMAT: &str = "rsync";
+const DEFAULT_COMPRESSION: &str = "none";
+const DEFAULT_COMPRESSION_LEVEL: i64 = 3;
+const DEFAULT_PROGRESS: &str = "auto";
+const DEFAULT_PROGRESS_INTERVAL: i64 = 30;
+
+const PROFILE_KEY: &str = "profile";
+const SCHEDULE_KEY: &str = "schedule";
+const SCHEDULE_INTERVAL_KEY: &str = "interval";
+const SCHEDULE_CRON_KEY: &str = "cron";
+const SCHEDULE_MESSAGE_KEY: &str = "message";
+const SCHEDULE_PRUNE_KEY: &str = "prune";
+
+const SERVE_KEY: &str = "serve";
+const SERVE_PASSWORD_KEY: &str = "password";
+
+const THROTTLE_KEY: &str = "throttle";
+const THROTTLE_BWLIMIT_KEY: &str = "bwlimit";
+const THROTTLE_IONICE_KEY: &str = "ionice";
+const THROTTLE_NICE_KEY: &str = "nice";
+const THROTTLE_WINDOW_KEY: &str = "window";
+const THROTTLE_WINDOW_START_KEY: &str = "start";
+const THROTTLE_WINDOW_END_KEY: &str = "end";
+
+const RETENTION_KEY: &str = "retention";
+const KEEP_LAST_KEY: &str = "keep_last";
+const KEEP_DAILY_KEY: &str = "keep_daily";
+const KEEP_WEEKLY_KEY: &str = "keep_weekly";
+const KEEP_MONTHLY_KEY: &str = "keep_monthly";
+
+const HOOKS_KEY: &str = "hooks";
+const PRE_CREATE_HOOK_KEY: &str = "pre_create";
+const POST_CREATE_HOOK_KEY: &str = "post_create";
+const ON_FAILURE_HOOK_KEY: &str = "on_failure";
+const PRE_RESTORE_HOOK_KEY: &str = "pre_restore";
+const POST_RESTORE_HOOK_KEY: &str = "post_restore";
+
+const NOTIFICATIONS_KEY: &str = "notifications";
+const NOTIFY_COMMAND_KEY: &str = "command";
+const NOTIFY_MAIL_TO_KEY: &str = "mail_to";
+const NOTIFY_SENDMAIL_KEY: &str = "sendmail";
+const NOTIFY_WEBHOOK_KEY: &str = "webhook";
+const NOTIFY_DESKTOP_KEY: &str = "desktop";
+
+const DEFAULT_SENDMAIL: &str = "sendmail";
+const DEFAULT_SCHEDULE_MESSAGE: &str = "Scheduled backup {date} {time}";
 
 const BACKUPS_FOLDER: &str = "backups";
+const OBJECTS_FOLDER: &str = "objects";
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum RepoFormat {
+    /// Hard linked copies made by rsync -aAX, keeping everything the filesystem has
+    Rsync,
+    /// Deduplicated and optionally compressed chunks. Extended attributes and ACLs aren't
+    /// stored, and commits fail on device nodes, fifos, sockets and file names that aren't UTF-8.
+    Chunked,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd(i32),
+}
+
+#[derive(Clone)]
+pub struct ScheduleConfig {
+    pub trigger: Trigger,
+    pub message: String,
+    /// Whether each scheduled commit is followed by a prune
+    pub prune: bool,
+}
+
+/// How many commits prune keeps, newest first. Each daily, weekly and monthly slot keeps the
+/// newest commit of a distinct day, week or month.
+#[derive(Clone, Default)]
+pub struct RetentionConfig {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct HooksConfig {
+    pub pre_create: Option<String>,
+    pub post_create: Option<String>,
+    pub on_failure: Option<String>,
+    pub pre_restore: Option<String>,
+    pub post_restore: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct NotificationConfig {
+    pub event: Event,
+    pub command: Option<String>,
+    pub mail_to: Option<String>,
+    pub sendmail: String,
+    /// Posted to with curl, which has to be installed
+    pub webhook: Option<String>,
+    pub desktop: bool,
+}
+
+#[derive(Clone)]
 pub struct Config {
+    path: Option<PathBuf>,
+    overrides: Vec<String>,
+    profile: Option<String>,
     backup_dir: PathBuf,
     repo_dir: PathBuf,
+    format: RepoFormat,
+    compression: Compression,
+    schedule: Option<ScheduleConfig>,
+    excludes: Excludes,
+    retention: Option<RetentionConfig>,
+    hooks: HooksConfig,
+    notifications: Vec<NotificationConfig>,
+    metrics_file: Option<PathBuf>,
+    create_if_changed: bool,
+    serve_password: Option<String>,
+    progress: ProgressMode,
+    progress_interval: Duration,
+    throttle: ThrottleConfig,
 }
 
 impl Config {
-    pub fn parse_config() -> Config {
-        let config_path = Config::get_config_path();
-        let config_contents = fs::read_to_string(&config_path);
-        if config_contents.is_err() {
-            eprintln!("Unable to read config file at location: {}", config_path.display());
+    pub fn parse_config(config_path: Option<&Path>, overrides: &[String], profile: Option<&str>) -> Config {
+        let layers = ConfigLayers::load(config_path, overrides, profile);
+        if report_problems(&layers.check()) {
+            eprintln!("Run 'resilient config check' after fixing the config");
+            std::process::exit(1);
+        }
+
+        Config::from_layers(layers, overrides, profile)
+    }
+
+    fn from_layers(layers: ConfigLayers, overrides: &[String], profile: Option<&str>) -> Config {
+        let toml_table = &layers.table;
+        let backup_dir = get_toml_string_key(toml_table, BACKUP_DIR_KEY);
+        let repo_dir = get_toml_string_key(toml_table, REPO_DIR_KEY);
+        let format = match get_optional_toml_string_key(toml_table, FORMAT_KEY).as_deref() {
+            None | Some("rsync") => RepoFormat::Rsync,
+            Some("chunked") => RepoFormat::Chunked,
+            Some(other) => {
+                eprintln!("Unknown repository format '{}', expected 'rsync' or 'chunked'", other);
+                std::process::exit(1);
+            }
+        };
+
+        let compression_level = get_optional_toml_integer_key(toml_table, COMPRESSION_LEVEL_KEY)
+            .unwrap_or(DEFAULT_COMPRESSION_LEVEL);
+        if !(1..=22).contains(&compression_level) {
+            eprintln!("Compression level must be between 1 and 22, got {}", compression_level);
             std::process::exit(1);
         }
 
-        let config_contents = config_contents.unwrap();
+        let compression = match get_optional_toml_string_key(toml_table, COMPRESSION_KEY).as_deref() {
+            None | Some("none") => Compression::None,
+            Some("zstd") => Compression::Zstd(compression_level as i32),
+            Some(other) => {
+                eprintln!("Unknown compression '{}', expected 'zstd' or 'none'", other);
+                std::process::exit(1);
+            }
+        };
 
-        let config_value = toml::from_str(&config_contents);
-        if config_value.is_err() {
-            eprintln!("Unable to parse toml in config");
+        if compression != Compression::None && format != RepoFormat::Chunked {
+            eprintln!("Compression is only supported by the 'chunked' repository format");
             std::process::exit(1);
         }
 
-        let config_value: Value = config_value.unwrap();
-        if !config_value.is_table() {
-            eprintln!("Toml isn't a table");
+        let schedule = get_optional_toml_table_key(toml_table, SCHEDULE_KEY).map(parse_schedule);
+        let excludes = parse_excludes(toml_table);
+        let retention = get_optional_toml_table_key(toml_table, RETENTION_KEY).map(parse_retention);
+        if schedule.as_ref().is_some_and(|schedule| schedule.prune) && retention.is_none() {
+            eprintln!("Schedule '{}' needs a [{}] section saying what to keep", SCHEDULE_PRUNE_KEY, RETENTION_KEY);
             std::process::exit(1);
         }
+        let hooks = get_optional_toml_table_key(toml_table, HOOKS_KEY).map(parse_hooks).unwrap_or_default();
+        let notifications = get_optional_toml_table_key(toml_table, NOTIFICATIONS_KEY)
+            .map(parse_notifications)
+            .unwrap_or_default();
+        let metrics_file = get_optional_toml_string_key(toml_table, METRICS_FILE_KEY).map(PathBuf::from);
+        let create_if_changed = get_optional_toml_bool_key(toml_table, CREATE_IF_CHANGED_KEY).unwrap_or(false);
+        let serve_password = get_optional_toml_table_key(toml_table, SERVE_KEY)
+            .and_then(|serve| get_optional_toml_string_key(serve, SERVE_PASSWORD_KEY));
+        let throttle = get_optional_toml_table_key(toml_table, THROTTLE_KEY).map(parse_throttle).unwrap_or_default();
 
-        let toml_table = config_value.as_table().unwrap();
-        let backup_dir = get_toml_string_key(&toml_table, BACKUP_DIR_KEY);
-        let repo_dir = get_toml_string_key(&toml_table, REPO_DIR_KEY);
+        let progress_name = get_optional_toml_string_key(toml_table, PROGRESS_KEY).unwrap_or_else(|| DEFAULT_PROGRESS.to_string());
+        let progress = ProgressMode::parse(&progress_name);
+        if progress.is_none() {
+            eprintln!("Unknown progress '{}', expected 'auto', 'bar', 'log', 'json' or 'none'", progress_name);
+            std::process::exit(1);
+        }
+        let progress_interval = get_optional_toml_integer_key(toml_table, PROGRESS_INTERVAL_KEY)
+            .unwrap_or(DEFAULT_PROGRESS_INTERVAL);
+        if progress_interval < 1 {
+            eprintln!("Progress interval must be at least 1 second, got {}", progress_interval);
+            std::process::exit(1);
+        }
 
         Config {
+            path: layers.path,
+            overrides: overrides.to_vec(),
+            profile: profile.map(|profile| profile.to_string()),
             backup_dir: PathBuf::from(backup_dir),
             repo_dir: PathBuf::from(repo_dir),
+            format,
+            compression,
+            schedule,
+            excludes,
+            retention,
+            hooks,
+            notifications,
+            metrics_file,
+            create_if_changed,
+            serve_password,
+            progress: progress.unwrap(),
+            progress_interval: Duration::from_secs(progress_interval as u64),
+            throttle,
+        }
+    }
+
+    pub fn with_repo_dir(&self, repo_dir: PathBuf) -> Config {
+        Config {
+            repo_dir,
+            ..self.clone()
         }
     }
 
+    pub fn with_progress_mode(&self, progress: ProgressMode) -> Config {
+        Config {
+            progress,
+            ..self.clone()
+        }
+    }
+
+    /// Limits from the command line, applied whatever the config says
+    pub fn with_forced_limits(&self, forced: Limits) -> Config {
+        let mut throttle = self.throttle.clone();
+        throttle.forced = forced;
+        Config {
+            throttle,
+            ..self.clone()
+        }
+    }
+
+    pub fn with_backup_dir(&self, backup_dir: PathBuf) -> Config {
+        Config {
+            backup_dir,
+            ..self.clone()
+        }
+    }
+
+    /// The highest priority config file that was loaded, if any
+    pub fn get_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn get_profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The `--set` overrides given on the command line
+    pub fn get_overrides(&self) -> &[String] {
+        &self.overrides
+    }
+
+    pub fn get_repo_dir(&self) -> &Path {
+        &self.repo_dir
+    }
+
     pub fn get_backups_folder(&self) -> PathBuf {
         self.repo_dir.join(BACKUPS_FOLDER)
     }
     
+    pub fn get_objects_folder(&self) -> PathBuf {
+        self.repo_dir.join(OBJECTS_FOLDER)
+    }
+
     pub fn get_backup_location(&self) -> &Path {
         &self.backup_dir
     }
 
-    fn get_config_path() -> PathBuf {
-        let config_path = dirs::config_dir();
-        if config_path.is_some() {
-            let config_path = config_path.unwrap();
-            let config_file_path = config_path.join(CONFIG_FILE_CONFIG_PATH);
-            if config_file_path.exists() {
-                return config_file_path;
+    pub fn get_format(&self) -> RepoFormat {
+        self.format
+    }
+
+    pub fn get_compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn get_schedule(&self) -> Option<&ScheduleConfig> {
+        self.schedule.as_ref()
+    }
+
+    pub fn get_excludes(&self) -> &Excludes {
+        &self.excludes
+    }
+
+    /// The retention policy prune applies, None if prune isn't configured
+    pub fn get_retention(&self) -> Option<&RetentionConfig> {
+        self.retention.as_ref()
+    }
+
+    pub fn get_hooks(&self) -> &HooksConfig {
+        &self.hooks
+    }
+
+    pub fn get_notifications(&self) -> &[NotificationConfig] {
+        &self.notifications
+    }
+
+    pub fn get_metrics_file(&self) -> Option<&Path> {
+        self.metrics_file.as_deref()
+    }
+
+    pub fn get_create_if_changed(&self) -> bool {
+        self.create_if_changed
+    }
+
+    pub fn get_progress_mode(&self) -> ProgressMode {
+        self.progress
+    }
+
+    /// How often log and json progress lines are printed
+    pub fn get_progress_interval(&self) -> Duration {
+        self.progress_interval
+    }
+
+    pub fn get_throttle(&self) -> &ThrottleConfig {
+        &self.throttle
+    }
+
+    /// Password protecting the web interface started by `serve`
+    pub fn get_serve_password(&self) -> Option<&str> {
+        self.serve_password.as_deref()
+    }
+}
+
+/// Validates the configuration for `config check`, exiting with an error if it can't be used
+pub fn check_config(config_path: Option<&Path>, overrides: &[String], profile: Option<&str>) {
+    let layers = ConfigLayers::load(config_path, overrides, profile);
+    if report_problems(&layers.check()) {
+        std::process::exit(1);
+    }
+
+    // Types and values are validated while parsing, which exits on the first bad one
+    let conf = Config::from_layers(layers, overrides, profile);
+    report_problems(&notify::check_programs(&conf));
+    if conf.get_format() == RepoFormat::Chunked {
+        println!("Note: the chunked format doesn't store extended attributes or ACLs, and refuses to commit device nodes, fifos, sockets and file names that aren't UTF-8. Use format = \"rsync\" to keep those.");
+    }
+    println!("Config OK");
+}
+
+/// Prints every problem, returning whether any of them is an error
+fn report_problems(problems: &[Problem]) -> bool {
+    let mut has_errors = false;
+    for problem in problems {
+        match problem.severity {
+            Severity::Warning => eprintln!("Warning: {}", problem.message),
+            Severity::Error => {
+                eprintln!("Error: {}", problem.message);
+                has_errors = true;
+            },
+        }
+    }
+    has_errors
+}
+
+/// The merged result of every configuration source, remembering where each key came from
+pub struct ConfigLayers {
+    table: Table,
+    origins: BTreeMap<String, String>,
+    path: Option<PathBuf>,
+    profiles: Table,
+}
+
+#[derive(PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[cfg(test)]
+impl Config {
+    /// The defaults with `toml` and then `overrides` on top, without reading config files or
+    /// the environment
+    pub fn from_toml(toml: &str, overrides: &[String]) -> Config {
+        let mut layers = ConfigLayers::new();
+        layers.merge(&get_defaults(), "default");
+        layers.merge(&toml::from_str(toml).unwrap(), "test");
+        layers.merge_overrides(overrides);
+        Config::from_layers(layers, overrides, None)
+    }
+}
+
+impl ConfigLayers {
+    /// Loads sources from lowest to highest priority: built-in defaults, the system config,
+    /// the user config, the file named by RESILIENT_CONFIG_PATH, the selected
+    /// [profile.<name>] section, RESILIENT_<KEY> environment variables, the --config file and
+    /// finally --set overrides. A profile defined in the --config file itself applies on top
+    /// of that file, so its top-level keys don't hide the profile's.
+    pub fn load(config_path: Option<&Path>, overrides: &[String], profile: Option<&str>) -> ConfigLayers {
+        let mut layers = ConfigLayers::new();
+        layers.merge(&get_defaults(), "default");
+        layers.merge_files(None);
+
+        // Profiles are only a source of values, never part of the effective config themselves
+        layers.profiles = take_profiles(&mut layers.table);
+        layers.origins.retain(|key, _| !key.starts_with(&format!("{}.", PROFILE_KEY)));
+        let mut config_file = config_path.and_then(|config_path| read_config_file(config_path, true));
+        let config_profiles = config_file.as_mut().map(take_profiles).unwrap_or_default();
+
+        let mut config_profile = None;
+        if let Some(profile) = profile {
+            let profile_table = layers.profiles.get(profile).and_then(|profile_table| profile_table.as_table()).cloned();
+            config_profile = config_profiles.get(profile).and_then(|profile_table| profile_table.as_table()).cloned();
+            if profile_table.is_none() && config_profile.is_none() {
+                eprintln!("Cannot find profile '{}' in config", profile);
+                std::process::exit(1);
+            }
+            if let Some(profile_table) = profile_table {
+                layers.merge(&profile_table, &format!("profile {}", profile));
             }
         }
 
-        let config_env_var = env::var(CONFIG_FILE_VAR_NAME);
-        if config_env_var.is_ok() {
-            let config_env_var = config_env_var.unwrap();
-            let config_path = PathBuf::from(config_env_var);
-            if config_path.exists() {
-                return config_path;
+        for key in ENV_OVERRIDE_KEYS {
+            let var_name = format!("{}{}", ENV_OVERRIDE_PREFIX, key.to_uppercase());
+            if let Ok(value) = env::var(&var_name) {
+                let mut env_table = Table::new();
+                env_table.insert(key.to_string(), parse_override_value(&value));
+                expand_paths(&mut env_table, &current_dir(), &var_name);
+                layers.merge(&env_table, &format!("env {}", var_name));
             }
         }
 
-        eprintln!("Cannot find config file");
+        if let (Some(config_path), Some(config_file)) = (config_path, config_file) {
+            layers.merge(&config_file, &config_path.display().to_string());
+            if let Some(config_profile) = config_profile {
+                layers.merge(&config_profile, &format!("profile {} in {}", profile.unwrap(), config_path.display()));
+            }
+            layers.path = Some(config_path.to_path_buf());
+        }
+        merge_table(&mut layers.profiles, &config_profiles, "", "", &mut BTreeMap::new());
+        layers.merge_overrides(overrides);
+
+        if layers.path.is_none() && !layers.table.contains_key(BACKUP_DIR_KEY) {
+            eprintln!("Cannot find config file");
+            std::process::exit(1);
+        }
+
+        layers
+    }
+
+    /// Names of every [profile.<name>] section across all config files, sorted by name
+    pub fn get_profile_names(config_path: Option<&Path>) -> Vec<String> {
+        let mut layers = ConfigLayers::new();
+        layers.merge_files(config_path);
+
+        match layers.table.get(PROFILE_KEY).and_then(|profiles| profiles.as_table()) {
+            Some(profiles) => profiles.keys().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Looks for mistakes toml parsing alone won't catch: misspelled keys and directories
+    /// that can't work together
+    pub fn check(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        self.check_keys(&self.table, TOP_LEVEL_KEYS, "", &mut problems);
+        for (profile, profile_table) in &self.profiles {
+            match profile_table.as_table() {
+                Some(profile_table) => self.check_keys(profile_table, PROFILE_KEYS, &format!("profile.{}.", profile), &mut problems),
+                None => problems.push(Problem {
+                    severity: Severity::Error,
+                    message: format!("Profile '{}' isn't a table", profile),
+                }),
+            }
+        }
+
+        let path_value = |key: &str| self.table.get(key).and_then(|value| value.as_str()).map(PathBuf::from);
+        if let (Some(backup_dir), Some(repo_dir)) = (path_value(BACKUP_DIR_KEY), path_value(REPO_DIR_KEY)) {
+            if !backup_dir.is_dir() {
+                problems.push(Problem {
+                    severity: Severity::Warning,
+                    message: format!("'{}' doesn't exist or isn't a directory: {}", BACKUP_DIR_KEY, backup_dir.display()),
+                });
+            }
+
+            let backup_dir = normalize_path(&backup_dir);
+            let repo_dir = normalize_path(&repo_dir);
+            if backup_dir == repo_dir {
+                problems.push(Problem {
+                    severity: Severity::Error,
+                    message: format!("'{}' and '{}' are the same directory: {}", BACKUP_DIR_KEY, REPO_DIR_KEY, repo_dir.display()),
+                });
+            } else if repo_dir.starts_with(&backup_dir) {
+                problems.push(Problem {
+                    severity: Severity::Error,
+                    message: format!("'{}' is inside '{}', every commit would back up the repository itself", REPO_DIR_KEY, BACKUP_DIR_KEY),
+                });
+            } else if backup_dir.starts_with(&repo_dir) {
+                problems.push(Problem {
+                    severity: Severity::Error,
+                    message: format!("'{}' is inside '{}', commits would end up mixed with the backed up files", BACKUP_DIR_KEY, REPO_DIR_KEY),
+                });
+            }
+        }
+
+        problems
+    }
+
+    fn check_keys(&self, table: &Table, allowed: &[&str], prefix: &str, problems: &mut Vec<Problem>) {
+        for (key, value) in table {
+            let full_key = format!("{}{}", prefix, key);
+            if !allowed.contains(&key.as_str()) {
+                let suggestion = allowed.iter()
+                    .map(|candidate| (edit_distance(key, candidate), candidate))
+                    .filter(|(distance, _)| *distance <= 2)
+                    .min()
+                    .map(|(_, candidate)| format!(", did you mean '{}'?", candidate))
+                    .unwrap_or_default();
+                problems.push(Problem {
+                    severity: Severity::Warning,
+                    message: format!("Unknown key '{}'{}{}", full_key, self.describe_origin(&full_key), suggestion),
+                });
+                continue;
+            }
+
+            let nested_keys = match key.as_str() {
+                SCHEDULE_KEY => SCHEDULE_KEYS,
+                RETENTION_KEY => RETENTION_KEYS,
+                HOOKS_KEY => HOOK_KEYS,
+                SERVE_KEY => SERVE_KEYS,
+                THROTTLE_KEY => THROTTLE_KEYS,
+                _ => continue,
+            };
+            if let Some(nested) = value.as_table() {
+                self.check_keys(nested, nested_keys, &format!("{}.", full_key), problems);
+            }
+        }
+
+        if let Some(notifications) = table.get(NOTIFICATIONS_KEY).and_then(|value| value.as_table()) {
+            for (event, sink) in notifications {
+                if let Some(sink) = sink.as_table() {
+                    self.check_keys(sink, NOTIFY_KEYS, &format!("{}{}.{}.", prefix, NOTIFICATIONS_KEY, event), problems);
+                }
+            }
+        }
+
+        let windows = table.get(THROTTLE_KEY)
+            .and_then(|throttle| throttle.get(THROTTLE_WINDOW_KEY))
+            .and_then(|windows| windows.as_table());
+        if let Some(windows) = windows {
+            for (name, window) in windows {
+                if let Some(window) = window.as_table() {
+                    let window_prefix = format!("{}{}.{}.{}.", prefix, THROTTLE_KEY, THROTTLE_WINDOW_KEY, name);
+                    self.check_keys(window, THROTTLE_WINDOW_KEYS, &window_prefix, problems);
+                }
+            }
+        }
+    }
+
+    fn describe_origin(&self, key: &str) -> String {
+        match self.origins.get(key) {
+            Some(origin) => format!(" (from {})", origin),
+            None => String::new(),
+        }
+    }
+
+    pub fn print(&self) {
+        for (key, origin) in &self.origins {
+            let mut value = None;
+            let mut table = &self.table;
+            for component in key.split('.') {
+                value = table.get(component);
+                if let Some(Value::Table(nested)) = value {
+                    table = nested;
+                }
+            }
+
+            if let Some(value) = value {
+                println!("{} = {}    # {}", key, value, origin);
+            }
+        }
+    }
+
+    fn new() -> ConfigLayers {
+        ConfigLayers {
+            table: Table::new(),
+            origins: BTreeMap::new(),
+            path: None,
+            profiles: Table::new(),
+        }
+    }
+
+    fn merge_files(&mut self, config_path: Option<&Path>) {
+        self.merge_file(Path::new(SYSTEM_CONFIG_PATH), false);
+        if let Some(user_config_dir) = dirs::config_dir() {
+            self.merge_file(&user_config_dir.join(CONFIG_FILE_CONFIG_PATH), false);
+        }
+        if let Ok(config_env_var) = env::var(CONFIG_FILE_VAR_NAME) {
+            self.merge_file(Path::new(&config_env_var), true);
+        }
+        if let Some(config_path) = config_path {
+            self.merge_file(config_path, true);
+        }
+    }
+
+    fn merge_file(&mut self, path: &Path, required: bool) {
+        if let Some(config_file) = read_config_file(path, required) {
+            self.merge(&config_file, &path.display().to_string());
+            self.path = Some(path.to_path_buf());
+        }
+    }
+
+    fn merge(&mut self, layer: &Table, origin: &str) {
+        merge_table(&mut self.table, layer, "", origin, &mut self.origins);
+    }
+
+    /// Merges `--set key=value` overrides, where dotted keys name nested tables
+    fn merge_overrides(&mut self, overrides: &[String]) {
+        for set in overrides {
+            let (key, value) = match set.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => (key.trim(), value),
+                _ => {
+                    eprintln!("Invalid --set '{}', expected key=value", set);
+                    std::process::exit(1);
+                }
+            };
+
+            let mut set_table = Table::new();
+            let mut components: Vec<&str> = key.split('.').collect();
+            let mut value = parse_override_value(value);
+            while components.len() > 1 {
+                let mut nested = Table::new();
+                nested.insert(components.pop().unwrap().to_string(), value);
+                value = Value::Table(nested);
+            }
+            set_table.insert(components[0].to_string(), value);
+            expand_paths(&mut set_table, &current_dir(), "--set");
+            self.merge(&set_table, "--set");
+        }
+    }
+}
+
+/// Reads a config file with its paths expanded, or None if an optional one doesn't exist
+fn read_config_file(path: &Path, required: bool) -> Option<Table> {
+    if !path.exists() {
+        if required {
+            eprintln!("Unable to read config file at location: {}", path.display());
+            std::process::exit(1);
+        }
+        return None;
+    }
+
+    let config_contents = fs::read_to_string(path);
+    if config_contents.is_err() {
+        eprintln!("Unable to read config file at location: {}", path.display());
         std::process::exit(1);
     }
+
+    let config_value = toml::from_str::<Value>(&config_contents.unwrap());
+    if let Err(error) = config_value {
+        eprintln!("Unable to parse toml in config {}: {}", path.display(), error);
+        std::process::exit(1);
+    }
+
+    let mut config_table = match config_value.unwrap() {
+        Value::Table(config_table) => config_table,
+        _ => {
+            eprintln!("Toml isn't a table");
+            std::process::exit(1);
+        }
+    };
+
+    // Relative paths are relative to the file that contains them, not to wherever resilient runs
+    let config_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let config_dir = env::current_dir().map(|cwd| cwd.join(config_dir)).unwrap_or_else(|_| config_dir.to_path_buf());
+    expand_paths(&mut config_table, &config_dir, &path.display().to_string());
+    Some(config_table)
+}
+
+const PATH_KEYS: &[&str] = &[BACKUP_DIR_KEY, REPO_DIR_KEY, METRICS_FILE_KEY];
+const PROFILE_KEYS: &[&str] = &[
+    BACKUP_DIR_KEY, REPO_DIR_KEY, FORMAT_KEY, COMPRESSION_KEY, COMPRESSION_LEVEL_KEY, METRICS_FILE_KEY,
+    CREATE_IF_CHANGED_KEY, PROGRESS_KEY, PROGRESS_INTERVAL_KEY, EXCLUDES_KEY, RETENTION_KEY, SCHEDULE_KEY, HOOKS_KEY,
+    NOTIFICATIONS_KEY, SERVE_KEY, THROTTLE_KEY,
+];
+const TOP_LEVEL_KEYS: &[&str] = &[
+    BACKUP_DIR_KEY, REPO_DIR_KEY, FORMAT_KEY, COMPRESSION_KEY, COMPRESSION_LEVEL_KEY, METRICS_FILE_KEY,
+    CREATE_IF_CHANGED_KEY, PROGRESS_KEY, PROGRESS_INTERVAL_KEY, EXCLUDES_KEY, RETENTION_KEY, SCHEDULE_KEY, HOOKS_KEY,
+    NOTIFICATIONS_KEY, SERVE_KEY, THROTTLE_KEY, PROFILE_KEY,
+];
+const SCHEDULE_KEYS: &[&str] = &[SCHEDULE_INTERVAL_KEY, SCHEDULE_CRON_KEY, SCHEDULE_MESSAGE_KEY, SCHEDULE_PRUNE_KEY];
+const RETENTION_KEYS: &[&str] = &[KEEP_LAST_KEY, KEEP_DAILY_KEY, KEEP_WEEKLY_KEY, KEEP_MONTHLY_KEY];
+const SERVE_KEYS: &[&str] = &[SERVE_PASSWORD_KEY];
+const THROTTLE_KEYS: &[&str] = &[THROTTLE_BWLIMIT_KEY, THROTTLE_IONICE_KEY, THROTTLE_NICE_KEY, THROTTLE_WINDOW_KEY];
+const THROTTLE_WINDOW_KEYS: &[&str] = &[
+    THROTTLE_WINDOW_START_KEY, THROTTLE_WINDOW_END_KEY, THROTTLE_BWLIMIT_KEY, THROTTLE_IONICE_KEY, THROTTLE_NICE_KEY,
+];
+const HOOK_KEYS: &[&str] = &[
+    PRE_CREATE_HOOK_KEY, POST_CREATE_HOOK_KEY, ON_FAILURE_HOOK_KEY, PRE_RESTORE_HOOK_KEY, POST_RESTORE_HOOK_KEY,
+];
+const NOTIFY_KEYS: &[&str] = &[
+    NOTIFY_COMMAND_KEY, NOTIFY_MAIL_TO_KEY, NOTIFY_SENDMAIL_KEY, NOTIFY_WEBHOOK_KEY, NOTIFY_DESKTOP_KEY,
+];
+
+const ENV_OVERRIDE_KEYS: &[&str] = &[
+    BACKUP_DIR_KEY, REPO_DIR_KEY, FORMAT_KEY, COMPRESSION_KEY, COMPRESSION_LEVEL_KEY,
+    METRICS_FILE_KEY, CREATE_IF_CHANGED_KEY, PROGRESS_KEY, PROGRESS_INTERVAL_KEY,
+];
+
+/// Removes the [profile.<name>] sections from a config table
+fn take_profiles(table: &mut Table) -> Table {
+    match table.remove(PROFILE_KEY) {
+        None => Table::new(),
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            eprintln!("Toml key '{}' isn't a table", PROFILE_KEY);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn merge_table(base: &mut Table, layer: &Table, prefix: &str, origin: &str, origins: &mut BTreeMap<String, String>) {
+    for (key, value) in layer {
+        let full_key = format!("{}{}", prefix, key);
+        if let (Some(Value::Table(base_nested)), Value::Table(layer_nested)) = (base.get_mut(key), value) {
+            merge_table(base_nested, layer_nested, &format!("{}.", full_key), origin, origins);
+            continue;
+        }
+
+        // Whatever was there before is replaced wholesale, including anything nested under it
+        let nested_prefix = format!("{}.", full_key);
+        origins.retain(|existing, _| existing != &full_key && !existing.starts_with(&nested_prefix));
+        record_origins(value, &full_key, origin, origins);
+        base.insert(key.clone(), value.clone());
+    }
+}
+
+fn record_origins(value: &Value, key: &str, origin: &str, origins: &mut BTreeMap<String, String>) {
+    match value {
+        Value::Table(nested) if !nested.is_empty() => {
+            for (nested_key, nested_value) in nested {
+                record_origins(nested_value, &format!("{}.{}", key, nested_key), origin, origins);
+            }
+        },
+        _ => {
+            origins.insert(key.to_string(), origin.to_string());
+        }
+    }
+}
+
+fn get_defaults() -> Table {
+    let mut defaults = Table::new();
+    defaults.insert(FORMAT_KEY.to_string(), Value::String(DEFAULT_FORMAT.to_string()));
+    defaults.insert(COMPRESSION_KEY.to_string(), Value::String(DEFAULT_COMPRESSION.to_string()));
+    defaults.insert(COMPRESSION_LEVEL_KEY.to_string(), Value::Integer(DEFAULT_COMPRESSION_LEVEL));
+    defaults.insert(CREATE_IF_CHANGED_KEY.to_string(), Value::Boolean(false));
+    defaults.insert(PROGRESS_KEY.to_string(), Value::String(DEFAULT_PROGRESS.to_string()));
+    defaults.insert(PROGRESS_INTERVAL_KEY.to_string(), Value::Integer(DEFAULT_PROGRESS_INTERVAL));
+    defaults
+}
+
+/// Expands every path-valued key in the table, including those in [profile.<name>]
+/// sections, resolving relative paths against `base`
+fn expand_paths(table: &mut Table, base: &Path, origin: &str) {
+    for key in PATH_KEYS {
+        if let Some(Value::String(value)) = table.get_mut(*key) {
+            match expand_path(value, base) {
+                Ok(path) => *value = path.display().to_string(),
+                Err(message) => {
+                    eprintln!("Invalid path for '{}' in {}: {}", key, origin, message);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if let Some(Value::Table(profiles)) = table.get_mut(PROFILE_KEY) {
+        for (_, profile_table) in profiles.iter_mut() {
+            if let Value::Table(profile_table) = profile_table {
+                expand_paths(profile_table, base, origin);
+            }
+        }
+    }
+}
+
+/// Expands a leading `~`, `$VAR` and `${VAR}`, then makes the result absolute
+fn expand_path(value: &str, base: &Path) -> Result<PathBuf, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    if rest == "~" || rest.starts_with("~/") {
+        let home = dirs::home_dir().ok_or_else(|| "cannot find the home directory".to_string())?;
+        expanded += &home.display().to_string();
+        rest = &rest[1..];
+    }
+
+    while let Some(start) = rest.find('$') {
+        expanded += &rest[..start];
+        let after = &rest[start + 1..];
+        let (name, remainder) = if let Some(braced) = after.strip_prefix('{') {
+            let end = braced.find('}').ok_or_else(|| format!("unclosed '${{' in '{}'", value))?;
+            (&braced[..end], &braced[end + 1..])
+        } else {
+            let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            return Err(format!("expected a variable name after '$' in '{}'", value));
+        }
+        let var_value = env::var(name).map_err(|_| format!("environment variable '{}' isn't set", name))?;
+        expanded += &var_value;
+        rest = remainder;
+    }
+    expanded += rest;
+
+    Ok(base.join(expanded))
+}
+
+fn current_dir() -> PathBuf {
+    env::current_dir().unwrap_or_default()
+}
+
+/// Resolves symlinks where the path exists so overlapping directories are caught however
+/// they're spelled, falling back to dropping `.` components
+fn normalize_path(path: &Path) -> PathBuf {
+    if let Ok(canonical) = path.canonicalize() {
+        return canonical;
+    }
+    path.components().filter(|component| component.as_os_str() != ".").collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Values given on the command line or in the environment are read as toml when they parse
+/// as such (numbers, booleans, quoted strings) and as plain strings otherwise
+fn parse_override_value(value: &str) -> Value {
+    match toml::from_str::<Table>(&format!("value = {}", value)) {
+        Ok(mut table) => table.remove("value").unwrap(),
+        Err(_) => Value::String(value.to_string()),
+    }
+}
+
+fn parse_schedule(table: &Table) -> ScheduleConfig {
+    let interval = get_optional_toml_string_key(table, SCHEDULE_INTERVAL_KEY);
+    let cron = get_optional_toml_string_key(table, SCHEDULE_CRON_KEY);
+
+    let trigger = match (interval, cron) {
+        (Some(interval), None) => {
+            let trigger = Trigger::parse_interval(&interval);
+            if trigger.is_none() {
+                eprintln!("Invalid schedule interval '{}', expected a number followed by s, m, h or d", interval);
+                std::process::exit(1);
+            }
+            trigger.unwrap()
+        },
+        (None, Some(cron)) => {
+            let trigger = Trigger::parse_cron(&cron);
+            if trigger.is_none() {
+                eprintln!("Invalid cron expression '{}'", cron);
+                std::process::exit(1);
+            }
+            trigger.unwrap()
+        },
+        _ => {
+            eprintln!("Schedule needs exactly one of '{}' or '{}'", SCHEDULE_INTERVAL_KEY, SCHEDULE_CRON_KEY);
+            std::process::exit(1);
+        }
+    };
+
+    let message = get_optional_toml_string_key(table, SCHEDULE_MESSAGE_KEY)
+        .unwrap_or_else(|| DEFAULT_SCHEDULE_MESSAGE.to_string());
+
+    ScheduleConfig {
+        trigger,
+        message,
+        prune: get_optional_toml_bool_key(table, SCHEDULE_PRUNE_KEY).unwrap_or(false),
+    }
+}
+
+fn parse_excludes(table: &Table) -> Excludes {
+    let patterns = match table.get(EXCLUDES_KEY) {
+        None => Vec::new(),
+        Some(Value::Array(patterns)) if patterns.iter().all(|pattern| pattern.is_str()) => {
+            patterns.iter().map(|pattern| pattern.as_str().unwrap().to_string()).collect()
+        },
+        Some(_) => {
+            eprintln!("Toml key '{}' isn't a list of strings", EXCLUDES_KEY);
+            std::process::exit(1);
+        }
+    };
+
+    match Excludes::parse(&patterns) {
+        Ok(excludes) => excludes,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_retention(table: &Table) -> RetentionConfig {
+    let keep = |key: &str| {
+        let count = get_optional_toml_integer_key(table, key).unwrap_or(0);
+        if count < 0 {
+            eprintln!("Retention '{}' can't be negative, got {}", key, count);
+            std::process::exit(1);
+        }
+        count as usize
+    };
+
+    let retention = RetentionConfig {
+        keep_last: keep(KEEP_LAST_KEY),
+        keep_daily: keep(KEEP_DAILY_KEY),
+        keep_weekly: keep(KEEP_WEEKLY_KEY),
+        keep_monthly: keep(KEEP_MONTHLY_KEY),
+    };
+    if retention.keep_last + retention.keep_daily + retention.keep_weekly + retention.keep_monthly == 0 {
+        eprintln!("Retention must keep at least one commit, set one of: {}", RETENTION_KEYS.join(", "));
+        std::process::exit(1);
+    }
+    retention
+}
+
+fn parse_hooks(table: &Table) -> HooksConfig {
+    HooksConfig {
+        pre_create: get_optional_toml_string_key(table, PRE_CREATE_HOOK_KEY),
+        post_create: get_optional_toml_string_key(table, POST_CREATE_HOOK_KEY),
+        on_failure: get_optional_toml_string_key(table, ON_FAILURE_HOOK_KEY),
+        pre_restore: get_optional_toml_string_key(table, PRE_RESTORE_HOOK_KEY),
+        post_restore: get_optional_toml_string_key(table, POST_RESTORE_HOOK_KEY),
+    }
+}
+
+fn parse_notifications(table: &Table) -> Vec<NotificationConfig> {
+    let mut notifications = Vec::new();
+    for event_name in table.keys() {
+        let event = Event::parse(event_name);
+        if event.is_none() {
+            eprintln!("Unknown notification event '{}', expected one of: {}", event_name, Event::names().join(", "));
+            std::process::exit(1);
+        }
+
+        let sink = get_optional_toml_table_key(table, event_name).unwrap();
+        notifications.push(NotificationConfig {
+            event: event.unwrap(),
+            command: get_optional_toml_string_key(sink, NOTIFY_COMMAND_KEY),
+            mail_to: get_optional_toml_string_key(sink, NOTIFY_MAIL_TO_KEY),
+            sendmail: get_optional_toml_string_key(sink, NOTIFY_SENDMAIL_KEY)
+                .unwrap_or_else(|| DEFAULT_SENDMAIL.to_string()),
+            webhook: get_optional_toml_string_key(sink, NOTIFY_WEBHOOK_KEY),
+            desktop: get_optional_toml_bool_key(sink, NOTIFY_DESKTOP_KEY).unwrap_or(false),
+        });
+    }
+    notifications
+}
+
+fn parse_throttle(table: &Table) -> ThrottleConfig {
+    let mut windows = Vec::new();
+    if let Some(window_tables) = get_optional_toml_table_key(table, THROTTLE_WINDOW_KEY) {
+        for name in window_tables.keys() {
+            let window = get_optional_toml_table_key(window_tables, name).unwrap();
+            let start = parse_time_of_day(window, THROTTLE_WINDOW_START_KEY);
+            let end = parse_time_of_day(window, THROTTLE_WINDOW_END_KEY);
+            if start == end {
+                eprintln!("Throttle window '{}' starts and ends at the same time", name);
+                std::process::exit(1);
+            }
+
+            windows.push(ThrottleWindow {
+                start,
+                end,
+                limits: parse_limits(window),
+            });
+        }
+    }
+
+    ThrottleConfig {
+        limits: parse_limits(table),
+        windows,
+        forced: Limits::default(),
+    }
+}
+
+fn parse_limits(table: &Table) -> Limits {
+    let bwlimit = match table.get(THROTTLE_BWLIMIT_KEY) {
+        None => None,
+        Some(Value::Integer(kib)) if *kib >= 0 => Some(*kib as u64 * 1024),
+        Some(Value::String(rate)) if throttle::parse_bwlimit(rate).is_some() => throttle::parse_bwlimit(rate),
+        Some(other) => {
+            eprintln!("Invalid bwlimit {}, expected KiB per second or a number followed by K, M or G", other);
+            std::process::exit(1);
+        }
+    };
+
+    let ionice = get_optional_toml_string_key(table, THROTTLE_IONICE_KEY).map(|ionice| {
+        IoClass::parse(&ionice).unwrap_or_else(|| {
+            eprintln!("Invalid ionice '{}', expected 'idle', 'best-effort' or 'realtime', optionally followed by :0 to :7", ionice);
+            std::process::exit(1);
+        })
+    });
+
+    let nice = get_optional_toml_integer_key(table, THROTTLE_NICE_KEY);
+    if let Some(nice) = nice {
+        if !(-20..=19).contains(&nice) {
+            eprintln!("Niceness must be between -20 and 19, got {}", nice);
+            std::process::exit(1);
+        }
+    }
+
+    Limits {
+        bwlimit,
+        ionice,
+        nice: nice.map(|nice| nice as i32),
+    }
+}
+
+fn parse_time_of_day(table: &Table, key: &str) -> NaiveTime {
+    let value = get_toml_string_key(table, key);
+    let time = NaiveTime::parse_from_str(&value, "%H:%M");
+    if time.is_err() {
+        eprintln!("Invalid time of day '{}' for '{}', expected HH:MM", value, key);
+        std::process::exit(1);
+    }
+    time.unwrap()
 }
 
 fn get_toml_string_key(table: &Table, key: &str) -> String {
@@ -97,5 +1089,153 @@ fn get_toml_string_key(table: &Table, key: &str) -> String {
         std::process::exit(1);
     }
 
-    return value.as_str().unwrap().to_string();
-}
\ No newline at end of file
+    value.as_str().unwrap().to_string()
+}
+
+fn get_optional_toml_string_key(table: &Table, key: &str) -> Option<String> {
+    if table.contains_key(key) {
+        Some(get_toml_string_key(table, key))
+    } else {
+        None
+    }
+}
+
+fn get_optional_toml_integer_key(table: &Table, key: &str) -> Option<i64> {
+    let value = table.get(key)?;
+    if !value.is_integer() {
+        eprintln!("Toml key '{}' isn't an integer", key);
+        std::process::exit(1);
+    }
+
+    value.as_integer()
+}
+
+fn get_optional_toml_bool_key(table: &Table, key: &str) -> Option<bool> {
+    let value = table.get(key)?;
+    if !value.is_bool() {
+        eprintln!("Toml key '{}' isn't a boolean", key);
+        std::process::exit(1);
+    }
+
+    value.as_bool()
+}
+
+fn get_optional_toml_table_key<'a>(table: &'a Table, key: &str) -> Option<&'a Table> {
+    let value = table.get(key)?;
+    if !value.is_table() {
+        eprintln!("Toml key '{}' isn't a table", key);
+        std::process::exit(1);
+    }
+
+    value.as_table()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held by tests that read or change the environment, which is shared by every test thread
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn unknown_key_messages(toml: &str) -> Vec<String> {
+        let table: Table = toml::from_str(toml).unwrap();
+        let mut problems = Vec::new();
+        ConfigLayers::new().check_keys(&table, TOP_LEVEL_KEYS, "", &mut problems);
+        problems.into_iter().map(|problem| problem.message).collect()
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("format", "format"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("repo_dri", "repo_dir"), 2);
+        assert_eq!(edit_distance("fromat", "format"), 2);
+        assert_eq!(edit_distance("größe", "grösse"), 2);
+    }
+
+    #[test]
+    fn unknown_keys_suggest_close_ones() {
+        assert_eq!(
+            unknown_key_messages("repo_dri = \"/srv\""),
+            vec!["Unknown key 'repo_dri', did you mean 'repo_dir'?"],
+        );
+        assert_eq!(
+            unknown_key_messages("compresion = \"zstd\""),
+            vec!["Unknown key 'compresion', did you mean 'compression'?"],
+        );
+    }
+
+    #[test]
+    fn unknown_keys_without_close_ones() {
+        assert_eq!(unknown_key_messages("colour = \"red\""), vec!["Unknown key 'colour'"]);
+    }
+
+    #[test]
+    fn known_keys_are_not_reported() {
+        assert!(unknown_key_messages("format = \"chunked\"\ncreate_if_changed = true").is_empty());
+    }
+
+    #[test]
+    fn profiles_have_their_own_excludes_and_retention() {
+        let table: Table = toml::from_str(
+            "[profile.etc]\nexcludes = ['*.dpkg-old']\n[profile.etc.retention]\nkeep_daily = 7\nkeep_montly = 6"
+        ).unwrap();
+        let mut layers = ConfigLayers::new();
+        layers.profiles = table.get(PROFILE_KEY).unwrap().as_table().unwrap().clone();
+        let messages: Vec<String> = layers.check().into_iter().map(|problem| problem.message).collect();
+        assert_eq!(messages, vec!["Unknown key 'profile.etc.retention.keep_montly', did you mean 'keep_monthly'?"]);
+
+        let conf = Config::from_toml(
+            "backup_dir = '/etc'\nrepo_dir = '/srv/repo'\nexcludes = ['*.dpkg-old']\n[retention]\nkeep_last = 3\nkeep_weekly = 4",
+            &[],
+        );
+        assert!(conf.get_excludes().is_excluded("apt/sources.list.dpkg-old", false));
+        let retention = conf.get_retention().unwrap();
+        assert_eq!((retention.keep_last, retention.keep_daily, retention.keep_weekly, retention.keep_monthly), (3, 0, 4, 0));
+    }
+
+    #[test]
+    fn paths_are_made_absolute() {
+        let base = Path::new("/etc/resilient");
+        assert_eq!(expand_path("/srv/backup", base), Ok(PathBuf::from("/srv/backup")));
+        assert_eq!(expand_path("repo", base), Ok(PathBuf::from("/etc/resilient/repo")));
+        assert_eq!(expand_path("../repo", base), Ok(PathBuf::from("/etc/resilient/../repo")));
+    }
+
+    #[test]
+    fn leading_tilde_is_the_home_directory() {
+        let _env = ENV_LOCK.lock().unwrap();
+        let home = dirs::home_dir().unwrap();
+        let base = Path::new("/base");
+        assert_eq!(expand_path("~", base), Ok(home.clone()));
+        assert_eq!(expand_path("~/backups", base), Ok(home.join("backups")));
+        assert_eq!(expand_path("~other/backups", base), Ok(PathBuf::from("/base/~other/backups")));
+        assert_eq!(expand_path("/srv/~/backups", base), Ok(PathBuf::from("/srv/~/backups")));
+    }
+
+    #[test]
+    fn environment_variables_are_expanded() {
+        let _env = ENV_LOCK.lock().unwrap();
+        env::set_var("RESILIENT_TEST_EXPAND_DIR", "/mnt/disk");
+        let base = Path::new("/base");
+        assert_eq!(expand_path("$RESILIENT_TEST_EXPAND_DIR", base), Ok(PathBuf::from("/mnt/disk")));
+        assert_eq!(expand_path("$RESILIENT_TEST_EXPAND_DIR/repo", base), Ok(PathBuf::from("/mnt/disk/repo")));
+        assert_eq!(expand_path("$RESILIENT_TEST_EXPAND_DIR-old", base), Ok(PathBuf::from("/mnt/disk-old")));
+        assert_eq!(expand_path("${RESILIENT_TEST_EXPAND_DIR}_old", base), Ok(PathBuf::from("/mnt/disk_old")));
+        assert_eq!(expand_path("/a/${RESILIENT_TEST_EXPAND_DIR}/b", base), Ok(PathBuf::from("/a//mnt/disk/b")));
+        env::remove_var("RESILIENT_TEST_EXPAND_DIR");
+    }
+
+    #[test]
+    fn invalid_variables_are_errors() {
+        let _env = ENV_LOCK.lock().unwrap();
+        let base = Path::new("/base");
+        assert!(expand_path("$RESILIENT_TEST_UNSET_VARIABLE/repo", base).unwrap_err().contains("isn't set"));
+        assert!(expand_path("/srv/$", base).unwrap_err().contains("expected a variable name"));
+        assert!(expand_path("/srv/${}", base).unwrap_err().contains("expected a variable name"));
+        assert!(expand_path("/srv/${HOME", base).unwrap_err().contains("unclosed"));
+    }
+}
@@ -0,0 +1,103 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::backend;
+use super::chunks;
+use super::lock;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+pub fn delete(conf: &Config, commits: &[String], verbose: bool) {
+    let _lock = lock::acquire(conf);
+
+    let mut to_delete: Vec<Commit> = Vec::new();
+    for commit in commits {
+        let commit = backend::resolve_commit(conf, Some(commit.clone()));
+        if commit.is_pinned() {
+            eprintln!("Commit '{}' is pinned, unpin it before deleting", commit.get_name());
+            std::process::exit(1);
+        }
+        if !to_delete.iter().any(|other| other.get_name() == commit.get_name()) {
+            to_delete.push(commit);
+        }
+    }
+
+    remove_commits(conf, &to_delete, verbose);
+}
+
+/// Removes commits and the objects only they used. The caller holds the repository lock and
+/// has already refused pinned commits.
+pub fn remove_commits(conf: &Config, to_delete: &[Commit], verbose: bool) {
+    let is_deleted = |commit: &Commit| to_delete.iter().any(|other| other.get_name() == commit.get_name());
+    let remaining: Vec<Commit> = Commit::get_commits(conf).into_iter().filter(|commit| !is_deleted(commit)).collect();
+
+    // Repoint latest before anything is removed so it never dangles, even if we fail halfway
+    if let Some(latest) = Commit::get_latest(conf) {
+        if is_deleted(&latest) {
+            let newest = remaining.last();
+            if newest.is_none() {
+                eprintln!("Refusing to delete '{}': it is the latest commit and no other commit would remain", latest.get_name());
+                std::process::exit(1);
+            }
+
+            let newest = newest.unwrap();
+            if let Err(message) = Commit::write_latest(conf, newest) {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+            println!("Latest commit is now: {}", newest.get_name());
+        }
+    }
+
+    let mut freed = freed_bytes(to_delete);
+    for commit in to_delete {
+        if fs::remove_dir_all(commit.get_folder()).is_err() {
+            eprintln!("Unable to delete commit: {}", commit.get_folder().display());
+            std::process::exit(1);
+        }
+        println!("Deleted: {}", commit.get_name());
+    }
+
+    if to_delete.iter().any(|commit| commit.is_chunked()) {
+        freed += chunks::collect_garbage(conf, verbose);
+    }
+
+    println!("Freed {} bytes", freed);
+}
+
+/// Counts only files whose every hard link lives in the deleted commits, since rsync
+/// commits share unchanged files with their neighbours
+fn freed_bytes(commits: &[Commit]) -> u64 {
+    let mut links: HashMap<(u64, u64), (u64, u64, u64)> = HashMap::new();
+    for commit in commits {
+        count_links(commit.get_folder(), &mut links);
+    }
+
+    links.values()
+        .filter(|(seen, nlink, _)| seen >= nlink)
+        .map(|(_, _, size)| size)
+        .sum()
+}
+
+fn count_links(dir: &Path, links: &mut HashMap<(u64, u64), (u64, u64, u64)>) {
+    let dir_entries = match dir.read_dir() {
+        Ok(dir_entries) => dir_entries,
+        Err(_) => return,
+    };
+
+    for dir_entry in dir_entries.flatten() {
+        let metadata = match fs::symlink_metadata(dir_entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_dir() {
+            count_links(&dir_entry.path(), links);
+            continue;
+        }
+
+        let link = links.entry((metadata.dev(), metadata.ino())).or_insert((0, metadata.nlink(), metadata.len()));
+        link.0 += 1;
+    }
+}
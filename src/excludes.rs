@@ -0,0 +1,144 @@
+/// Paths left out of commits, as rsync style patterns relative to the backup directory. `*`
+/// and `?` match within a path component and `**` across components. A leading `/` anchors the
+/// pattern to the backup directory and a trailing `/` only matches directories. A pattern
+/// without a `/` matches the name of a file or directory at any depth, any other pattern
+/// matches the end of the path.
+#[derive(Clone, Default)]
+pub struct Excludes {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Clone)]
+struct Pattern {
+    source: String,
+    glob: Vec<char>,
+    anchored: bool,
+    dir_only: bool,
+    whole_path: bool,
+}
+
+impl Excludes {
+    pub fn parse(patterns: &[String]) -> Result<Excludes, String> {
+        let mut parsed = Vec::new();
+        for source in patterns {
+            if source.contains('[') || source.contains('\\') {
+                return Err(format!("Invalid exclude '{}', only *, ** and ? are supported", source));
+            }
+
+            let anchored = source.starts_with('/');
+            let dir_only = source.ends_with('/');
+            let glob = source.trim_start_matches('/').trim_end_matches('/');
+            if glob.is_empty() {
+                return Err(format!("Invalid exclude '{}', it would exclude everything", source));
+            }
+
+            parsed.push(Pattern {
+                source: source.clone(),
+                glob: glob.chars().collect(),
+                anchored,
+                dir_only,
+                whole_path: glob.contains('/') || glob.contains("**"),
+            });
+        }
+        Ok(Excludes { patterns: parsed })
+    }
+
+    /// Whether `path`, relative to the backup directory and separated by `/`, is excluded
+    pub fn is_excluded(&self, path: &str, is_dir: bool) -> bool {
+        let path = path.trim_matches('/');
+        self.patterns.iter().any(|pattern| pattern.matches(path, is_dir))
+    }
+
+    /// Arguments making rsync leave out the same paths
+    pub fn get_rsync_args(&self) -> Vec<String> {
+        self.patterns.iter().map(|pattern| format!("--exclude={}", pattern.source)).collect()
+    }
+}
+
+impl Pattern {
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let path: Vec<char> = path.chars().collect();
+        if self.anchored {
+            return glob_matches(&self.glob, &path);
+        }
+        if !self.whole_path {
+            let name_start = path.iter().rposition(|c| *c == '/').map(|index| index + 1).unwrap_or(0);
+            return glob_matches(&self.glob, &path[name_start..]);
+        }
+
+        // Unanchored patterns match the end of the path, starting at any component
+        glob_matches(&self.glob, &path)
+            || path.iter().enumerate()
+                .filter(|(_, c)| **c == '/')
+                .any(|(index, _)| glob_matches(&self.glob, &path[index + 1..]))
+    }
+}
+
+fn glob_matches(glob: &[char], text: &[char]) -> bool {
+    match glob {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|skip| glob_matches(rest, &text[skip..])),
+        ['*', rest @ ..] => {
+            let component_len = text.iter().position(|c| *c == '/').unwrap_or(text.len());
+            (0..=component_len).any(|skip| glob_matches(rest, &text[skip..]))
+        },
+        ['?', rest @ ..] => !text.is_empty() && text[0] != '/' && glob_matches(rest, &text[1..]),
+        [c, rest @ ..] => !text.is_empty() && text[0] == *c && glob_matches(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excludes(patterns: &[&str]) -> Excludes {
+        Excludes::parse(&patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn names_match_at_any_depth() {
+        let excludes = excludes(&["*.tmp", "node_modules"]);
+        assert!(excludes.is_excluded("a.tmp", false));
+        assert!(excludes.is_excluded("src/deep/b.tmp", false));
+        assert!(excludes.is_excluded("web/node_modules", true));
+        assert!(!excludes.is_excluded("a.tmp.txt", false));
+        assert!(!excludes.is_excluded("node_modules_old", true));
+    }
+
+    #[test]
+    fn anchored_patterns_match_from_the_root() {
+        let excludes = excludes(&["/cache", "/build/*.o"]);
+        assert!(excludes.is_excluded("cache", true));
+        assert!(!excludes.is_excluded("home/cache", true));
+        assert!(excludes.is_excluded("build/main.o", false));
+        assert!(!excludes.is_excluded("build/sub/main.o", false));
+    }
+
+    #[test]
+    fn paths_match_the_end_and_double_stars_cross_directories() {
+        let excludes = excludes(&[".cache/thumbnails", "logs/**/*.gz"]);
+        assert!(excludes.is_excluded(".cache/thumbnails", true));
+        assert!(excludes.is_excluded("home/me/.cache/thumbnails", true));
+        assert!(!excludes.is_excluded("home/me/x.cache/thumbnails", true));
+        assert!(excludes.is_excluded("var/logs/2024/01/app.gz", false));
+        assert!(!excludes.is_excluded("var/logs/app.txt", false));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_directories() {
+        let excludes = excludes(&["tmp/"]);
+        assert!(excludes.is_excluded("tmp", true));
+        assert!(!excludes.is_excluded("tmp", false));
+    }
+
+    #[test]
+    fn unsupported_patterns_are_rejected() {
+        assert!(Excludes::parse(&["[ab].txt".to_string()]).is_err());
+        assert!(Excludes::parse(&["/".to_string()]).is_err());
+        assert_eq!(excludes(&["*.tmp", "/x/"]).get_rsync_args(), vec!["--exclude=*.tmp", "--exclude=/x/"]);
+    }
+}
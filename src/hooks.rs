@@ -0,0 +1,74 @@
+use super::config::Config;
+use super::commit_log::CommitLog;
+use std::process::Command;
+
+#[derive(Clone, Copy)]
+pub enum Hook {
+    PreCreate,
+    PostCreate,
+    OnFailure,
+    PreRestore,
+    PostRestore,
+}
+
+impl Hook {
+    fn get_name(self) -> &'static str {
+        match self {
+            Hook::PreCreate => "pre_create",
+            Hook::PostCreate => "post_create",
+            Hook::OnFailure => "on_failure",
+            Hook::PreRestore => "pre_restore",
+            Hook::PostRestore => "post_restore",
+        }
+    }
+
+    fn get_command(self, conf: &Config) -> Option<&str> {
+        let hooks = conf.get_hooks();
+        let command = match self {
+            Hook::PreCreate => &hooks.pre_create,
+            Hook::PostCreate => &hooks.post_create,
+            Hook::OnFailure => &hooks.on_failure,
+            Hook::PreRestore => &hooks.pre_restore,
+            Hook::PostRestore => &hooks.post_restore,
+        };
+        command.as_deref()
+    }
+}
+
+/// Runs the configured command for the hook, if any, recording its output in the commit log
+pub fn run(conf: &Config, hook: Hook, log: &mut CommitLog, outcome: &str, env: &[(&str, &str)]) -> Result<(), String> {
+    let command = match hook.get_command(conf) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    let mut hook_command = Command::new("sh");
+    hook_command.arg("-c").arg(command);
+    hook_command.env("RESILIENT_EVENT", hook.get_name());
+    hook_command.env("RESILIENT_COMMIT", log.get_commit());
+    hook_command.env("RESILIENT_REPO", conf.get_repo_dir());
+    hook_command.env("RESILIENT_BACKUP_DIR", conf.get_backup_location());
+    hook_command.env("RESILIENT_OUTCOME", outcome);
+    for (key, value) in env {
+        hook_command.env(key, value);
+    }
+
+    log.event(&format!("Running {} hook: {}", hook.get_name(), command));
+    let output = hook_command.output()
+        .map_err(|_| format!("Unable to spawn {} hook", hook.get_name()))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    print!("{}", stdout);
+    eprint!("{}", stderr);
+    log.output(&stdout);
+    log.output(&stderr);
+
+    if !output.status.success() {
+        let message = format!("{} hook failed with code: {}", hook.get_name(), output.status.code().unwrap_or(-1));
+        log.event(&message);
+        return Err(message);
+    }
+
+    Ok(())
+}
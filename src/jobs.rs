@@ -0,0 +1,165 @@
+use super::config::Config;
+use super::schedule;
+use super::progress::{self, ProgressSnapshot};
+use chrono::Utc;
+use serde::Serialize;
+use std::io::{BufRead, BufReader};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const MAX_KEPT_JOBS: usize = 50;
+const COMMITTED_PREFIX: &str = "Committed: ";
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub kind: &'static str,
+    pub state: JobState,
+    pub message: String,
+    pub started: i64,
+    pub finished: Option<i64>,
+    pub commit: Option<String>,
+    pub error: Option<String>,
+    pub progress: Option<ProgressSnapshot>,
+}
+
+#[derive(Default)]
+struct JobList {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+/// Long-running operations started over HTTP. Each job runs resilient in a child process so
+/// the request that started it returns straight away and a failure can't stop the server.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    list: Arc<Mutex<JobList>>,
+}
+
+impl Jobs {
+    pub fn start_create(&self, conf: &Config, message: &str) -> Result<u64, String> {
+        let mut list = self.list.lock().unwrap();
+        if list.jobs.iter().any(|job| job.state == JobState::Running) {
+            return Err("A backup is already running".to_string());
+        }
+
+        let child = schedule::child_command(conf)
+            .arg("--set").arg("progress=json")
+            .arg("--set").arg("progress_interval=1")
+            .arg("create")
+            // One argument, so a message starting with '-' can't be taken for a flag
+            .arg(format!("--message={}", message))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        if child.is_err() {
+            return Err("Unable to start the backup".to_string());
+        }
+        let mut child = child.unwrap();
+
+        list.next_id += 1;
+        let id = list.next_id;
+        list.jobs.push(Job {
+            id,
+            kind: "create",
+            state: JobState::Running,
+            message: message.to_string(),
+            started: Utc::now().timestamp(),
+            finished: None,
+            commit: None,
+            error: None,
+            progress: None,
+        });
+        if list.jobs.len() > MAX_KEPT_JOBS {
+            list.jobs.remove(0);
+        }
+
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let jobs = self.clone();
+        thread::spawn(move || {
+            let stderr_reader = thread::spawn(move || {
+                BufReader::new(stderr).lines().map_while(Result::ok).filter(|line| !line.trim().is_empty()).last()
+            });
+
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                jobs.update(id, |job| {
+                    if let Some(commit) = line.strip_prefix(COMMITTED_PREFIX) {
+                        job.commit = Some(commit.trim().to_string());
+                    } else if let Some(snapshot) = progress::parse_line(&line) {
+                        job.progress = Some(snapshot);
+                    }
+                });
+            }
+
+            let last_error = stderr_reader.join().ok().flatten();
+            let status = child.wait();
+            jobs.update(id, |job| {
+                job.finished = Some(Utc::now().timestamp());
+                match &status {
+                    Ok(status) if status.success() => job.state = JobState::Succeeded,
+                    Ok(status) => {
+                        job.state = JobState::Failed;
+                        job.error = Some(last_error.clone()
+                            .unwrap_or_else(|| format!("Backup exited with code: {}", status.code().unwrap_or(-1))));
+                    },
+                    Err(_) => {
+                        job.state = JobState::Failed;
+                        job.error = Some("Unable to wait for the backup".to_string());
+                    },
+                }
+            });
+        });
+
+        Ok(id)
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.list.lock().unwrap().jobs.iter().any(|job| job.state == JobState::Running)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.list.lock().unwrap().jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Every job still remembered, newest first
+    pub fn get_jobs(&self) -> Vec<Job> {
+        self.list.lock().unwrap().jobs.iter().rev().cloned().collect()
+    }
+
+    /// Records a running job without starting anything
+    #[cfg(test)]
+    pub fn start_fake(&self, message: &str) -> u64 {
+        let mut list = self.list.lock().unwrap();
+        list.next_id += 1;
+        let id = list.next_id;
+        list.jobs.push(Job {
+            id,
+            kind: "create",
+            state: JobState::Running,
+            message: message.to_string(),
+            started: Utc::now().timestamp(),
+            finished: None,
+            commit: None,
+            error: None,
+            progress: None,
+        });
+        id
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Job)) {
+        let mut list = self.list.lock().unwrap();
+        if let Some(job) = list.jobs.iter_mut().find(|job| job.id == id) {
+            f(job);
+        }
+    }
+}
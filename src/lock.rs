@@ -0,0 +1,34 @@
+use super::config::Config;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+
+const LOCK_FILE_NAME: &str = "lock";
+
+/// Exclusive lock on a repository, held until dropped. Uses flock so the kernel releases it
+/// however the process exits.
+pub struct RepoLock {
+    _file: File,
+}
+
+pub fn acquire(conf: &Config) -> RepoLock {
+    let repo_dir = conf.get_repo_dir();
+    let lock_path = repo_dir.join(LOCK_FILE_NAME);
+    let _ = fs::create_dir_all(repo_dir);
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path);
+    if file.is_err() {
+        eprintln!("Unable to open repository lock: {}", lock_path.display());
+        std::process::exit(1);
+    }
+
+    let file = file.unwrap();
+    let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if res != 0 {
+        eprintln!("Repository is locked by another resilient process: {}", repo_dir.display());
+        std::process::exit(1);
+    }
+
+    RepoLock {
+        _file: file,
+    }
+}
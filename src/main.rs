@@ -1,36 +1,165 @@
 mod config;
 mod commit;
 mod rsync;
+mod chunks;
+mod backend;
+mod commit_log;
+mod hooks;
+mod notify;
+mod metrics;
+mod mount;
+mod archive;
+mod replicate;
+mod delete;
+mod prune;
+mod excludes;
+mod lock;
+mod tags;
+mod profiles;
+mod schedule;
+mod systemd;
 mod cli;
 mod status;
-use cli::Command;
+mod tui;
+mod web;
+mod api;
+mod jobs;
+mod progress;
+mod throttle;
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Command, ConfigCommand};
+use std::time::Instant;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let command = Command::parse_options(&args);
-    let config = config::Config::parse_config();
+    let cli = Cli::parse();
+    let verbose = cli.verbose;
+    let quiet = cli.quiet;
 
-    match command {
+    if let Command::Completions(options) = &cli.command {
+        clap_complete::generate(options.shell, &mut Cli::command(), "resilient", &mut std::io::stdout());
+        return;
+    }
+
+    if let Command::Config(ConfigCommand::Show) = &cli.command {
+        config::ConfigLayers::load(cli.config.as_deref(), &cli.overrides, cli.profile.as_deref()).print();
+        return;
+    }
+
+    if let Command::Config(ConfigCommand::Check) = &cli.command {
+        config::check_config(cli.config.as_deref(), &cli.overrides, cli.profile.as_deref());
+        return;
+    }
+
+    if let Command::Create(options) = &cli.command {
+        if options.all_profiles {
+            if cli.profile.is_some() {
+                eprintln!("--all-profiles can't be combined with --profile");
+                std::process::exit(1);
+            }
+            profiles::run_all(cli.config.as_deref());
+            return;
+        }
+    }
+
+    let mut config = config::Config::parse_config(cli.config.as_deref(), &cli.overrides, cli.profile.as_deref());
+    if let Some(repo) = cli.repo {
+        config = config.with_repo_dir(repo);
+    }
+    config = config.with_forced_limits(throttle::parse_cli_limits(cli.bwlimit.as_deref(), cli.ionice.as_deref(), cli.nice));
+    if quiet {
+        config = config.with_progress_mode(progress::ProgressMode::None);
+    }
+
+    match cli.command {
         Command::Create(options) => {
-            let _ = rsync::make_commit(&config, options.name.clone(), options.message, options.verbose);
-            println!("Committed: {}", options.name);
+            let if_changed = options.if_changed().unwrap_or_else(|| config.get_create_if_changed());
+            let started = Instant::now();
+            if if_changed && backend::get_changes(&config).is_empty() {
+                metrics::write(&config, metrics::Outcome::Skipped, started.elapsed().as_secs_f64());
+                if !quiet {
+                    println!("nothing to commit");
+                }
+                return;
+            }
+            let _ = backend::make_commit(&config, options.name.clone(), options.message, verbose);
+            if !quiet {
+                println!("Committed: {}", options.name);
+            }
         },
         Command::Status => {
-            rsync::print_status(&config);
+            backend::print_status(&config);
+        },
+        Command::Log => {
+            backend::print_log(&config);
         },
         Command::Restore(options) => {
-            if options.commit.is_none() || options.commit == Some("latest".to_string()) {
-                let latest = commit::Commit::get_latest(&config);
-                if latest.is_none() {
-                    eprintln!("No Commits to restore from");
-                    std::process::exit(1);
-                }
-                let latest = latest.unwrap();
-                let latest_commit_name = latest.get_folder().file_name().unwrap();
-                rsync::restore(&config, latest_commit_name.to_str().unwrap().to_string(), options.path, options.verbose);
+            let (commit, path) = options.commit_and_path();
+            let commit = backend::resolve_commit(&config, commit);
+            backend::restore(&config, &commit, path, verbose);
+        },
+        Command::Show(options) => {
+            let commit = backend::resolve_commit(&config, options.commit);
+            backend::show(&config, &commit, options.changes, options.log);
+        },
+        Command::Delete(options) => {
+            delete::delete(&config, &options.commits, verbose);
+        },
+        Command::Prune(options) => {
+            prune::prune(&config, options.dry_run, verbose);
+        },
+        Command::Tag(options) => {
+            tags::tag(&config, &options.commit, &options.label);
+        },
+        Command::Untag(options) => {
+            tags::untag(&config, &options.label);
+        },
+        Command::Pin(options) => {
+            tags::set_pinned(&config, &options.commit, true);
+        },
+        Command::Unpin(options) => {
+            tags::set_pinned(&config, &options.commit, false);
+        },
+        Command::AmendMessage(options) => {
+            tags::amend_message(&config, &options.commit, options.message);
+        },
+        Command::Mount(options) => {
+            mount::mount(&config, &options.mountpoint);
+        },
+        Command::Export(options) => {
+            let (commit, path) = options.commit_and_path();
+            let commit = backend::resolve_commit(&config, Some(commit));
+            archive::export(&config, &commit, path, &options.output, verbose);
+        },
+        Command::Import(options) => {
+            let archive_path = options.archive;
+            let message = options.message.unwrap_or_else(|| archive::default_import_message(&archive_path));
+            let _ = archive::import(&config, &archive_path, options.name.clone(), message, verbose);
+            if !quiet {
+                println!("Committed: {}", options.name);
+            }
+        },
+        Command::Replicate(options) => {
+            replicate::replicate(&config, &options.target, verbose);
+        },
+        Command::Tui => {
+            tui::run(&config);
+        },
+        Command::Serve(options) => {
+            web::serve(&config, &options.listen);
+        },
+        Command::Daemon(options) => {
+            if options.once {
+                schedule::run_once(&config);
             } else {
-                rsync::restore(&config, options.commit.unwrap(), options.path, options.verbose);
+                schedule::run_daemon(&config);
             }
-        }
+        },
+        Command::InstallSystemd(options) => {
+            systemd::install(&config, options.user, options.unit_dir, &options.on_calendar);
+        },
+        Command::UninstallSystemd(options) => {
+            systemd::uninstall(cli.profile.as_deref(), options.user, options.unit_dir);
+        },
+        Command::Config(_) | Command::Completions(_) => {}
     }
 }
@@ -0,0 +1,139 @@
+use super::config::Config;
+use super::commit::{Commit, CommitStats};
+use chrono::Utc;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+const LAST_SUCCESS_METRIC: &str = "resilient_last_success_timestamp_seconds";
+
+pub enum Outcome<'a> {
+    Success(&'a Commit),
+    /// Nothing changed since the latest commit, so none was made
+    Skipped,
+    Failure(&'a str),
+}
+
+/// Writes the metrics of a create run in the Prometheus text exposition format, for
+/// node_exporter's textfile collector. Does nothing unless `metrics_file` is configured.
+pub fn write(conf: &Config, outcome: Outcome, duration_seconds: f64) {
+    let metrics_file = match conf.get_metrics_file() {
+        Some(metrics_file) => metrics_file,
+        None => return,
+    };
+
+    let stats = match &outcome {
+        Outcome::Success(commit) => commit.get_stats().unwrap_or_default(),
+        Outcome::Skipped | Outcome::Failure(_) => CommitStats::default(),
+    };
+
+    // Metrics are written on the way out of a failed run too, which must not exit before the
+    // failure is reported, e.g. when the first commit of a new repository fails
+    let now = Utc::now().timestamp();
+    let last_commit = Commit::try_get_latest(conf).ok().flatten().map(|latest| latest.get_timestamp()).unwrap_or(0);
+    let last_success = match outcome {
+        Outcome::Success(_) | Outcome::Skipped => now,
+        Outcome::Failure(_) => read_previous(metrics_file, LAST_SUCCESS_METRIC).unwrap_or(0).max(last_commit),
+    };
+    let commits = Commit::try_get_commits(conf).map(|commits| commits.len()).unwrap_or(0);
+
+    let mut metrics = String::new();
+    push_metric(&mut metrics, "resilient_last_run_timestamp_seconds", "Time the last create run finished.", now);
+    push_metric(&mut metrics, "resilient_last_run_success", "Whether the last create run succeeded.", !matches!(outcome, Outcome::Failure(_)) as u8);
+    push_metric(&mut metrics, "resilient_last_run_skipped", "Whether the last create run found nothing to commit.", matches!(outcome, Outcome::Skipped) as u8);
+    push_metric(&mut metrics, "resilient_last_run_duration_seconds", "Duration of the last create run.", duration_seconds);
+    push_metric(&mut metrics, LAST_SUCCESS_METRIC, "Time of the latest successful create run, including runs with nothing to commit.", last_success);
+    push_metric(&mut metrics, "resilient_last_commit_timestamp_seconds", "Time of the latest commit.", last_commit);
+    push_metric(&mut metrics, "resilient_last_files_total", "Files captured by the last create run.", stats.files_total);
+    push_metric(&mut metrics, "resilient_last_bytes_total", "Bytes captured by the last create run.", stats.bytes_total);
+    push_metric(&mut metrics, "resilient_last_files_changed", "Files changed since the previous commit.", stats.files_changed);
+    push_metric(&mut metrics, "resilient_last_bytes_changed", "Bytes changed since the previous commit.", stats.bytes_changed);
+    push_metric(&mut metrics, "resilient_repository_size_bytes", "Size of the repository on disk.", repository_size(conf.get_repo_dir()));
+    push_metric(&mut metrics, "resilient_commits", "Number of commits in the repository.", commits);
+    if let Outcome::Failure(error) = outcome {
+        let name = format!("resilient_last_error_info{{error=\"{}\"}}", escape_label(error));
+        metrics += "# HELP resilient_last_error_info Error of the last create run, if it failed.\n";
+        metrics += "# TYPE resilient_last_error_info gauge\n";
+        metrics += &format!("{} 1\n", name);
+    }
+
+    // The collector may read at any time, so never let it see a half written file
+    let tmp_file = metrics_file.with_extension("prom.tmp");
+    let write_res = fs::write(&tmp_file, metrics).and_then(|_| fs::rename(&tmp_file, metrics_file));
+    if write_res.is_err() {
+        eprintln!("Unable to write metrics file: {}", metrics_file.display());
+    }
+}
+
+/// The value of a metric in the file written by the previous run
+fn read_previous(metrics_file: &Path, name: &str) -> Option<i64> {
+    let contents = fs::read_to_string(metrics_file).ok()?;
+    contents.lines()
+        .filter_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .find_map(|value| value.trim().parse::<i64>().ok())
+}
+
+fn push_metric<T: std::fmt::Display>(metrics: &mut String, name: &str, help: &str, value: T) {
+    *metrics += &format!("# HELP {} {}\n", name, help);
+    *metrics += &format!("# TYPE {} gauge\n", name);
+    *metrics += &format!("{} {}\n", name, value);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn repository_size(repo_dir: &Path) -> u64 {
+    let mut seen_inodes = HashSet::new();
+    let mut size = 0;
+    let mut pending = vec![repo_dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let dir_entries = match dir.read_dir() {
+            Ok(dir_entries) => dir_entries,
+            Err(_) => continue,
+        };
+
+        for dir_entry in dir_entries.flatten() {
+            let metadata = match fs::symlink_metadata(dir_entry.path()) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            if metadata.is_dir() {
+                pending.push(dir_entry.path());
+            } else if metadata.nlink() == 1 || seen_inodes.insert((metadata.dev(), metadata.ino())) {
+                // Hard linked files are shared between rsync commits, only count them once
+                size += metadata.len();
+            }
+        }
+    }
+
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn previous_values_are_read_back() {
+        let dir = TempDir::new("metrics").unwrap();
+        let metrics_file = dir.path().join("resilient.prom");
+        let mut metrics = String::new();
+        push_metric(&mut metrics, "resilient_last_success_timestamp_seconds_total", "A longer name.", 1);
+        push_metric(&mut metrics, LAST_SUCCESS_METRIC, "Time of the latest successful create run.", 1792384977);
+        fs::write(&metrics_file, metrics).unwrap();
+
+        assert_eq!(read_previous(&metrics_file, LAST_SUCCESS_METRIC), Some(1792384977));
+        assert_eq!(read_previous(&metrics_file, "resilient_commits"), None);
+        assert_eq!(read_previous(&dir.path().join("missing.prom"), LAST_SUCCESS_METRIC), None);
+    }
+
+    #[test]
+    fn error_labels_are_escaped() {
+        assert_eq!(escape_label("rsync said \"no\"\nat C:\\"), "rsync said \\\"no\\\"\\nat C:\\\\");
+    }
+}
@@ -0,0 +1,603 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::chunks::{Store, Entry, EntryKind};
+use super::rsync::DATA_FOLDER_NAME;
+use chrono::{Local, TimeZone};
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request, FUSE_ROOT_ID};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::Metadata;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TTL: Duration = Duration::from_secs(60);
+/// How long the kernel may cache by-name, by-date and latest, which change as commits are made
+const LAYOUT_TTL: Duration = Duration::from_secs(1);
+const BLOCK_SIZE: u32 = 4096;
+
+const BY_NAME_FOLDER: &str = "by-name";
+const BY_DATE_FOLDER: &str = "by-date";
+const LATEST_LINK: &str = "latest";
+
+enum Node {
+    Virtual(Vec<(String, u64)>),
+    Link(String),
+    Real(PathBuf),
+    Chunked(Entry),
+}
+
+struct CommitFs {
+    conf: Config,
+    store: Store,
+    nodes: HashMap<u64, Node>,
+    parents: HashMap<u64, u64>,
+    next_ino: u64,
+    children: HashMap<(u64, String), u64>,
+    /// Inodes of the nodes added by build_layout, kept across rebuilds
+    layout: HashMap<(u64, String), u64>,
+    /// Modification times of the backups folder and latest.commit when the layout was built
+    layout_stamp: (Option<SystemTime>, Option<SystemTime>),
+    chunk_offsets: HashMap<u64, Vec<u64>>,
+    cached_chunk: Option<(String, Vec<u8>)>,
+    uid: u32,
+    gid: u32,
+    mount_time: SystemTime,
+}
+
+pub fn mount(conf: &Config, mountpoint: &Path) {
+    let commit_fs = match CommitFs::new(conf) {
+        Ok(commit_fs) => commit_fs,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
+    let options = [
+        MountOption::RO,
+        MountOption::DefaultPermissions,
+        MountOption::FSName("resilient".to_string()),
+    ];
+
+    println!("Mounting commits at {}, unmount with 'fusermount -u {}'", mountpoint.display(), mountpoint.display());
+    let mount_res = fuser::mount2(commit_fs, mountpoint, &options);
+    if let Err(err) = mount_res {
+        eprintln!("Unable to mount at '{}': {}", mountpoint.display(), err);
+        std::process::exit(1);
+    }
+}
+
+impl CommitFs {
+    fn new(conf: &Config) -> Result<CommitFs, String> {
+        let repo_metadata = fs::metadata(conf.get_backups_folder())
+            .map_err(|_| "Unable to read backups from repo".to_string())?;
+
+        let mut commit_fs = CommitFs {
+            conf: conf.clone(),
+            store: Store::try_open(conf)?,
+            nodes: HashMap::from([(FUSE_ROOT_ID, Node::Virtual(Vec::new()))]),
+            parents: HashMap::from([(FUSE_ROOT_ID, FUSE_ROOT_ID)]),
+            next_ino: FUSE_ROOT_ID + 1,
+            children: HashMap::new(),
+            layout: HashMap::new(),
+            layout_stamp: get_layout_stamp(conf),
+            chunk_offsets: HashMap::new(),
+            cached_chunk: None,
+            uid: repo_metadata.uid(),
+            gid: repo_metadata.gid(),
+            mount_time: SystemTime::now(),
+        };
+        commit_fs.build_layout()?;
+        Ok(commit_fs)
+    }
+
+    fn add_node(&mut self, parent: u64, node: Node) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.nodes.insert(ino, node);
+        self.parents.insert(ino, parent);
+        ino
+    }
+
+    /// Adds a node to a virtual directory, reusing the inode the same node had in an earlier
+    /// layout so the kernel's cached lookups and open files stay valid
+    fn add_virtual_child(&mut self, parent: u64, name: String, node: Node) -> u64 {
+        let key = (parent, name.clone());
+        let ino = match self.layout.get(&key) {
+            Some(ino) if self.nodes.get(ino).is_some_and(|old| is_same_node(old, &node)) => {
+                self.nodes.insert(*ino, node);
+                *ino
+            },
+            _ => {
+                let ino = self.add_node(parent, node);
+                self.layout.insert(key, ino);
+                ino
+            },
+        };
+        if let Some(Node::Virtual(entries)) = self.nodes.get_mut(&parent) {
+            entries.push((name, ino));
+        }
+        ino
+    }
+
+    fn find_virtual_child(&self, parent: u64, name: &str) -> Option<u64> {
+        match self.get_node(parent)? {
+            Node::Virtual(entries) => entries.iter().find(|entry| entry.0 == name).map(|entry| entry.1),
+            _ => None,
+        }
+    }
+
+    fn get_virtual_dir(&mut self, parent: u64, name: &str) -> u64 {
+        match self.find_virtual_child(parent, name) {
+            Some(ino) => ino,
+            None => self.add_virtual_child(parent, name.to_string(), Node::Virtual(Vec::new())),
+        }
+    }
+
+    /// Fills the root with by-name, by-date and latest, replacing whatever was there before
+    fn build_layout(&mut self) -> Result<(), String> {
+        let commits = Commit::try_get_commits(&self.conf)?;
+        let latest = Commit::try_get_latest(&self.conf)?;
+
+        let root = FUSE_ROOT_ID;
+        self.nodes.insert(root, Node::Virtual(Vec::new()));
+        let by_name = self.add_virtual_child(root, BY_NAME_FOLDER.to_string(), Node::Virtual(Vec::new()));
+        let by_date = self.add_virtual_child(root, BY_DATE_FOLDER.to_string(), Node::Virtual(Vec::new()));
+
+        for commit in commits {
+            let name = commit.get_name();
+            let commit_node = if commit.is_chunked() {
+                let tree = commit.try_get_tree()?;
+                Node::Chunked(Entry {
+                    name: name.clone(),
+                    kind: EntryKind::Directory,
+                    mode: 0o555,
+                    uid: self.uid,
+                    gid: self.gid,
+                    mtime: commit.get_timestamp(),
+                    mtime_nsec: 0,
+                    size: 0,
+                    chunks: Vec::new(),
+                    tree: Some(tree),
+                    target: None,
+                })
+            } else {
+                Node::Real(commit.get_folder().join(DATA_FOLDER_NAME))
+            };
+            self.add_virtual_child(by_name, name.clone(), commit_node);
+
+            let date = Local.timestamp_opt(commit.get_timestamp(), 0).unwrap();
+            let year = self.get_virtual_dir(by_date, &date.format("%Y").to_string());
+            let month = self.get_virtual_dir(year, &date.format("%m").to_string());
+            let day = self.get_virtual_dir(month, &date.format("%d").to_string());
+            let target = format!("../../../../{}/{}", BY_NAME_FOLDER, name);
+            self.add_virtual_child(day, name, Node::Link(target));
+        }
+
+        if let Some(latest) = latest {
+            let target = format!("{}/{}", BY_NAME_FOLDER, latest.get_name());
+            self.add_virtual_child(root, LATEST_LINK.to_string(), Node::Link(target));
+        }
+
+        self.evict_unreachable();
+        Ok(())
+    }
+
+    /// Forgets every node the new layout no longer reaches, such as the files of deleted
+    /// commits, so a long running mount doesn't keep them all. The kernel gets ENOENT for any
+    /// inode it still remembers.
+    fn evict_unreachable(&mut self) {
+        let mut reachable = HashSet::new();
+        let mut pending = vec![FUSE_ROOT_ID];
+        while let Some(ino) = pending.pop() {
+            reachable.insert(ino);
+            if let Some(Node::Virtual(entries)) = self.nodes.get(&ino) {
+                pending.extend(entries.iter().map(|entry| entry.1));
+            }
+        }
+
+        let mut stale: Vec<u64> = self.layout.values().copied().filter(|ino| !reachable.contains(ino)).collect();
+        self.layout.retain(|_, ino| reachable.contains(ino));
+        if stale.is_empty() {
+            return;
+        }
+
+        let mut children_of: HashMap<u64, Vec<u64>> = HashMap::new();
+        for ((parent, _), ino) in &self.children {
+            children_of.entry(*parent).or_default().push(*ino);
+        }
+        while let Some(ino) = stale.pop() {
+            stale.extend(children_of.remove(&ino).unwrap_or_default());
+            self.nodes.remove(&ino);
+            self.parents.remove(&ino);
+            self.chunk_offsets.remove(&ino);
+        }
+
+        let nodes = &self.nodes;
+        self.children.retain(|_, ino| nodes.contains_key(ino));
+    }
+
+    /// Rebuilds the layout when commits were made or deleted since it was last built. A commit
+    /// that can't be read yet leaves the current layout in place until the next check.
+    fn refresh_layout(&mut self, ino: u64) {
+        if !matches!(self.get_node(ino), Some(Node::Virtual(_))) {
+            return;
+        }
+
+        let stamp = get_layout_stamp(&self.conf);
+        if stamp != self.layout_stamp && self.build_layout().is_ok() {
+            self.layout_stamp = stamp;
+        }
+    }
+
+    fn get_ttl(&self, ino: u64) -> Duration {
+        match self.get_node(ino) {
+            Some(Node::Virtual(_)) | Some(Node::Link(_)) => LAYOUT_TTL,
+            _ => TTL,
+        }
+    }
+
+    fn get_node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(&ino)
+    }
+
+    fn cache_child(&mut self, parent: u64, name: &str, node: Node) -> u64 {
+        if let Some(ino) = self.children.get(&(parent, name.to_string())) {
+            return *ino;
+        }
+
+        let ino = self.add_node(parent, node);
+        self.children.insert((parent, name.to_string()), ino);
+        ino
+    }
+
+    fn get_child(&mut self, parent: u64, name: &str) -> Option<u64> {
+        if let Some(ino) = self.children.get(&(parent, name.to_string())) {
+            return Some(*ino);
+        }
+
+        let child_node = match self.get_node(parent)? {
+            Node::Virtual(_) => return self.find_virtual_child(parent, name),
+            Node::Link(_) => return None,
+            Node::Real(path) => {
+                let child_path = path.join(name);
+                fs::symlink_metadata(&child_path).ok()?;
+                Node::Real(child_path)
+            },
+            Node::Chunked(entry) => {
+                let tree = self.store.read_tree(entry.tree.as_ref()?).ok()?;
+                Node::Chunked(tree.find(name)?.clone())
+            },
+        };
+
+        Some(self.cache_child(parent, name, child_node))
+    }
+
+    fn list_children(&mut self, ino: u64) -> Option<Vec<(String, u64)>> {
+        let child_nodes: Vec<(String, Node)> = match self.get_node(ino)? {
+            Node::Virtual(entries) => return Some(entries.clone()),
+            Node::Link(_) => return None,
+            Node::Real(path) => {
+                let mut names: Vec<String> = path.read_dir().ok()?
+                    .filter_map(|dir_entry| dir_entry.ok())
+                    .filter_map(|dir_entry| dir_entry.file_name().into_string().ok())
+                    .collect();
+                names.sort();
+                names.into_iter().map(|name| {
+                    let child_path = path.join(&name);
+                    (name, Node::Real(child_path))
+                }).collect()
+            },
+            Node::Chunked(entry) => {
+                let tree = self.store.read_tree(entry.tree.as_ref()?).ok()?;
+                tree.entries.into_iter().map(|child| (child.name.clone(), Node::Chunked(child))).collect()
+            },
+        };
+
+        Some(child_nodes.into_iter().map(|(name, node)| {
+            let child = self.cache_child(ino, &name, node);
+            (name, child)
+        }).collect())
+    }
+
+    fn get_attr(&self, ino: u64) -> Option<FileAttr> {
+        let attr = match self.get_node(ino)? {
+            Node::Virtual(_) => self.virtual_attr(ino, FileType::Directory, 0),
+            Node::Link(target) => self.virtual_attr(ino, FileType::Symlink, target.len() as u64),
+            Node::Real(path) => real_attr(ino, &fs::symlink_metadata(path).ok()?),
+            Node::Chunked(entry) => chunked_attr(ino, entry),
+        };
+        Some(attr)
+    }
+
+    fn virtual_attr(&self, ino: u64, kind: FileType, size: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks: 0,
+            atime: self.mount_time,
+            mtime: self.mount_time,
+            ctime: self.mount_time,
+            crtime: self.mount_time,
+            kind,
+            perm: if kind == FileType::Directory { 0o555 } else { 0o777 },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        }
+    }
+
+    fn read_chunked(&mut self, ino: u64, offset: u64, size: u64) -> Option<Vec<u8>> {
+        let chunks = match self.get_node(ino)? {
+            Node::Chunked(entry) if entry.kind == EntryKind::File => entry.chunks.clone(),
+            _ => return None,
+        };
+
+        if !self.chunk_offsets.contains_key(&ino) {
+            let mut chunk_end = 0;
+            let mut offsets = Vec::new();
+            for chunk in &chunks {
+                chunk_end += self.store.object_size(chunk).ok()?;
+                offsets.push(chunk_end);
+            }
+            self.chunk_offsets.insert(ino, offsets);
+        }
+        let offsets = self.chunk_offsets[&ino].clone();
+
+        let end_offset = offset + size;
+        let mut chunk_start = 0;
+        let mut data = Vec::new();
+        for (chunk, chunk_end) in chunks.iter().zip(offsets) {
+            if chunk_end > offset && chunk_start < end_offset {
+                let chunk_data = self.read_chunk(chunk)?;
+                let from = offset.saturating_sub(chunk_start) as usize;
+                let to = (end_offset.min(chunk_end) - chunk_start) as usize;
+                data.extend_from_slice(&chunk_data[from..to]);
+            }
+            chunk_start = chunk_end;
+        }
+        Some(data)
+    }
+
+    fn read_chunk(&mut self, hash: &str) -> Option<&[u8]> {
+        let cached = match &self.cached_chunk {
+            Some((cached_hash, _)) => cached_hash == hash,
+            None => false,
+        };
+        if !cached {
+            self.cached_chunk = Some((hash.to_string(), self.store.read_object(hash).ok()?));
+        }
+        Some(&self.cached_chunk.as_ref().unwrap().1)
+    }
+}
+
+impl Filesystem for CommitFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.refresh_layout(parent);
+        let attr = name.to_str()
+            .and_then(|name| self.get_child(parent, name))
+            .and_then(|ino| self.get_attr(ino));
+        match attr {
+            Some(attr) => reply.entry(&self.get_ttl(attr.ino), &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.get_attr(ino) {
+            Some(attr) => reply.attr(&self.get_ttl(ino), &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let target = match self.get_node(ino) {
+            Some(Node::Link(target)) => Some(target.clone().into_bytes()),
+            Some(Node::Real(path)) => fs::read_link(path).ok().map(|target| target.into_os_string().into_encoded_bytes()),
+            Some(Node::Chunked(entry)) => entry.target.clone().map(|target| target.into_bytes()),
+            _ => None,
+        };
+        match target {
+            Some(target) => reply.data(&target),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let offset = offset.max(0) as u64;
+        let data = match self.get_node(ino) {
+            Some(Node::Real(path)) => {
+                let mut buffer = vec![0; size as usize];
+                fs::File::open(path)
+                    .and_then(|file| file.read_at(&mut buffer, offset))
+                    .ok()
+                    .map(|read_len| {
+                        buffer.truncate(read_len);
+                        buffer
+                    })
+            },
+            Some(Node::Chunked(_)) => self.read_chunked(ino, offset, size as u64),
+            _ => None,
+        };
+        match data {
+            Some(data) => reply.data(&data),
+            None => reply.error(libc::EIO),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        // Only a listing from the start rebuilds, so a listing split over several calls stays consistent
+        if offset == 0 {
+            self.refresh_layout(ino);
+        }
+        let children = self.list_children(ino);
+        if children.is_none() {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+
+        let parent = self.parents.get(&ino).copied().unwrap_or(FUSE_ROOT_ID);
+        let mut entries = vec![(ino, FileType::Directory, ".".to_string()), (parent, FileType::Directory, "..".to_string())];
+        for (name, child) in children.unwrap() {
+            if let Some(attr) = self.get_attr(child) {
+                entries.push((child, attr.kind, name));
+            }
+        }
+
+        for (index, (child, kind, name)) in entries.into_iter().enumerate().skip(offset.max(0) as usize) {
+            if reply.add(child, (index + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Commits being added or deleted change the backups folder, finishing one rewrites latest.commit
+fn get_layout_stamp(conf: &Config) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    (modified(&conf.get_backups_folder()), modified(&Commit::get_latest_file(conf)))
+}
+
+/// Whether `new` can take the place of `old` under the same inode
+fn is_same_node(old: &Node, new: &Node) -> bool {
+    match (old, new) {
+        (Node::Virtual(_), Node::Virtual(_)) | (Node::Link(_), Node::Link(_)) => true,
+        (Node::Real(old), Node::Real(new)) => old == new,
+        (Node::Chunked(old), Node::Chunked(new)) => old.tree == new.tree,
+        _ => false,
+    }
+}
+
+fn to_system_time(seconds: i64, nanoseconds: u32) -> SystemTime {
+    if seconds >= 0 {
+        UNIX_EPOCH + Duration::new(seconds as u64, nanoseconds)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()) + Duration::from_nanos(nanoseconds as u64)
+    }
+}
+
+fn real_attr(ino: u64, metadata: &Metadata) -> FileAttr {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    };
+
+    FileAttr {
+        ino,
+        size: metadata.len(),
+        blocks: metadata.blocks(),
+        atime: to_system_time(metadata.atime(), metadata.atime_nsec() as u32),
+        mtime: to_system_time(metadata.mtime(), metadata.mtime_nsec() as u32),
+        ctime: to_system_time(metadata.ctime(), metadata.ctime_nsec() as u32),
+        crtime: to_system_time(metadata.ctime(), metadata.ctime_nsec() as u32),
+        kind,
+        perm: (metadata.mode() & 0o7777) as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    }
+}
+
+fn chunked_attr(ino: u64, entry: &Entry) -> FileAttr {
+    let (kind, size) = match entry.kind {
+        EntryKind::File => (FileType::RegularFile, entry.size),
+        EntryKind::Directory => (FileType::Directory, 0),
+        EntryKind::Symlink => (FileType::Symlink, entry.target.as_ref().map_or(0, |target| target.len() as u64)),
+    };
+    let mtime = to_system_time(entry.mtime, entry.mtime_nsec);
+
+    FileAttr {
+        ino,
+        size,
+        blocks: size.div_ceil(512),
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind,
+        perm: (entry.mode & 0o7777) as u16,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: entry.uid,
+        gid: entry.gid,
+        rdev: 0,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::chunks;
+    use super::super::commit_log::CommitLog;
+    use tempdir::TempDir;
+
+    fn make_commit(conf: &Config, name: &str) -> Commit {
+        fs::write(conf.get_backup_location().join("notes.txt"), name).unwrap();
+        let mut log = CommitLog::new(conf, name);
+        chunks::make_commit(conf, conf.get_backup_location(), name.to_string(), String::new(), &mut log, false).unwrap()
+    }
+
+    fn lookup(commit_fs: &mut CommitFs, path: &str) -> Option<u64> {
+        path.split('/').try_fold(FUSE_ROOT_ID, |parent, name| commit_fs.get_child(parent, name))
+    }
+
+    #[test]
+    fn deleted_commits_are_evicted_on_refresh() {
+        let dir = TempDir::new("mount").unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let conf = Config::from_toml(&format!(
+            "backup_dir = '{}'\nrepo_dir = '{}'\nformat = 'chunked'\nprogress = 'none'",
+            dir.path().join("src").display(), dir.path().join("repo").display(),
+        ), &[]);
+        let first = make_commit(&conf, "first");
+        make_commit(&conf, "second");
+
+        let mut commit_fs = CommitFs::new(&conf).unwrap();
+        let first_file = lookup(&mut commit_fs, "by-name/first/notes.txt").unwrap();
+        let second_file = lookup(&mut commit_fs, "by-name/second/notes.txt").unwrap();
+        assert_eq!(commit_fs.read_chunked(first_file, 0, 100).unwrap(), b"first");
+        assert!(commit_fs.chunk_offsets.contains_key(&first_file));
+
+        fs::remove_dir_all(first.get_folder()).unwrap();
+        commit_fs.build_layout().unwrap();
+
+        assert!(commit_fs.get_node(first_file).is_none());
+        assert!(!commit_fs.chunk_offsets.contains_key(&first_file));
+        assert!(commit_fs.children.values().all(|ino| commit_fs.nodes.contains_key(ino)));
+        assert!(lookup(&mut commit_fs, "by-name/first").is_none());
+        assert_eq!(lookup(&mut commit_fs, "by-name/second/notes.txt"), Some(second_file));
+        assert_eq!(commit_fs.read_chunked(second_file, 0, 100).unwrap(), b"second");
+    }
+}
@@ -0,0 +1,201 @@
+use super::config::{Config, NotificationConfig, Problem, Severity};
+use std::env;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Event {
+    CreateSuccess,
+    CreateFailure,
+}
+
+const EVENTS: &[Event] = &[Event::CreateSuccess, Event::CreateFailure];
+
+impl Event {
+    pub fn parse(name: &str) -> Option<Event> {
+        EVENTS.iter().copied().find(|event| event.get_name() == name)
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        EVENTS.iter().map(|event| event.get_name()).collect()
+    }
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            Event::CreateSuccess => "create_success",
+            Event::CreateFailure => "create_failure",
+        }
+    }
+
+    fn is_failure(self) -> bool {
+        matches!(self, Event::CreateFailure)
+    }
+}
+
+pub struct Notification<'a> {
+    pub event: Event,
+    pub commit: &'a str,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends the notification to every sink configured for its event. Sink failures are
+/// reported but never fail the run that triggered them.
+pub fn send(conf: &Config, notification: &Notification) {
+    for sink in conf.get_notifications().iter().filter(|sink| sink.event == notification.event) {
+        if let Err(message) = send_to_sink(sink, notification) {
+            eprintln!("Unable to send {} notification: {}", notification.event.get_name(), message);
+        }
+    }
+}
+
+/// Warns about the external programs notifications rely on that can't be found: sendmail for
+/// mail, curl for webhooks and notify-send for desktop notifications
+pub fn check_programs(conf: &Config) -> Vec<Problem> {
+    let mut programs: Vec<(&str, &str)> = Vec::new();
+    for sink in conf.get_notifications() {
+        if sink.mail_to.is_some() {
+            programs.push((&sink.sendmail, "Mail notifications"));
+        }
+        if sink.webhook.is_some() {
+            programs.push(("curl", "Webhook notifications"));
+        }
+        if sink.desktop {
+            programs.push(("notify-send", "Desktop notifications"));
+        }
+    }
+    programs.dedup();
+
+    programs.into_iter()
+        .filter(|(program, _)| !is_installed(program))
+        .map(|(program, feature)| Problem {
+            severity: Severity::Warning,
+            message: format!("{} need {}, which isn't installed", feature, program),
+        })
+        .collect()
+}
+
+fn is_installed(program: &str) -> bool {
+    let is_executable = |path: &Path| path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| is_executable(&dir.join(program))))
+}
+
+fn send_to_sink(sink: &NotificationConfig, notification: &Notification) -> Result<(), String> {
+    if let Some(command) = &sink.command {
+        let mut notify_command = Command::new("sh");
+        notify_command.arg("-c").arg(command);
+        notify_command.env("RESILIENT_EVENT", notification.event.get_name());
+        notify_command.env("RESILIENT_COMMIT", notification.commit);
+        notify_command.env("RESILIENT_SUBJECT", &notification.subject);
+        notify_command.env("RESILIENT_BODY", &notification.body);
+        run(notify_command, "command")?;
+    }
+
+    if let Some(mail_to) = &sink.mail_to {
+        let mail = format!("To: {}\nSubject: {}\n\n{}\n", mail_to, notification.subject, notification.body);
+        let mut sendmail_command = Command::new(&sink.sendmail);
+        sendmail_command.arg("-t");
+        run_with_input(sendmail_command, &sink.sendmail, mail.as_bytes())?;
+    }
+
+    // Webhooks are posted with curl, which has to be installed
+    if let Some(webhook) = &sink.webhook {
+        let payload = format!(
+            "{{\"event\":\"{}\",\"commit\":\"{}\",\"subject\":\"{}\",\"body\":\"{}\"}}",
+            notification.event.get_name(),
+            escape_json(notification.commit),
+            escape_json(&notification.subject),
+            escape_json(&notification.body)
+        );
+        let mut curl_command = Command::new("curl");
+        curl_command.arg("--silent").arg("--show-error").arg("--fail");
+        curl_command.arg("--max-time").arg("30");
+        curl_command.arg("--header").arg("Content-Type: application/json");
+        curl_command.arg("--data-binary").arg("@-");
+        curl_command.arg(webhook);
+        curl_command.stdout(Stdio::null());
+        run_with_input(curl_command, "curl", payload.as_bytes())?;
+    }
+
+    if sink.desktop {
+        let mut desktop_command = Command::new("notify-send");
+        if notification.event.is_failure() {
+            desktop_command.arg("--urgency=critical");
+        }
+        desktop_command.arg(&notification.subject);
+        desktop_command.arg(&notification.body);
+        run(desktop_command, "notify-send")?;
+    }
+
+    Ok(())
+}
+
+fn run(mut command: Command, name: &str) -> Result<(), String> {
+    let status = command.status().map_err(|_| format!("Unable to spawn {}", name))?;
+    if !status.success() {
+        return Err(format!("{} exited with code: {}", name, status.code().unwrap_or(-1)));
+    }
+    Ok(())
+}
+
+fn run_with_input(mut command: Command, name: &str, input: &[u8]) -> Result<(), String> {
+    let mut child = command.stdin(Stdio::piped())
+        .spawn()
+        .map_err(|_| format!("Unable to spawn {}", name))?;
+
+    let write_res = child.stdin.take().unwrap().write_all(input);
+    let status = child.wait().map_err(|_| format!("Unable to wait for {}", name))?;
+    if write_res.is_err() {
+        return Err(format!("Unable to write to {}", name));
+    }
+    if !status.success() {
+        return Err(format!("{} exited with code: {}", name, status.code().unwrap_or(-1)));
+    }
+    Ok(())
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_programs_are_reported() {
+        assert!(is_installed("sh"));
+        assert!(is_installed("/bin/sh"));
+        assert!(!is_installed("resilient-no-such-program"));
+
+        let conf = Config::from_toml(r#"
+            backup_dir = "/tmp"
+            repo_dir = "/tmp"
+            [notifications.create_failure]
+            mail_to = "root@localhost"
+            sendmail = "/nonexistent/sendmail"
+        "#, &[]);
+        let problems = check_programs(&conf);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].message, "Mail notifications need /nonexistent/sendmail, which isn't installed");
+    }
+}
@@ -0,0 +1,45 @@
+use super::config::ConfigLayers;
+use std::path::Path;
+use std::process::Command;
+
+const ALL_PROFILES_ARG: &str = "--all-profiles";
+
+/// Runs the current command once per profile, each in its own process so one failing
+/// profile doesn't stop the rest
+pub fn run_all(config_path: Option<&Path>) {
+    let profiles = ConfigLayers::get_profile_names(config_path);
+    if profiles.is_empty() {
+        eprintln!("No [profile.<name>] sections found in config");
+        std::process::exit(1);
+    }
+
+    let current_exe = std::env::current_exe();
+    if current_exe.is_err() {
+        eprintln!("Unable to find resilient executable");
+        std::process::exit(1);
+    }
+    let current_exe = current_exe.unwrap();
+    let args: Vec<String> = std::env::args().skip(1).filter(|arg| arg != ALL_PROFILES_ARG).collect();
+
+    let mut failed = Vec::new();
+    for profile in &profiles {
+        println!("==> Profile: {}", profile);
+        let status = Command::new(&current_exe)
+            .arg("--profile").arg(profile)
+            .args(&args)
+            .status();
+        match status {
+            Ok(status) if status.success() => {},
+            Ok(_) => failed.push(profile.as_str()),
+            Err(_) => {
+                eprintln!("Unable to spawn run for profile: {}", profile);
+                failed.push(profile.as_str());
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        eprintln!("Failed profiles: {}", failed.join(", "));
+        std::process::exit(1);
+    }
+}
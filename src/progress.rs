@@ -0,0 +1,266 @@
+use super::config::Config;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+const BAR_REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+pub const PROGRESS_PREFIX: &str = "Progress: ";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// A bar on terminals, log lines otherwise
+    Auto,
+    Bar,
+    Log,
+    /// Log lines carrying a json snapshot, for programs watching a commit
+    Json,
+    None,
+}
+
+impl ProgressMode {
+    pub fn parse(name: &str) -> Option<ProgressMode> {
+        match name {
+            "auto" => Some(ProgressMode::Auto),
+            "bar" => Some(ProgressMode::Bar),
+            "log" => Some(ProgressMode::Log),
+            "json" => Some(ProgressMode::Json),
+            "none" => Some(ProgressMode::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ProgressSnapshot {
+    pub files_done: u64,
+    pub files_total: Option<u64>,
+    pub bytes_done: u64,
+    pub bytes_total: Option<u64>,
+    pub bytes_per_second: f64,
+    pub eta_seconds: Option<u64>,
+}
+
+/// Tracks how far a commit got and reports it as it goes
+pub struct Progress {
+    mode: ProgressMode,
+    interval: Duration,
+    started: Instant,
+    last_report: Option<Instant>,
+    snapshot: ProgressSnapshot,
+}
+
+impl Progress {
+    pub fn new(conf: &Config, verbose: bool) -> Progress {
+        // A bar would garble the file list printed by -v, so that gets log lines instead
+        let mode = match conf.get_progress_mode() {
+            ProgressMode::Auto if verbose || !is_terminal() => ProgressMode::Log,
+            ProgressMode::Auto => ProgressMode::Bar,
+            mode => mode,
+        };
+
+        Progress {
+            mode,
+            interval: conf.get_progress_interval(),
+            started: Instant::now(),
+            last_report: None,
+            snapshot: ProgressSnapshot::default(),
+        }
+    }
+
+    /// Totals are often estimates, e.g. taken from the previous commit
+    pub fn set_totals(&mut self, files_total: Option<u64>, bytes_total: Option<u64>) {
+        self.snapshot.files_total = files_total;
+        self.snapshot.bytes_total = bytes_total;
+    }
+
+    pub fn update(&mut self, files_done: u64, bytes_done: u64) {
+        self.snapshot.files_done = files_done;
+        self.snapshot.bytes_done = bytes_done;
+
+        // Once an estimate is overtaken it says nothing about what's left
+        if self.snapshot.files_total.is_some_and(|files_total| files_done > files_total) {
+            self.snapshot.files_total = None;
+        }
+        if self.snapshot.bytes_total.is_some_and(|bytes_total| bytes_done > bytes_total) {
+            self.snapshot.bytes_total = None;
+        }
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        self.snapshot.bytes_per_second = if elapsed > 0.0 { bytes_done as f64 / elapsed } else { 0.0 };
+        self.snapshot.eta_seconds = match self.snapshot.bytes_total {
+            Some(total) if self.snapshot.bytes_per_second > 0.0 => {
+                Some((total.saturating_sub(bytes_done) as f64 / self.snapshot.bytes_per_second) as u64)
+            },
+            _ => None,
+        };
+
+        let interval = if self.mode == ProgressMode::Bar { BAR_REDRAW_INTERVAL } else { self.interval };
+        let due = match self.last_report {
+            Some(last_report) => last_report.elapsed() >= interval,
+            None => self.mode == ProgressMode::Bar || self.started.elapsed() >= interval,
+        };
+        if due {
+            self.report();
+            self.last_report = Some(Instant::now());
+        }
+    }
+
+    pub fn finish(&mut self) {
+        match self.mode {
+            ProgressMode::Bar if self.last_report.is_some() => {
+                eprint!("\r\x1b[K");
+                let _ = std::io::stderr().flush();
+            },
+            ProgressMode::Json => self.report(),
+            _ => {},
+        }
+    }
+
+    fn report(&self) {
+        match self.mode {
+            ProgressMode::Bar => {
+                eprint!("\r\x1b[K{}", self.render_bar());
+                let _ = std::io::stderr().flush();
+            },
+            ProgressMode::Log => println!("{}{}", PROGRESS_PREFIX, self.render_line()),
+            ProgressMode::Json => {
+                println!("{}{}", PROGRESS_PREFIX, serde_json::to_string(&self.snapshot).unwrap_or_default());
+            },
+            ProgressMode::Auto | ProgressMode::None => {},
+        }
+    }
+
+    fn render_bar(&self) -> String {
+        let snapshot = &self.snapshot;
+        let mut bar = match self.get_fraction() {
+            Some(fraction) => {
+                let filled = (fraction * BAR_WIDTH as f64) as usize;
+                format!("[{}{}] {:3.0}% ", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled), fraction * 100.0)
+            },
+            None => String::new(),
+        };
+        bar += &format!("{} files  {}", snapshot.files_done, format_bytes(snapshot.bytes_done));
+        if let Some(bytes_total) = snapshot.bytes_total {
+            bar += &format!(" / {}", format_bytes(bytes_total));
+        }
+        bar += &format!("  {}/s", format_bytes(snapshot.bytes_per_second as u64));
+        if let Some(eta) = snapshot.eta_seconds {
+            bar += &format!("  ETA {}", format_duration(eta));
+        }
+        bar
+    }
+
+    fn render_line(&self) -> String {
+        let snapshot = &self.snapshot;
+        let mut line = match snapshot.files_total {
+            Some(files_total) => format!("{} of {} files", snapshot.files_done, files_total),
+            None => format!("{} files", snapshot.files_done),
+        };
+        line += &format!(", {}", format_bytes(snapshot.bytes_done));
+        if let (Some(bytes_total), Some(fraction)) = (snapshot.bytes_total, self.get_fraction()) {
+            line += &format!(" of {} ({:.0}%)", format_bytes(bytes_total), fraction * 100.0);
+        }
+        line += &format!(", {}/s", format_bytes(snapshot.bytes_per_second as u64));
+        if let Some(eta) = snapshot.eta_seconds {
+            line += &format!(", ETA {}", format_duration(eta));
+        }
+        line
+    }
+
+    fn get_fraction(&self) -> Option<f64> {
+        match self.snapshot.bytes_total {
+            Some(total) if total > 0 => Some((self.snapshot.bytes_done as f64 / total as f64).min(1.0)),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a snapshot back from a json progress line
+pub fn parse_line(line: &str) -> Option<ProgressSnapshot> {
+    serde_json::from_str(line.strip_prefix(PROGRESS_PREFIX)?).ok()
+}
+
+fn is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines_round_trip() {
+        let snapshot = ProgressSnapshot {
+            files_done: 12,
+            files_total: Some(40),
+            bytes_done: 1_048_576,
+            bytes_total: None,
+            bytes_per_second: 524_288.5,
+            eta_seconds: None,
+        };
+        let line = format!("{}{}", PROGRESS_PREFIX, serde_json::to_string(&snapshot).unwrap());
+
+        let parsed = parse_line(&line).unwrap();
+        assert_eq!(parsed.files_done, 12);
+        assert_eq!(parsed.files_total, Some(40));
+        assert_eq!(parsed.bytes_done, 1_048_576);
+        assert_eq!(parsed.bytes_total, None);
+        assert_eq!(parsed.bytes_per_second, 524_288.5);
+        assert_eq!(parsed.eta_seconds, None);
+    }
+
+    #[test]
+    fn other_lines_are_not_snapshots() {
+        assert!(parse_line("{\"files_done\":1}").is_none(), "the prefix is required");
+        assert!(parse_line("Progress: 3 of 10 files, 1.0 KiB, 512 B/s").is_none());
+        assert!(parse_line("Progress: {\"files_done\":1").is_none());
+        assert!(parse_line("Committed: 2026-10-19_04-23-56").is_none());
+        assert!(parse_line("").is_none());
+    }
+
+    #[test]
+    fn modes() {
+        assert!(ProgressMode::parse("auto") == Some(ProgressMode::Auto));
+        assert!(ProgressMode::parse("json") == Some(ProgressMode::Json));
+        assert!(ProgressMode::parse("none") == Some(ProgressMode::None));
+        assert!(ProgressMode::parse("Bar").is_none());
+        assert!(ProgressMode::parse("").is_none());
+    }
+
+    #[test]
+    fn byte_sizes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536 * 1024), "1.5 MiB");
+        assert_eq!(format_bytes(u64::MAX), "16777216.0 TiB");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(0), "0:00:00");
+        assert_eq!(format_duration(59), "0:00:59");
+        assert_eq!(format_duration(3661), "1:01:01");
+        assert_eq!(format_duration(100 * 3600), "100:00:00");
+    }
+}
@@ -0,0 +1,142 @@
+use super::config::{Config, RetentionConfig};
+use super::commit::Commit;
+use super::delete;
+use super::lock;
+use chrono::{DateTime, Local, TimeZone};
+
+/// Whether prune keeps a commit, and why
+pub struct Decision {
+    pub commit: Commit,
+    /// The rules keeping the commit, empty if it would be pruned
+    pub reasons: Vec<&'static str>,
+}
+
+/// Deletes every commit the retention policy doesn't keep. Pinned commits and the latest commit
+/// are always kept.
+pub fn prune(conf: &Config, dry_run: bool, verbose: bool) {
+    let _lock = if dry_run { None } else { Some(lock::acquire(conf)) };
+    let decisions = or_exit(try_plan(conf));
+
+    let mut to_delete = Vec::new();
+    for decision in decisions {
+        if decision.reasons.is_empty() {
+            println!("prune {}", decision.commit.get_name());
+            to_delete.push(decision.commit);
+        } else if verbose || dry_run {
+            println!("keep  {} ({})", decision.commit.get_name(), decision.reasons.join(", "));
+        }
+    }
+
+    if to_delete.is_empty() {
+        println!("Nothing to prune");
+    } else if !dry_run {
+        delete::remove_commits(conf, &to_delete, verbose);
+    }
+}
+
+/// What prune would do with every commit, newest first
+pub fn try_plan(conf: &Config) -> Result<Vec<Decision>, String> {
+    let retention = conf.get_retention()
+        .ok_or_else(|| "No [retention] policy configured, set keep_last, keep_daily, keep_weekly or keep_monthly".to_string())?;
+    let commits: Vec<Commit> = Commit::try_get_commits(conf)?.into_iter().rev().collect();
+    let latest = Commit::try_get_latest(conf)?.map(|latest| latest.get_name());
+
+    let times: Vec<DateTime<Local>> = commits.iter()
+        .map(|commit| Local.timestamp_opt(commit.get_timestamp(), 0).unwrap())
+        .collect();
+    let reasons = get_reasons(retention, &times);
+
+    Ok(commits.into_iter().zip(reasons).map(|(commit, mut reasons)| {
+        if commit.is_pinned() {
+            reasons.push("pinned");
+        }
+        if latest.as_deref() == Some(commit.get_name().as_str()) {
+            reasons.push("latest");
+        }
+        Decision {
+            commit,
+            reasons,
+        }
+    }).collect())
+}
+
+/// The retention rules keeping each of `times`, which are sorted newest first. A daily, weekly
+/// or monthly rule keeps the newest commit of each period until it has kept its count.
+fn get_reasons(retention: &RetentionConfig, times: &[DateTime<Local>]) -> Vec<Vec<&'static str>> {
+    let mut reasons = vec![Vec::new(); times.len()];
+    for reason in reasons.iter_mut().take(retention.keep_last) {
+        reason.push("last");
+    }
+
+    let rules: [(&'static str, usize, &str); 3] = [
+        ("daily", retention.keep_daily, "%Y-%m-%d"),
+        ("weekly", retention.keep_weekly, "%G-%V"),
+        ("monthly", retention.keep_monthly, "%Y-%m"),
+    ];
+    for (name, keep, period_format) in rules {
+        let mut kept = 0;
+        let mut last_period = None;
+        for (time, reason) in times.iter().zip(reasons.iter_mut()) {
+            if kept == keep {
+                break;
+            }
+
+            let period = time.format(period_format).to_string();
+            if last_period.as_ref() != Some(&period) {
+                reason.push(name);
+                kept += 1;
+                last_period = Some(period);
+            }
+        }
+    }
+    reasons
+}
+
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retention(keep_last: usize, keep_daily: usize, keep_weekly: usize, keep_monthly: usize) -> RetentionConfig {
+        RetentionConfig {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+        }
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn keep_last_keeps_the_newest() {
+        let times = [at(3, 12), at(2, 12), at(1, 12)];
+        assert_eq!(get_reasons(&retention(2, 0, 0, 0), &times), vec![vec!["last"], vec!["last"], vec![]]);
+    }
+
+    #[test]
+    fn daily_keeps_the_newest_commit_of_each_day() {
+        let times = [at(3, 18), at(3, 9), at(2, 18), at(2, 9), at(1, 9)];
+        let reasons = get_reasons(&retention(0, 2, 0, 0), &times);
+        assert_eq!(reasons, vec![vec!["daily"], vec![], vec!["daily"], vec![], vec![]]);
+    }
+
+    #[test]
+    fn rules_combine() {
+        // 2024-01-01 is a Monday, so the 7th ends the first ISO week
+        let times = [at(9, 12), at(8, 12), at(7, 12), at(1, 12)];
+        let reasons = get_reasons(&retention(1, 1, 2, 1), &times);
+        assert_eq!(reasons, vec![vec!["last", "daily", "weekly", "monthly"], vec![], vec!["weekly"], vec![]]);
+    }
+}
@@ -0,0 +1,160 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::chunks::Store;
+use super::lock;
+use super::rsync::DATA_FOLDER_NAME;
+use super::throttle;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const INCOMING_FOLDER: &str = "incoming";
+
+pub fn replicate(conf: &Config, target_repo: &Path, verbose: bool) {
+    // Commit paths end up in the target's latest.commit, so a relative --to must not leak into them
+    let target_repo = match fs::create_dir_all(target_repo).and_then(|_| target_repo.canonicalize()) {
+        Ok(target_repo) => target_repo,
+        Err(_) => {
+            eprintln!("Unable to prepare target repository at location: {}", target_repo.display());
+            std::process::exit(1);
+        },
+    };
+    if conf.get_repo_dir().canonicalize().ok().as_ref() == Some(&target_repo) {
+        eprintln!("Cannot replicate a repository into itself");
+        std::process::exit(1);
+    }
+
+    let target_conf = conf.with_repo_dir(target_repo.clone());
+    let _source_lock = lock::acquire(conf);
+    let _target_lock = lock::acquire(&target_conf);
+    let target_backups = target_conf.get_backups_folder();
+    let incoming_dir = target_repo.join(INCOMING_FOLDER);
+    let mkdir_res = fs::create_dir_all(&target_backups).and_then(|_| fs::create_dir_all(&incoming_dir));
+    if mkdir_res.is_err() {
+        eprintln!("Unable to prepare target repository at location: {}", target_repo.display());
+        std::process::exit(1);
+    }
+
+    let source_store = Store::open(conf);
+    let target_store = Store::open(&target_conf);
+
+    let mut previous: Option<Commit> = None;
+    let mut replicated = 0;
+    for commit in Commit::get_commits(conf) {
+        let name = commit.get_name();
+        if let Some(existing) = Commit::find(&target_conf, &name) {
+            previous = Some(existing);
+            continue;
+        }
+
+        println!("Replicating: {}", name);
+        let incoming_commit = incoming_dir.join(&name);
+        let mkdir_res = fs::create_dir_all(&incoming_commit);
+        if mkdir_res.is_err() {
+            eprintln!("Unable to make incoming folder at location: {}", incoming_commit.display());
+            std::process::exit(1);
+        }
+
+        if commit.is_chunked() {
+            target_store.copy_tree_from(&source_store, &commit.get_tree());
+        } else {
+            let link_dest = match &previous {
+                Some(previous) if !previous.is_chunked() => Some(previous.get_folder().join(DATA_FOLDER_NAME)),
+                _ => None,
+            };
+            sync_data(conf, &commit.get_folder().join(DATA_FOLDER_NAME), &incoming_commit.join(DATA_FOLDER_NAME), link_dest.as_deref(), verbose);
+        }
+
+        copy_commit_files(commit.get_folder(), &incoming_commit);
+
+        let target_commit_dir = target_backups.join(&name);
+        let rename_res = fs::rename(&incoming_commit, &target_commit_dir);
+        if rename_res.is_err() {
+            eprintln!("Unable to move replicated commit into place: {}", target_commit_dir.display());
+            std::process::exit(1);
+        }
+
+        previous = Commit::find(&target_conf, &name);
+        replicated += 1;
+    }
+
+    let source_latest = Commit::get_latest(conf);
+    if let Some(source_latest) = source_latest {
+        let target_latest = Commit::get_latest(&target_conf);
+        let outdated = match &target_latest {
+            Some(target_latest) => target_latest.get_timestamp() < source_latest.get_timestamp(),
+            None => true,
+        };
+
+        if outdated {
+            let latest = Commit::find(&target_conf, &source_latest.get_name());
+            if let Some(latest) = latest {
+                if let Err(message) = Commit::write_latest(&target_conf, &latest) {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    println!("Replicated {} commit(s) to {}", replicated, target_repo.display());
+}
+
+fn sync_data(conf: &Config, source: &Path, dest: &Path, link_dest: Option<&Path>, verbose: bool) {
+    let mut flags = "-aAXH".to_string();
+    if verbose {
+        flags += "v";
+    }
+
+    let mut rsync_command = Command::new("rsync");
+    rsync_command.arg(&flags);
+    rsync_command.arg("--delete");
+    throttle::throttle_rsync(throttle::get_current_limits(conf), &mut rsync_command);
+    if let Some(link_dest) = link_dest {
+        let link_dest = link_dest.canonicalize();
+        if link_dest.is_err() {
+            eprintln!("Unable to find previous replicated commit");
+            std::process::exit(1);
+        }
+        rsync_command.arg(format!("--link-dest={}", link_dest.unwrap().display()));
+    }
+    rsync_command.arg(format!("{}/", source.display()));
+    rsync_command.arg(format!("{}", dest.display()));
+    let rsync_command = rsync_command.status();
+
+    if rsync_command.is_err() {
+        eprintln!("Unable to spawn rsync command");
+        std::process::exit(1);
+    }
+
+    let rsync_output = rsync_command.unwrap();
+    if !rsync_output.success() {
+        eprintln!("rsync errored out with code: {}", rsync_output.code().unwrap_or(-1));
+        std::process::exit(1);
+    }
+}
+
+fn copy_commit_files(source: &Path, dest: &Path) {
+    let files = source.read_dir();
+    if files.is_err() {
+        eprintln!("Unable to read commit folder: {}", source.display());
+        std::process::exit(1);
+    }
+
+    for file in files.unwrap() {
+        if file.is_err() {
+            continue;
+        }
+
+        let file_path = file.unwrap().path();
+        if !file_path.is_file() {
+            continue;
+        }
+
+        let copy_res = fs::copy(&file_path, dest.join(file_path.file_name().unwrap()));
+        if copy_res.is_err() {
+            eprintln!("Unable to copy commit file: {}", file_path.display());
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,86 +1,234 @@
 use super::config::Config;
-use super::commit::Commit;
+use super::commit::{Commit, CommitStats};
+use super::commit_log::CommitLog;
+use super::excludes::Excludes;
+use super::progress::Progress;
 use super::status;
-use std::process::Command;
+use super::throttle;
+use super::status::Change;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
 use tempdir::TempDir;
 
 
-const DATA_FOLDER_NAME: &str = "data";
+pub const DATA_FOLDER_NAME: &str = "data";
 
-pub fn make_commit(conf: &Config, name: String, message: String, verbose: bool) -> Commit {
+pub fn make_commit(conf: &Config, source: &Path, name: String, message: String, log: &mut CommitLog, verbose: bool) -> Result<Commit, String> {
     let backups_dir = conf.get_backups_folder();
     let new_backup_folder = backups_dir.join(&name);
     if new_backup_folder.exists() {
-        eprintln!("Commit with name '{}' already exists", name);
-        std::process::exit(1);
+        return Err(format!("Commit with name '{}' already exists", name));
     }
     
-    let mkdir_res = fs::create_dir_all(&new_backup_folder);
-    if mkdir_res.is_err() {
-        eprintln!("Unable to make new backup folder at location: {}", new_backup_folder.display());
-        std::process::exit(1);
-    }
+    let started = Instant::now();
+    fs::create_dir_all(&new_backup_folder)
+        .map_err(|_| format!("Unable to make new backup folder at location: {}", new_backup_folder.display()))?;
     let new_commit = Commit::new(new_backup_folder.clone(), message);
 
     let data_folder = new_backup_folder.join(DATA_FOLDER_NAME);
     let latest_commit = Commit::get_latest(conf);
-    let mut flags = "-aAX".to_string();
-    if verbose {
-        flags += "v";
-    }
+    // Always ask for the file list so it ends up in the commit log, but only echo it with -v
+    let flags = "-aAXv";
 
-    let link_arg = if latest_commit.is_some() {
-        let latest_commit = latest_commit.unwrap();
-        let latest_folder = latest_commit.get_folder();
-        format!("--link-dest={}", latest_folder.canonicalize().unwrap().join(DATA_FOLDER_NAME).display())
-    } else {
-        String::new()
+    let link_arg = match &latest_commit {
+        Some(latest_commit) if !latest_commit.is_chunked() => {
+            let latest_folder = latest_commit.get_folder();
+            format!("--link-dest={}", latest_folder.canonicalize().unwrap().join(DATA_FOLDER_NAME).display())
+        },
+        _ => String::new(),
     };
 
-    let src_arg = format!("{}/", conf.get_backup_location().display());
+    let src_arg = format!("{}/", source.display());
     let dest_arg = format!("{}", data_folder.display());
 
     let mut rsync_command = Command::new("rsync");
-    rsync_command.arg(&flags);
+    rsync_command.arg(flags);
     rsync_command.arg("--delete");
+    rsync_command.arg("--stats");
+    rsync_command.arg("--info=progress2");
+    rsync_command.args(conf.get_excludes().get_rsync_args());
+    let limits = throttle::get_current_limits(conf);
+    throttle::throttle_rsync(limits, &mut rsync_command);
+    if !limits.is_empty() {
+        log.event(&format!("Throttling rsync: {}", limits.describe()));
+    }
     if !link_arg.is_empty() {
         rsync_command.arg(&link_arg);
     }
     rsync_command.arg(&src_arg);
     rsync_command.arg(&dest_arg);
-    let rsync_command = rsync_command.status();
-    
-    if rsync_command.is_err() {
-        eprintln!("Unable to spawn rsync command");
-        std::process::exit(1);
-    }
+    rsync_command.stdout(Stdio::piped());
+    rsync_command.stderr(Stdio::piped());
+    log.event(&format!("Running {}", describe_command(&rsync_command)));
+    let mut rsync_child = rsync_command.spawn()
+        .map_err(|err| format!("Unable to spawn rsync command: {}", err))?;
 
-    let rsync_output = rsync_command.unwrap();
+    // Drain stderr on its own thread so a chatty rsync can't block on a full pipe
+    let rsync_stderr = BufReader::new(rsync_child.stderr.take().unwrap());
+    let stderr_thread = thread::spawn(move || {
+        let mut errors = String::new();
+        for line in rsync_stderr.lines().map_while(Result::ok) {
+            eprintln!("{}", line);
+            errors += &line;
+            errors += "\n";
+        }
+        errors
+    });
+
+    // Until rsync has scanned everything the previous commit is the best guess at the totals
+    let mut progress = Progress::new(conf, verbose);
+    let previous_stats = latest_commit.as_ref().and_then(|latest_commit| latest_commit.get_stats());
+    progress.set_totals(
+        previous_stats.as_ref().map(|stats| stats.files_total),
+        previous_stats.as_ref().map(|stats| stats.bytes_total),
+    );
+
+    let mut stats = CommitStats::default();
+    let rsync_stdout = BufReader::new(rsync_child.stdout.take().unwrap());
+    for_each_record(rsync_stdout, |line| {
+        if let Some(update) = parse_progress_line(line) {
+            if let Some(files_total) = update.files_total {
+                let bytes_total = (update.bytes * 100).checked_div(update.percent);
+                progress.set_totals(Some(files_total), bytes_total);
+            }
+            progress.update(update.files_done, update.bytes);
+            return;
+        }
+
+        if verbose {
+            println!("{}", line);
+        }
+        parse_stats_line(line, &mut stats);
+        log.output(line);
+    });
+    progress.finish();
+
+    let rsync_output = rsync_child.wait()
+        .map_err(|_| "Unable to wait for rsync command".to_string())?;
+    log.output(&stderr_thread.join().unwrap_or_default());
     if !rsync_output.success() {
-        eprintln!("rsync errored out with code: {}", rsync_output.code().unwrap());
-        std::process::exit(1);
+        return Err(format!("rsync errored out with code: {}", rsync_output.code().unwrap_or(-1)));
     }
+    stats.duration_seconds = started.elapsed().as_secs_f64();
     
-    new_commit.write_commit_file();
-    Commit::write_latest(conf, &new_commit);
-    new_commit
+    new_commit.write_stats_file(&stats)?;
+    new_commit.write_metadata_file(latest_commit.as_ref())?;
+    new_commit.write_commit_file()?;
+    Commit::write_latest(conf, &new_commit)?;
+    Ok(new_commit)
 }
 
-pub fn print_status(conf: &Config) {
-    let latest_commit = Commit::get_latest(conf);
-    let compare_path = if latest_commit.is_some() {
-        latest_commit.unwrap().get_folder().join(DATA_FOLDER_NAME)
-    } else {
-        let empty_dir = TempDir::new("resilient");
-        if empty_dir.is_err() {
-            eprintln!("Unable to allocate empty dir!");
-            std::process::exit(1);
+struct ProgressUpdate {
+    bytes: u64,
+    percent: u64,
+    files_done: u64,
+    /// Only known once rsync has the complete file list
+    files_total: Option<u64>,
+}
+
+/// Parses --info=progress2 output, e.g. "1,238,099  45%  1.23MB/s  0:00:02 (xfr#12, to-chk=100/2000)"
+fn parse_progress_line(line: &str) -> Option<ProgressUpdate> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.len() < 2 {
+        return None;
+    }
+    let bytes = tokens[0].replace(',', "").parse::<u64>().ok()?;
+    let percent = tokens[1].strip_suffix('%')?.parse::<u64>().ok()?;
+
+    let mut update = ProgressUpdate { bytes, percent, files_done: 0, files_total: None };
+    for token in &tokens[2..] {
+        let (is_final, count) = if let Some(count) = token.strip_prefix("to-chk=") {
+            (true, count)
+        } else if let Some(count) = token.strip_prefix("ir-chk=") {
+            (false, count)
+        } else {
+            continue;
+        };
+
+        let (remaining, total) = count.trim_end_matches(')').split_once('/')?;
+        let remaining = remaining.replace(',', "").parse::<u64>().ok()?;
+        let total = total.replace(',', "").parse::<u64>().ok()?;
+        update.files_done = total.saturating_sub(remaining);
+        if is_final {
+            update.files_total = Some(total);
         }
-        empty_dir.unwrap().path().to_path_buf()
+    }
+    Some(update)
+}
+
+/// Calls `f` with every line of `reader`, treating the carriage returns rsync redraws its
+/// progress with as line ends too
+fn for_each_record(mut reader: impl BufRead, mut f: impl FnMut(&str)) {
+    let mut record = Vec::new();
+    loop {
+        let buffer = match reader.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => buffer,
+            _ => break,
+        };
+
+        let consumed = buffer.len();
+        for byte in buffer {
+            if *byte == b'\n' || *byte == b'\r' {
+                if !record.is_empty() {
+                    f(&String::from_utf8_lossy(&record));
+                    record.clear();
+                }
+            } else {
+                record.push(*byte);
+            }
+        }
+        reader.consume(consumed);
+    }
+
+    if !record.is_empty() {
+        f(&String::from_utf8_lossy(&record));
+    }
+}
+
+fn parse_stats_line(line: &str, stats: &mut CommitStats) {
+    let (key, value) = match line.split_once(':') {
+        Some(split) => split,
+        None => return,
+    };
+
+    // Newer rsync versions break the file count down by type, e.g. "5 (reg: 3, dir: 2)"
+    let value = match value.split_once("reg:") {
+        Some((_, regular)) => regular,
+        None => value,
+    };
+    let number: String = value.trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == ',')
+        .filter(|c| *c != ',')
+        .collect();
+    let number = match number.parse::<u64>() {
+        Ok(number) => number,
+        Err(_) => return,
     };
 
-    let src_arg = format!("{}/", conf.get_backup_location().display());
+    match key {
+        "Number of files" => stats.files_total = number,
+        "Number of regular files transferred" | "Number of files transferred" => stats.files_changed = number,
+        "Total file size" => stats.bytes_total = number,
+        "Total transferred file size" => stats.bytes_changed = number,
+        _ => {},
+    }
+}
+
+/// Lists how `source` differs from a commit, leaving out excluded paths as the next commit would
+pub fn get_changes(source: &Path, latest_commit: Option<&Commit>, excludes: &Excludes) -> Result<Vec<Change>, String> {
+    let empty_dir = TempDir::new("resilient").map_err(|_| "Unable to allocate empty dir!".to_string())?;
+
+    let compare_path = match latest_commit {
+        Some(latest_commit) => latest_commit.get_folder().join(DATA_FOLDER_NAME),
+        None => empty_dir.path().to_path_buf(),
+    };
+
+    let src_arg = format!("{}/", source.display());
     let dest_arg = format!("{}", compare_path.display());
 
     let flags = "-aAXin";
@@ -89,64 +237,39 @@ pub fn print_status(conf: &Config) {
     let rsync_command = Command::new("rsync")
         .arg(flags)
         .arg(delete_flag)
+        .arg("--delete-excluded")
+        .args(excludes.get_rsync_args())
         .arg(&src_arg)
         .arg(&dest_arg)
         .output();
-    
-    if rsync_command.is_err() {
-        eprintln!("Unable to spawn rsync command");
-        std::process::exit(1);
-    }
 
-    let rsync_output = rsync_command.unwrap();
+    let rsync_output = rsync_command.map_err(|_| "Unable to spawn rsync command".to_string())?;
     if !rsync_output.status.success() {
-        eprintln!("rsync command failued with: {}", String::from_utf8(rsync_output.stderr).unwrap());
-        std::process::exit(1);
+        return Err(format!("rsync command failued with: {}", String::from_utf8_lossy(&rsync_output.stderr)));
     }
 
-    let output_str = String::from_utf8(rsync_output.stdout);
-    if output_str.is_err() {
-        eprintln!("Unable to parse rsync output");
-        std::process::exit(1);
-    }
-
-    let output_str = output_str.unwrap();
-    for output_line in output_str.lines() {
-        let parsed_change = status::parse_change(output_line);
-        if parsed_change.is_some() {
-            println!("\t{}", parsed_change.unwrap().get_mod_string());
-        }
-    }
+    let output_str = String::from_utf8(rsync_output.stdout).map_err(|_| "Unable to parse rsync output".to_string())?;
+    Ok(output_str.lines().filter_map(status::parse_change).collect())
 }
 
-pub fn restore(conf: &Config, commit: String, path: String, verbose: bool) {
-    let backups_dir = conf.get_backups_folder();
-    let commit_dir = backups_dir.join(&commit);
-    if !commit_dir.exists() {
-        eprintln!("Cannot find commit: {}", commit);
-        std::process::exit(1);
-    }
-
+pub fn restore(conf: &Config, commit: &Commit, path: String, verbose: bool) -> Result<(), String> {
+    let commit_dir = commit.get_folder();
 
-    let src_arg = if path.is_empty() || path == "/".to_string() {
+    let src_arg = if path.is_empty() || path == "/" {
         format!("{}/", commit_dir.join(DATA_FOLDER_NAME).display())
+    } else if let Some(stripped_path) = path.strip_prefix('/') {
+        format!("{}{}", commit_dir.join(DATA_FOLDER_NAME).display(), stripped_path)
     } else {
-        if path.starts_with('/') {
-            format!("{}{}", commit_dir.join(DATA_FOLDER_NAME).display(), &path[1..])
-        } else {
-            format!("{}/{}", commit_dir.join(DATA_FOLDER_NAME).display(), path)
-        }
+        format!("{}/{}", commit_dir.join(DATA_FOLDER_NAME).display(), path)
     };
 
     let backup_location = conf.get_backup_location();
-    let dest_arg = if path.is_empty() || path == "/".to_string() {
+    let dest_arg = if path.is_empty() || path == "/" {
         format!("{}", backup_location.display())
+    } else if path.starts_with('/') {
+        format!("{}{}", backup_location.display(), path)
     } else {
-        if path.starts_with('/') {
-            format!("{}{}", backup_location.display(), path)
-        } else {
-            format!("{}", backup_location.join(path).display())
-        }
+        format!("{}", backup_location.join(&path).display())
     };
 
     let flags = if verbose {
@@ -155,21 +278,150 @@ pub fn restore(conf: &Config, commit: String, path: String, verbose: bool) {
         "-aAX"
     };
 
-    let rsync_command = Command::new("rsync")
-        .arg(flags)
-        .arg("--delete")
-        .arg(&src_arg)
-        .arg(&dest_arg)
-        .status();
-    
-    if rsync_command.is_err() {
-        eprintln!("Unable to spawn rsync command");
-        std::process::exit(1);
+    let mut rsync_command = Command::new("rsync");
+    rsync_command.arg(flags);
+    rsync_command.arg("--delete");
+    rsync_command.arg("--info=progress2");
+    // Excluded paths aren't in the commit, so keep --delete away from them on a whole tree restore
+    if path.is_empty() || path == "/" {
+        rsync_command.args(conf.get_excludes().get_rsync_args());
     }
+    throttle::throttle_rsync(throttle::get_current_limits(conf), &mut rsync_command);
+    rsync_command.arg(&src_arg);
+    rsync_command.arg(&dest_arg);
+    rsync_command.stdout(Stdio::piped());
+    let mut rsync_child = rsync_command.spawn()
+        .map_err(|err| format!("Unable to spawn rsync command: {}", err))?;
 
-    let rsync_output = rsync_command.unwrap();
+    // The commit's stats only describe the whole tree, so restoring a part of it has no totals
+    let mut progress = Progress::new(conf, verbose);
+    if path.is_empty() || path == "/" {
+        let stats = commit.get_stats();
+        progress.set_totals(
+            stats.as_ref().map(|stats| stats.files_total),
+            stats.as_ref().map(|stats| stats.bytes_total),
+        );
+    }
+
+    let rsync_stdout = BufReader::new(rsync_child.stdout.take().unwrap());
+    for_each_record(rsync_stdout, |line| {
+        if let Some(update) = parse_progress_line(line) {
+            if let Some(files_total) = update.files_total {
+                let bytes_total = (update.bytes * 100).checked_div(update.percent);
+                progress.set_totals(Some(files_total), bytes_total);
+            }
+            progress.update(update.files_done, update.bytes);
+        } else if verbose {
+            println!("{}", line);
+        }
+    });
+    progress.finish();
+
+    let rsync_output = rsync_child.wait()
+        .map_err(|_| "Unable to wait for rsync command".to_string())?;
     if !rsync_output.success() {
-        eprintln!("rsync errored out with code: {}", rsync_output.code().unwrap());
-        std::process::exit(1);
+        return Err(format!("rsync errored out with code: {}", rsync_output.code().unwrap_or(-1)));
+    }
+    Ok(())
+}
+
+/// The command line a command runs, for the commit log
+fn describe_command(command: &Command) -> String {
+    let mut parts = vec![command.get_program().to_string_lossy().into_owned()];
+    parts.extend(command.get_args().map(|arg| arg.to_string_lossy().into_owned()));
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_line_while_scanning() {
+        let update = parse_progress_line("     32,768   3%   31.25MB/s    0:00:00 (xfr#1, ir-chk=1004/1012)").unwrap();
+        assert_eq!(update.bytes, 32768);
+        assert_eq!(update.percent, 3);
+        assert_eq!(update.files_done, 8);
+        assert_eq!(update.files_total, None);
+    }
+
+    #[test]
+    fn progress_line_with_complete_file_list() {
+        let update = parse_progress_line("  1,238,099,456  45%  1.23MB/s  0:00:02 (xfr#12, to-chk=100/2,000)").unwrap();
+        assert_eq!(update.bytes, 1238099456);
+        assert_eq!(update.percent, 45);
+        assert_eq!(update.files_done, 1900);
+        assert_eq!(update.files_total, Some(2000));
+    }
+
+    #[test]
+    fn progress_line_without_file_counts() {
+        let update = parse_progress_line("              0   0%    0.00kB/s    0:00:00").unwrap();
+        assert_eq!(update.bytes, 0);
+        assert_eq!(update.files_done, 0);
+        assert_eq!(update.files_total, None);
+    }
+
+    #[test]
+    fn other_lines_are_not_progress() {
+        assert!(parse_progress_line("sending incremental file list").is_none());
+        assert!(parse_progress_line("etc/hostname").is_none());
+        assert!(parse_progress_line("Number of files: 5 (reg: 3, dir: 2)").is_none());
+        assert!(parse_progress_line("").is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn records_split_on_carriage_returns_and_newlines() {
+        let output = "sending incremental file list\n\r      1,024  50%    0.00kB/s    0:00:00\r      2,048 100%    0.00kB/s    0:00:00 (xfr#1, to-chk=0/2)\r\ndone";
+        let mut records = Vec::new();
+        for_each_record(output.as_bytes(), |record| records.push(record.to_string()));
+        assert_eq!(records, vec![
+            "sending incremental file list",
+            "      1,024  50%    0.00kB/s    0:00:00",
+            "      2,048 100%    0.00kB/s    0:00:00 (xfr#1, to-chk=0/2)",
+            "done",
+        ]);
+    }
+
+    #[test]
+    fn records_span_buffer_refills() {
+        let output = "first line\rsecond line\n";
+        let reader = BufReader::with_capacity(4, output.as_bytes());
+        let mut records = Vec::new();
+        for_each_record(reader, |record| records.push(record.to_string()));
+        assert_eq!(records, vec!["first line", "second line"]);
+    }
+
+    #[test]
+    fn stats_with_type_breakdown() {
+        let mut stats = CommitStats::default();
+        parse_stats_line("Number of files: 1,205 (reg: 1,100, dir: 100, link: 5)", &mut stats);
+        parse_stats_line("Number of regular files transferred: 12", &mut stats);
+        parse_stats_line("Total file size: 10,485,760 bytes", &mut stats);
+        parse_stats_line("Total transferred file size: 4,096 bytes", &mut stats);
+        assert_eq!(stats.files_total, 1100);
+        assert_eq!(stats.files_changed, 12);
+        assert_eq!(stats.bytes_total, 10485760);
+        assert_eq!(stats.bytes_changed, 4096);
+    }
+
+    #[test]
+    fn stats_from_older_rsync() {
+        let mut stats = CommitStats::default();
+        parse_stats_line("Number of files: 42", &mut stats);
+        parse_stats_line("Number of files transferred: 7", &mut stats);
+        assert_eq!(stats.files_total, 42);
+        assert_eq!(stats.files_changed, 7);
+    }
+
+    #[test]
+    fn unrelated_stats_lines_are_ignored() {
+        let mut stats = CommitStats::default();
+        parse_stats_line("Number of created files: 3 (reg: 3)", &mut stats);
+        parse_stats_line("Literal data: 4,096 bytes", &mut stats);
+        parse_stats_line("sent 1,234 bytes  received 56 bytes  2,580.00 bytes/sec", &mut stats);
+        parse_stats_line("Total file size: unknown", &mut stats);
+        assert_eq!(stats.files_total, 0);
+        assert_eq!(stats.bytes_total, 0);
+    }
+}
@@ -0,0 +1,398 @@
+use super::config::Config;
+use super::backend;
+use super::metrics::{self, Outcome};
+use super::prune;
+use chrono::{DateTime, Datelike, Duration, Local, TimeZone, Timelike};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+const LAST_RUN_FILE_NAME: &str = "schedule.last";
+const MAX_SLEEP_SECONDS: i64 = 60;
+const MINUTES_PER_YEAR: i64 = 366 * 24 * 60;
+
+#[derive(Clone)]
+pub enum Trigger {
+    Interval(Duration),
+    Cron(CronSchedule),
+}
+
+#[derive(Clone)]
+pub struct CronSchedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl Trigger {
+    pub fn parse_interval(interval: &str) -> Option<Trigger> {
+        let interval = interval.trim();
+        if interval.len() < 2 {
+            return None;
+        }
+
+        let (amount, unit) = interval.split_at(interval.len() - 1);
+        let amount = amount.trim().parse::<i64>().ok()?;
+        if amount <= 0 {
+            return None;
+        }
+
+        let duration = match unit {
+            "s" => Duration::seconds(amount),
+            "m" => Duration::minutes(amount),
+            "h" => Duration::hours(amount),
+            "d" => Duration::days(amount),
+            _ => return None,
+        };
+        Some(Trigger::Interval(duration))
+    }
+
+    pub fn parse_cron(expression: &str) -> Option<Trigger> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+
+        let mut days_of_week = parse_cron_field(fields[4], 0, 7)?;
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+
+        Some(Trigger::Cron(CronSchedule {
+            minutes: parse_cron_field(fields[0], 0, 59)?,
+            hours: parse_cron_field(fields[1], 0, 23)?,
+            days_of_month: parse_cron_field(fields[2], 1, 31)?,
+            months: parse_cron_field(fields[3], 1, 12)?,
+            days_of_week,
+            any_day_of_month: fields[2] == "*",
+            any_day_of_week: fields[4] == "*",
+        }))
+    }
+
+    fn next_after(&self, after: DateTime<Local>) -> DateTime<Local> {
+        match self {
+            Trigger::Interval(duration) => after + *duration,
+            Trigger::Cron(cron) => cron.next_after(after),
+        }
+    }
+}
+
+impl CronSchedule {
+    fn matches(&self, time: &DateTime<Local>) -> bool {
+        let day_of_month = self.days_of_month[time.day() as usize];
+        let day_of_week = self.days_of_week[time.weekday().num_days_from_sunday() as usize];
+
+        // Like cron, when both day fields are restricted a match on either one is enough
+        let day = if self.any_day_of_month || self.any_day_of_week {
+            day_of_month && day_of_week
+        } else {
+            day_of_month || day_of_week
+        };
+
+        day && self.minutes[time.minute() as usize]
+            && self.hours[time.hour() as usize]
+            && self.months[time.month() as usize]
+    }
+
+    fn next_after(&self, after: DateTime<Local>) -> DateTime<Local> {
+        let mut candidate = after.with_second(0).unwrap().with_nanosecond(0).unwrap() + Duration::minutes(1);
+        for _ in 0..MINUTES_PER_YEAR {
+            if self.matches(&candidate) {
+                return candidate;
+            }
+            candidate += Duration::minutes(1);
+        }
+
+        eprintln!("Cron schedule never matches within a year");
+        std::process::exit(1);
+    }
+}
+
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Option<Vec<bool>> {
+    let mut allowed = vec![false; max as usize + 1];
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return None;
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse::<u32>().ok()?, end.parse::<u32>().ok()?)
+        } else {
+            let value = range.parse::<u32>().ok()?;
+            if part.contains('/') {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return None;
+        }
+
+        let mut value = start;
+        while value <= end {
+            allowed[value as usize] = true;
+            value += step;
+        }
+    }
+
+    Some(allowed)
+}
+
+pub fn run_daemon(conf: &Config) {
+    let schedule = conf.get_schedule();
+    if schedule.is_none() {
+        eprintln!("No [schedule] section found in config");
+        std::process::exit(1);
+    }
+    let trigger = schedule.unwrap().trigger.clone();
+
+    let mut next_run = match read_last_run(conf) {
+        Some(last_run) => trigger.next_after(last_run),
+        None => match trigger {
+            Trigger::Interval(_) => Local::now(),
+            Trigger::Cron(_) => trigger.next_after(Local::now()),
+        },
+    };
+    log(&format!("Next run at {}", next_run.format("%Y-%m-%d %H:%M:%S")));
+
+    loop {
+        let now = Local::now();
+        if now < next_run {
+            // Sleep in short steps so runs missed during suspend are caught up on wake
+            let wait = (next_run - now).num_seconds().clamp(1, MAX_SLEEP_SECONDS);
+            std::thread::sleep(std::time::Duration::from_secs(wait as u64));
+            continue;
+        }
+
+        if now - next_run > Duration::seconds(MAX_SLEEP_SECONDS) {
+            log("Catching up on missed run");
+        }
+
+        // Failed runs aren't recorded, so a restarted daemon catches up on them
+        if run_child(conf) {
+            write_last_run(conf, now);
+        }
+
+        next_run = trigger.next_after(now);
+        log(&format!("Next run at {}", next_run.format("%Y-%m-%d %H:%M:%S")));
+    }
+}
+
+pub fn run_once(conf: &Config) {
+    let schedule = conf.get_schedule();
+    if schedule.is_none() {
+        eprintln!("No [schedule] section found in config");
+        std::process::exit(1);
+    }
+    let schedule = schedule.unwrap();
+
+    let started = Instant::now();
+    let changes = backend::get_changes(conf);
+    if changes.is_empty() {
+        metrics::write(conf, Outcome::Skipped, started.elapsed().as_secs_f64());
+        log("Nothing changed since the latest commit, skipping");
+        return;
+    }
+
+    let now = Local::now();
+    let message = schedule.message
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{time}", &now.format("%H:%M:%S").to_string())
+        .replace("{changes}", &changes.len().to_string());
+    let name = format!("{}", chrono::Utc::now().format("%Y-%m-%d_%H-%M-%S"));
+
+    let _ = backend::make_commit(conf, name.clone(), message, false);
+    log(&format!("Committed: {}", name));
+
+    if schedule.prune {
+        prune::prune(conf, false, false);
+    }
+}
+
+/// A resilient command using the same config, profile, overrides and repository as `conf`.
+/// Long-running processes make commits through it so a failed commit, which exits, doesn't
+/// take them down.
+pub fn child_command(conf: &Config) -> Command {
+    let mut child = Command::new(get_current_exe());
+    child.args(child_args(conf));
+    child
+}
+
+pub fn get_current_exe() -> PathBuf {
+    match std::env::current_exe() {
+        Ok(current_exe) => current_exe,
+        Err(_) => {
+            eprintln!("Unable to find resilient executable");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The global options selecting the same config, profile, overrides, repository and limits
+/// as `conf`. Paths are made absolute, since children may run from another directory.
+pub fn child_args(conf: &Config) -> Vec<OsString> {
+    let absolute = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut args: Vec<OsString> = Vec::new();
+    if let Some(config_path) = conf.get_path() {
+        args.push("--config".into());
+        args.push(absolute(config_path).into());
+    }
+    if let Some(profile) = conf.get_profile() {
+        args.push("--profile".into());
+        args.push(profile.into());
+    }
+    for set in conf.get_overrides() {
+        args.push("--set".into());
+        args.push(set.into());
+    }
+    args.push("--repo".into());
+    args.push(absolute(conf.get_repo_dir()).into());
+    args.extend(conf.get_throttle().forced.get_args().into_iter().map(OsString::from));
+    args
+}
+
+/// Runs a scheduled commit in a child process, returning whether it succeeded
+fn run_child(conf: &Config) -> bool {
+    let child = child_command(conf)
+        .arg("daemon")
+        .arg("--once")
+        .status();
+    match child {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            log(&format!("Scheduled run failed with code: {}", status.code().unwrap_or(-1)));
+            false
+        },
+        Err(_) => {
+            log("Unable to spawn scheduled run");
+            false
+        },
+    }
+}
+
+fn read_last_run(conf: &Config) -> Option<DateTime<Local>> {
+    let contents = fs::read_to_string(conf.get_repo_dir().join(LAST_RUN_FILE_NAME)).ok()?;
+    let timestamp = contents.trim().parse::<i64>().ok()?;
+    Local.timestamp_opt(timestamp, 0).single()
+}
+
+fn write_last_run(conf: &Config, time: DateTime<Local>) {
+    let write_res = fs::write(conf.get_repo_dir().join(LAST_RUN_FILE_NAME), format!("{}", time.timestamp()));
+    if write_res.is_err() {
+        log("Unable to record scheduled run time");
+    }
+}
+
+fn log(message: &str) {
+    println!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    fn interval(value: &str) -> Option<Duration> {
+        match Trigger::parse_interval(value)? {
+            Trigger::Interval(duration) => Some(duration),
+            Trigger::Cron(_) => None,
+        }
+    }
+
+    fn allowed(field: &str, min: u32, max: u32) -> Option<Vec<u32>> {
+        let allowed = parse_cron_field(field, min, max)?;
+        Some((0..=max).filter(|value| allowed[*value as usize]).collect())
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(interval("45s"), Some(Duration::seconds(45)));
+        assert_eq!(interval("30m"), Some(Duration::minutes(30)));
+        assert_eq!(interval(" 6h "), Some(Duration::hours(6)));
+        assert_eq!(interval("1d"), Some(Duration::days(1)));
+    }
+
+    #[test]
+    fn invalid_intervals() {
+        for value in ["", "h", "0m", "-5m", "10", "10w", "1.5h", "h1"] {
+            assert!(interval(value).is_none(), "'{}' should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn cron_fields() {
+        assert_eq!(allowed("*/15", 0, 59), Some(vec![0, 15, 30, 45]));
+        assert_eq!(allowed("5/20", 0, 59), Some(vec![5, 25, 45]));
+        assert_eq!(allowed("1-5", 0, 6), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(allowed("9-17/4", 0, 23), Some(vec![9, 13, 17]));
+        assert_eq!(allowed("1,15,31", 1, 31), Some(vec![1, 15, 31]));
+        assert_eq!(allowed("*", 1, 12), Some((1..=12).collect()));
+    }
+
+    #[test]
+    fn invalid_cron_fields() {
+        for field in ["60", "5-1", "*/0", "0", "a", "1-", "1,,2"] {
+            assert!(allowed(field, 1, 59).is_none(), "'{}' should be rejected", field);
+        }
+    }
+
+    #[test]
+    fn cron_needs_five_fields() {
+        assert!(Trigger::parse_cron("0 2 * *").is_none());
+        assert!(Trigger::parse_cron("0 2 * * * *").is_none());
+        assert!(Trigger::parse_cron("0 2 * * *").is_some());
+    }
+
+    #[test]
+    fn cron_runs_at_the_next_matching_minute() {
+        let trigger = Trigger::parse_cron("30 2 * * *").unwrap();
+        assert_eq!(trigger.next_after(at(2026, 10, 19, 1, 0)), at(2026, 10, 19, 2, 30));
+        assert_eq!(trigger.next_after(at(2026, 10, 19, 2, 30)), at(2026, 10, 20, 2, 30));
+        assert_eq!(trigger.next_after(at(2026, 12, 31, 3, 0)), at(2027, 1, 1, 2, 30));
+    }
+
+    #[test]
+    fn cron_sunday_is_zero_or_seven() {
+        // 2026-10-19 is a Monday
+        for expression in ["0 12 * * 0", "0 12 * * 7"] {
+            let trigger = Trigger::parse_cron(expression).unwrap();
+            assert_eq!(trigger.next_after(at(2026, 10, 19, 0, 0)), at(2026, 10, 25, 12, 0));
+        }
+    }
+
+    #[test]
+    fn cron_restricted_day_fields_match_either() {
+        // On the 1st of the month or on Mondays, like cron
+        let trigger = Trigger::parse_cron("0 0 1 * 1").unwrap();
+        assert_eq!(trigger.next_after(at(2026, 10, 20, 0, 0)), at(2026, 10, 26, 0, 0));
+        assert_eq!(trigger.next_after(at(2026, 10, 27, 0, 0)), at(2026, 11, 1, 0, 0));
+
+        // With one of them left at * only the other one counts
+        let trigger = Trigger::parse_cron("0 0 1 * *").unwrap();
+        assert_eq!(trigger.next_after(at(2026, 10, 20, 0, 0)), at(2026, 11, 1, 0, 0));
+    }
+
+    #[test]
+    fn interval_runs_after_the_duration() {
+        let trigger = Trigger::parse_interval("90m").unwrap();
+        assert_eq!(trigger.next_after(at(2026, 10, 19, 23, 0)), at(2026, 10, 20, 0, 30));
+    }
+}
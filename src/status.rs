@@ -7,6 +7,29 @@ pub enum Change {
 }
 
 impl Change {
+    pub fn creation(file_name: String, file_type: FileType) -> Change {
+        Change::Modification(Modification {
+            file_name,
+            file_type,
+            modifiers: Modifiers::Creation
+        })
+    }
+
+    pub fn update(file_name: String, file_type: FileType, modlist: ModList) -> Change {
+        Change::Modification(Modification {
+            file_name,
+            file_type,
+            modifiers: Modifiers::Update(modlist)
+        })
+    }
+
+    pub fn get_file_name(&self) -> &str {
+        match self {
+            Change::Deleting(fname) => fname,
+            Change::Modification(modification) => &modification.file_name,
+        }
+    }
+
     pub fn get_mod_string(&self) -> String {
         match self {
             Change::Deleting(fname) => {
@@ -50,19 +73,13 @@ impl Change {
     }
 }
 
-struct Modification {
+pub struct Modification {
     file_name: String,
-    update_type: UpdateType,
     file_type: FileType,
     modifiers: Modifiers,
 }
 
-enum UpdateType {
-    Transfer,
-    Creation,
-}
-
-enum FileType {
+pub enum FileType {
     File,
     Directory,
     Symlink(String),
@@ -72,15 +89,12 @@ enum FileType {
 
 impl FileType {
     fn is_symlink(&self) -> bool {
-        match self {
-            FileType::Symlink(_) => true,
-            _ =>  false
-        }
+        matches!(self, FileType::Symlink(_))
     }
 
     fn get_symlink_dest(&self) -> &str {
         match self {
-            FileType::Symlink(dest) => &dest,
+            FileType::Symlink(dest) => dest,
             _ => panic!("File isn't a symlink")
         }
     }
@@ -91,15 +105,22 @@ enum Modifiers {
     Update(ModList)
 }
 
-struct ModList {
-    checksum: bool,
-    size: bool,
-    mod_time: bool,
-    perms: bool,
-    owner: bool,
-    group: bool,
-    acl: bool,
-    xattrs: bool,
+#[derive(Default)]
+pub struct ModList {
+    pub checksum: bool,
+    pub size: bool,
+    pub mod_time: bool,
+    pub perms: bool,
+    pub owner: bool,
+    pub group: bool,
+    pub acl: bool,
+    pub xattrs: bool,
+}
+
+impl ModList {
+    pub fn has_changes(&self) -> bool {
+        self.checksum || self.size || self.mod_time || self.perms || self.owner || self.group || self.acl || self.xattrs
+    }
 }
 
 pub fn parse_change(line: &str) -> Option<Change> {
@@ -118,22 +139,9 @@ pub fn parse_change(line: &str) -> Option<Change> {
         return None;
     }
     let update_type_char = chars[0];
-    
-    let update_type = match update_type_char {
-        '>' => {
-            UpdateType::Transfer
-        },
-        '.' => {
-            UpdateType::Transfer
-        },
-        'c' => {
-            UpdateType::Creation
-        },
-
-        _ => {
-            return None;
-        }
-    };
+    if update_type_char != '>' && update_type_char != '.' && update_type_char != 'c' {
+        return None;
+    }
 
     if chars.len() < 2 {
         return None;
@@ -163,11 +171,8 @@ pub fn parse_change(line: &str) -> Option<Change> {
             Modifiers::Creation
         },
         _ => {
-            let modlist = parse_modifiers(&chars[2..]);
-            if modlist.is_none() {
-                return None;
-            }
-            Modifiers::Update(modlist.unwrap())
+            let modlist = parse_modifiers(&chars[2..])?;
+            Modifiers::Update(modlist)
         }
     };
 
@@ -180,7 +185,6 @@ pub fn parse_change(line: &str) -> Option<Change> {
 
     Some(Change::Modification(Modification {
         file_name,
-        update_type,
         file_type,
         modifiers
     }))
@@ -0,0 +1,169 @@
+use super::config::Config;
+use super::schedule;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const UNIT_NAME: &str = "resilient";
+const USER_UNIT_PATH: &str = "systemd/user";
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+pub fn install(conf: &Config, user: bool, unit_dir: Option<PathBuf>, on_calendar: &str) {
+    let (service_file_name, timer_file_name) = get_unit_file_names(conf.get_profile());
+    let unit_dir = get_unit_dir(user, unit_dir);
+    let mkdir_res = fs::create_dir_all(&unit_dir);
+    if mkdir_res.is_err() {
+        eprintln!("Unable to create unit directory: {}", unit_dir.display());
+        std::process::exit(1);
+    }
+
+    // Run with the same config file, profile, overrides and repository we were run with
+    let mut exec_start = quote_arg(schedule::get_current_exe().as_os_str());
+    for arg in schedule::child_args(conf) {
+        exec_start.push(' ');
+        exec_start.push_str(&quote_arg(&arg));
+    }
+
+    let service = format!(
+"[Unit]
+Description=Resilient incremental backup
+
+[Service]
+Type=oneshot
+ExecStart={} create -m \"Scheduled backup\"
+", exec_start);
+
+    let timer = format!(
+"[Unit]
+Description=Run resilient backups on a schedule
+
+[Timer]
+OnCalendar={}
+Persistent=true
+RandomizedDelaySec=5min
+
+[Install]
+WantedBy=timers.target
+", on_calendar);
+
+    write_unit(&unit_dir.join(&service_file_name), &service);
+    write_unit(&unit_dir.join(&timer_file_name), &timer);
+
+    let systemctl = if user { "systemctl --user" } else { "systemctl" };
+    println!("Installed {} and {} into {}", service_file_name, timer_file_name, unit_dir.display());
+    println!("Enable them with: {} daemon-reload && {} enable --now {}", systemctl, systemctl, timer_file_name);
+}
+
+pub fn uninstall(profile: Option<&str>, user: bool, unit_dir: Option<PathBuf>) {
+    let (service_file_name, timer_file_name) = get_unit_file_names(profile);
+    let unit_dir = get_unit_dir(user, unit_dir);
+
+    for unit_name in &[&timer_file_name, &service_file_name] {
+        let unit_path = unit_dir.join(unit_name);
+        if !unit_path.exists() {
+            continue;
+        }
+
+        let remove_res = fs::remove_file(&unit_path);
+        if remove_res.is_err() {
+            eprintln!("Unable to remove unit file: {}", unit_path.display());
+            std::process::exit(1);
+        }
+        println!("Removed {}", unit_path.display());
+    }
+
+    let systemctl = if user { "systemctl --user" } else { "systemctl" };
+    println!("Stop a running timer with: {} disable --now {} && {} daemon-reload", systemctl, timer_file_name, systemctl);
+}
+
+fn get_unit_file_names(profile: Option<&str>) -> (String, String) {
+    let unit_name = match profile {
+        Some(profile) => format!("{}-{}", UNIT_NAME, profile),
+        None => UNIT_NAME.to_string(),
+    };
+    (format!("{}.service", unit_name), format!("{}.timer", unit_name))
+}
+
+fn get_unit_dir(user: bool, unit_dir: Option<PathBuf>) -> PathBuf {
+    if let Some(unit_dir) = unit_dir {
+        return unit_dir;
+    }
+
+    if !user {
+        return PathBuf::from(SYSTEM_UNIT_DIR);
+    }
+
+    match dirs::config_dir() {
+        Some(config_dir) => config_dir.join(USER_UNIT_PATH),
+        None => {
+            eprintln!("Unable to find user config directory");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Quotes an argument for an `ExecStart=` line, where `%` and `$` are expanded by systemd
+fn quote_arg(arg: &OsStr) -> String {
+    let mut quoted = String::from("\"");
+    for c in arg.to_string_lossy().chars() {
+        match c {
+            '\\' | '"' => quoted.push('\\'),
+            '%' | '$' => quoted.push(c),
+            _ => {}
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn write_unit(path: &Path, contents: &str) {
+    let write_res = fs::write(path, contents);
+    if write_res.is_err() {
+        eprintln!("Unable to write unit file: {}", path.display());
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn read_line(path: &Path, prefix: &str) -> String {
+        let contents = fs::read_to_string(path).unwrap();
+        contents.lines().find(|line| line.starts_with(prefix)).unwrap().to_string()
+    }
+
+    #[test]
+    fn install_forwards_overrides_and_schedule() {
+        let dir = TempDir::new("systemd").unwrap();
+        let repo_dir = dir.path().join("repo");
+        fs::create_dir(&repo_dir).unwrap();
+        let conf = Config::from_toml(
+            &format!("backup_dir = '{}'\nrepo_dir = '{}'", dir.path().display(), repo_dir.display()),
+            &["hooks.pre_create=\"echo 100% $HOME\"".to_string()],
+        );
+        let unit_dir = dir.path().join("units");
+
+        install(&conf, true, Some(unit_dir.clone()), "hourly");
+
+        let exec_start = read_line(&unit_dir.join("resilient.service"), "ExecStart=");
+        assert!(exec_start.contains(r#" "--set" "hooks.pre_create=\"echo 100%% $$HOME\"" "#), "{}", exec_start);
+        assert!(exec_start.contains(&format!(r#" "--repo" "{}""#, repo_dir.canonicalize().unwrap().display())), "{}", exec_start);
+        assert!(exec_start.ends_with(r#" create -m "Scheduled backup""#), "{}", exec_start);
+        assert_eq!(read_line(&unit_dir.join("resilient.timer"), "OnCalendar="), "OnCalendar=hourly");
+
+        uninstall(None, true, Some(unit_dir.clone()));
+        assert_eq!(fs::read_dir(&unit_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn profiles_get_their_own_units() {
+        assert_eq!(get_unit_file_names(None), ("resilient.service".to_string(), "resilient.timer".to_string()));
+        assert_eq!(
+            get_unit_file_names(Some("photos")),
+            ("resilient-photos.service".to_string(), "resilient-photos.timer".to_string()),
+        );
+    }
+}
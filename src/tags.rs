@@ -0,0 +1,88 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::backend;
+use super::lock;
+
+/// Like every change below, resolves `commit` only once the repository lock is held, so a
+/// delete can't remove it in between
+pub fn tag(conf: &Config, commit: &str, label: &str) {
+    let _lock = lock::acquire(conf);
+    let commit = backend::resolve_commit(conf, Some(commit.to_string()));
+    if !is_valid_tag(label) {
+        eprintln!("Invalid tag '{}': tags can't be 'latest' or contain whitespace, '/' or ':'", label);
+        std::process::exit(1);
+    }
+
+    if Commit::find(conf, label).is_some() {
+        eprintln!("Tag '{}' would shadow the commit with the same name", label);
+        std::process::exit(1);
+    }
+
+    if let Some(tagged) = Commit::find_by_tag(conf, label) {
+        if tagged.get_name() == commit.get_name() {
+            return;
+        }
+        eprintln!("Tag '{}' already points at commit: {}", label, tagged.get_name());
+        std::process::exit(1);
+    }
+
+    let mut tags = commit.get_tags();
+    tags.push(label.to_string());
+    or_exit(commit.write_tags_file(&tags));
+}
+
+pub fn untag(conf: &Config, label: &str) {
+    let _lock = lock::acquire(conf);
+    let commit = Commit::find_by_tag(conf, label);
+    if commit.is_none() {
+        eprintln!("Cannot find tag: {}", label);
+        std::process::exit(1);
+    }
+
+    let commit = commit.unwrap();
+    let tags: Vec<String> = commit.get_tags().into_iter().filter(|tag| tag != label).collect();
+    or_exit(commit.write_tags_file(&tags));
+}
+
+pub fn set_pinned(conf: &Config, commit: &str, pinned: bool) {
+    let _lock = lock::acquire(conf);
+    let commit = backend::resolve_commit(conf, Some(commit.to_string()));
+    or_exit(commit.set_pinned(pinned));
+}
+
+pub fn amend_message(conf: &Config, commit: &str, message: String) {
+    let _lock = lock::acquire(conf);
+    let mut commit = backend::resolve_commit(conf, Some(commit.to_string()));
+    or_exit(commit.set_message(message));
+}
+
+/// Tags name commits wherever a commit name is accepted, including in [commit:]path arguments
+fn is_valid_tag(label: &str) -> bool {
+    !label.is_empty() && label != "latest" && !label.contains(|c: char| c.is_whitespace() || c == '/' || c == ':')
+}
+
+fn or_exit(result: Result<(), String>) {
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_tags() {
+        for label in ["release", "v1.2.3", "before-upgrade", "2026_10_19", "ünïcode", "latest-good"] {
+            assert!(is_valid_tag(label), "'{}' should be accepted", label);
+        }
+    }
+
+    #[test]
+    fn invalid_tags() {
+        for label in ["", "latest", "two words", "tab\there", "line\nbreak", "a/b", "/", "commit:path", "trailing "] {
+            assert!(!is_valid_tag(label), "'{}' should be rejected", label);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use super::config::Config;
+use chrono::{Local, NaiveTime};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const DEFAULT_IO_LEVEL: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum IoClass {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoClass {
+    /// Parses `idle`, `best-effort` or `realtime`, the latter two optionally followed by a
+    /// `:<level>` from 0 (highest priority) to 7
+    pub fn parse(value: &str) -> Option<IoClass> {
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => (name, Some(level.parse::<u8>().ok().filter(|level| *level <= 7)?)),
+            None => (value, None),
+        };
+        match name {
+            "idle" if level.is_none() => Some(IoClass::Idle),
+            "best-effort" => Some(IoClass::BestEffort(level.unwrap_or(DEFAULT_IO_LEVEL))),
+            "realtime" => Some(IoClass::Realtime(level.unwrap_or(DEFAULT_IO_LEVEL))),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        match self {
+            IoClass::Realtime(level) => format!("realtime:{}", level),
+            IoClass::BestEffort(level) => format!("best-effort:{}", level),
+            IoClass::Idle => "idle".to_string(),
+        }
+    }
+
+    fn get_ioprio(&self) -> libc::c_int {
+        let (class, level) = match self {
+            IoClass::Realtime(level) => (1, *level),
+            IoClass::BestEffort(level) => (2, *level),
+            IoClass::Idle => (3, 0),
+        };
+        (class << IOPRIO_CLASS_SHIFT) | level as libc::c_int
+    }
+}
+
+/// Limits for a sync, anything left at `None` is not limited
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Bytes per second, 0 explicitly lifting a limit set elsewhere
+    pub bwlimit: Option<u64>,
+    pub ionice: Option<IoClass>,
+    pub nice: Option<i32>,
+}
+
+impl Limits {
+    /// Takes every limit this doesn't set from `fallback`
+    pub fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            bwlimit: self.bwlimit.or(fallback.bwlimit),
+            ionice: self.ionice.or(fallback.ionice),
+            nice: self.nice.or(fallback.nice),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.get_bwlimit().is_none() && self.ionice.is_none() && self.nice.is_none()
+    }
+
+    pub fn get_bwlimit(&self) -> Option<u64> {
+        self.bwlimit.filter(|bwlimit| *bwlimit > 0)
+    }
+
+    /// The command line options that set these limits, for passing them on to a child process
+    pub fn get_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(bwlimit) = self.bwlimit {
+            args.push("--bwlimit".to_string());
+            args.push((bwlimit / 1024).to_string());
+        }
+        if let Some(ionice) = self.ionice {
+            args.push("--ionice".to_string());
+            args.push(ionice.get_name());
+        }
+        if let Some(nice) = self.nice {
+            args.push(format!("--nice={}", nice));
+        }
+        args
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(bwlimit) = self.get_bwlimit() {
+            parts.push(format!("bwlimit {} KiB/s", bwlimit / 1024));
+        }
+        if let Some(ionice) = self.ionice {
+            parts.push(format!("ionice {}", ionice.get_name()));
+        }
+        if let Some(nice) = self.nice {
+            parts.push(format!("nice {}", nice));
+        }
+        parts.join(", ")
+    }
+}
+
+/// A time of day range with its own limits, ranges ending before they start run past midnight
+#[derive(Clone)]
+pub struct ThrottleWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub limits: Limits,
+}
+
+impl ThrottleWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ThrottleConfig {
+    pub limits: Limits,
+    pub windows: Vec<ThrottleWindow>,
+    /// Limits given on the command line, which beat everything in the config
+    pub forced: Limits,
+}
+
+impl ThrottleConfig {
+    /// Command line limits come first, then the first window (by name) containing `time`,
+    /// then the [throttle] section itself
+    pub fn get_limits_at(&self, time: NaiveTime) -> Limits {
+        let window = self.windows.iter()
+            .find(|window| window.contains(time))
+            .map(|window| window.limits)
+            .unwrap_or_default();
+        self.forced.or(window).or(self.limits)
+    }
+}
+
+/// Limits for a sync starting now. They stay in place until the sync is done, even when it
+/// runs into a different window.
+pub fn get_current_limits(conf: &Config) -> Limits {
+    conf.get_throttle().get_limits_at(Local::now().time())
+}
+
+/// Parses a rate in KiB per second, or with a K, M or G suffix
+pub fn parse_bwlimit(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1024),
+        'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1024),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Limits from the command line options, exiting on invalid ones
+pub fn parse_cli_limits(bwlimit: Option<&str>, ionice: Option<&str>, nice: Option<i32>) -> Limits {
+    let bwlimit = bwlimit.map(|bwlimit| parse_bwlimit(bwlimit).unwrap_or_else(|| {
+        eprintln!("Invalid --bwlimit '{}', expected KiB per second or a number followed by K, M or G", bwlimit);
+        std::process::exit(1);
+    }));
+    let ionice = ionice.map(|ionice| IoClass::parse(ionice).unwrap_or_else(|| {
+        eprintln!("Invalid --ionice '{}', expected 'idle', 'best-effort' or 'realtime', optionally followed by :0 to :7", ionice);
+        std::process::exit(1);
+    }));
+    if let Some(nice) = nice {
+        if !(-20..=19).contains(&nice) {
+            eprintln!("Niceness must be between -20 and 19, got {}", nice);
+            std::process::exit(1);
+        }
+    }
+
+    Limits {
+        bwlimit,
+        ionice,
+        nice,
+    }
+}
+
+/// Passes the bandwidth limit to rsync and lowers the priorities of the rsync process
+pub fn throttle_rsync(limits: Limits, command: &mut Command) {
+    if let Some(bwlimit) = limits.get_bwlimit() {
+        command.arg(format!("--bwlimit={}", (bwlimit / 1024).max(1)));
+    }
+    if limits.ionice.is_some() || limits.nice.is_some() {
+        // Only async-signal-safe calls are allowed between fork and exec, which both syscalls are
+        unsafe {
+            command.pre_exec(move || set_priorities(limits));
+        }
+    }
+}
+
+/// Priorities the calling thread had before throttle_current_thread, put back when dropped
+pub struct ThreadPriorities {
+    nice: Option<libc::c_int>,
+    ioprio: Option<libc::c_int>,
+}
+
+impl Drop for ThreadPriorities {
+    fn drop(&mut self) {
+        // Unprivileged threads can't lower their niceness again, they stay at the throttled value
+        if let Some(nice) = self.nice {
+            unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+        }
+        if let Some(ioprio) = self.ioprio {
+            unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+        }
+    }
+}
+
+/// Changes the priorities of the calling thread until the returned value is dropped, for syncs
+/// that run in process. A niceness below the current one that the thread isn't allowed to
+/// go back to, e.g. after an earlier sync with a higher one, leaves it where it is.
+pub fn throttle_current_thread(limits: Limits) -> Result<ThreadPriorities, String> {
+    let describe_err = |err: io::Error| format!("Unable to apply '{}': {}", limits.describe(), err);
+    let mut previous = ThreadPriorities {
+        nice: None,
+        ioprio: None,
+    };
+
+    if let Some(nice) = limits.nice {
+        let current = get_nice().map_err(describe_err)?;
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == 0 {
+            previous.nice = Some(current);
+        } else {
+            let err = io::Error::last_os_error();
+            if nice >= current || err.raw_os_error() != Some(libc::EACCES) {
+                return Err(describe_err(err));
+            }
+        }
+    }
+
+    if let Some(ionice) = limits.ionice {
+        let current = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+        if current < 0 {
+            return Err(describe_err(io::Error::last_os_error()));
+        }
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ionice.get_ioprio()) } != 0 {
+            return Err(describe_err(io::Error::last_os_error()));
+        }
+        previous.ioprio = Some(current as libc::c_int);
+    }
+    Ok(previous)
+}
+
+fn get_nice() -> io::Result<libc::c_int> {
+    // -1 is a valid niceness, only errno tells it apart from a failure
+    unsafe { *libc::__errno_location() = 0 };
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+    if nice == -1 && io::Error::last_os_error().raw_os_error() != Some(0) {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(nice)
+}
+
+fn set_priorities(limits: Limits) -> io::Result<()> {
+    if let Some(nice) = limits.nice {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(ionice) = limits.ionice {
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ionice.get_ioprio()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Keeps a stream of bytes under a rate by sleeping whenever it gets ahead
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    pub fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_second as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn window(start: NaiveTime, end: NaiveTime, limits: Limits) -> ThrottleWindow {
+        ThrottleWindow { start, end, limits }
+    }
+
+    fn bwlimit(kib: u64) -> Limits {
+        Limits { bwlimit: Some(kib * 1024), ..Limits::default() }
+    }
+
+    #[test]
+    fn bandwidth_limits() {
+        assert_eq!(parse_bwlimit("500"), Some(500 * 1024));
+        assert_eq!(parse_bwlimit("500K"), Some(500 * 1024));
+        assert_eq!(parse_bwlimit("2m"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_bwlimit(" 1G "), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_bwlimit("0"), Some(0));
+    }
+
+    #[test]
+    fn invalid_bandwidth_limits() {
+        for value in ["", " ", "K", "1.5M", "-1", "10T", "M10", "1 M", "18446744073709551615G"] {
+            assert_eq!(parse_bwlimit(value), None, "'{}' should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn io_classes() {
+        assert!(IoClass::parse("idle") == Some(IoClass::Idle));
+        assert!(IoClass::parse("best-effort") == Some(IoClass::BestEffort(DEFAULT_IO_LEVEL)));
+        assert!(IoClass::parse("best-effort:7") == Some(IoClass::BestEffort(7)));
+        assert!(IoClass::parse("realtime:0") == Some(IoClass::Realtime(0)));
+        for value in ["idle:3", "best-effort:8", "realtime:", "realtime:-1", "Idle", "low", ""] {
+            assert!(IoClass::parse(value).is_none(), "'{}' should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn io_class_names_parse_back() {
+        for class in [IoClass::Idle, IoClass::BestEffort(2), IoClass::Realtime(5)] {
+            assert!(IoClass::parse(&class.get_name()) == Some(class));
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let window = window(time(9, 0), time(17, 30), Limits::default());
+        assert!(!window.contains(time(8, 59)));
+        assert!(window.contains(time(9, 0)));
+        assert!(window.contains(time(17, 29)));
+        assert!(!window.contains(time(17, 30)));
+    }
+
+    #[test]
+    fn window_past_midnight() {
+        let window = window(time(22, 0), time(6, 0), Limits::default());
+        assert!(!window.contains(time(21, 59)));
+        assert!(window.contains(time(22, 0)));
+        assert!(window.contains(time(23, 59)));
+        assert!(window.contains(time(0, 0)));
+        assert!(window.contains(time(5, 59)));
+        assert!(!window.contains(time(6, 0)));
+        assert!(!window.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn limits_by_time_of_day() {
+        let throttle = ThrottleConfig {
+            limits: Limits { bwlimit: Some(100 * 1024), nice: Some(10), ..Limits::default() },
+            windows: vec![
+                window(time(8, 0), time(18, 0), bwlimit(50)),
+                window(time(17, 0), time(1, 0), bwlimit(0)),
+            ],
+            forced: Limits::default(),
+        };
+
+        // Outside every window only the section itself applies
+        assert!(throttle.get_limits_at(time(3, 0)) == throttle.limits);
+        // A window keeps the section's limits it doesn't set
+        assert!(throttle.get_limits_at(time(9, 0)) == Limits { bwlimit: Some(50 * 1024), nice: Some(10), ..Limits::default() });
+        // The first window wins where they overlap
+        assert_eq!(throttle.get_limits_at(time(17, 30)).bwlimit, Some(50 * 1024));
+        // A zero rate lifts the section's limit
+        let evening = throttle.get_limits_at(time(23, 0));
+        assert_eq!(evening.bwlimit, Some(0));
+        assert_eq!(evening.get_bwlimit(), None);
+    }
+
+    #[test]
+    fn command_line_limits_win() {
+        let throttle = ThrottleConfig {
+            limits: bwlimit(100),
+            windows: vec![window(time(0, 0), time(23, 59), bwlimit(50))],
+            forced: Limits { bwlimit: Some(0), ionice: Some(IoClass::Idle), nice: None },
+        };
+        let limits = throttle.get_limits_at(time(12, 0));
+        assert_eq!(limits.get_bwlimit(), None);
+        assert!(limits.ionice == Some(IoClass::Idle));
+    }
+
+    #[test]
+    fn thread_limits_apply_one_after_another() {
+        // On a thread of its own, so other tests don't run with these priorities
+        thread::spawn(|| {
+            let original = get_nice().unwrap();
+            let higher = Limits { nice: Some(original + 5), ionice: Some(IoClass::Idle), ..Limits::default() };
+            let lower = Limits { nice: Some(original + 2), ionice: Some(IoClass::BestEffort(7)), ..Limits::default() };
+
+            let priorities = throttle_current_thread(higher).unwrap();
+            assert_eq!(get_nice().unwrap(), original + 5);
+            drop(priorities);
+
+            // Going back down may not be allowed, which must not fail the second sync
+            let priorities = throttle_current_thread(lower).unwrap();
+            let nice = get_nice().unwrap();
+            assert!(nice == original + 2 || nice == original + 5, "unexpected niceness {}", nice);
+            drop(priorities);
+            assert!(get_nice().unwrap() <= nice);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn limits_as_child_arguments() {
+        let limits = Limits { bwlimit: Some(2048 * 1024), ionice: Some(IoClass::BestEffort(6)), nice: Some(-5) };
+        assert_eq!(limits.get_args(), vec!["--bwlimit", "2048", "--ionice", "best-effort:6", "--nice=-5"]);
+        assert!(Limits::default().get_args().is_empty());
+        assert!(bwlimit(0).is_empty());
+    }
+}
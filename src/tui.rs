@@ -0,0 +1,467 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::backend;
+use super::chunks;
+use super::rsync::DATA_FOLDER_NAME;
+use chrono::{Local, TimeZone};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use tempdir::TempDir;
+
+const COMMITS_HELP: &str = "↑↓ select  enter browse files  q quit";
+const FILES_HELP: &str = "↑↓ select  enter open  ← up  v view  d diff  r restore  R restore to...  tab commits  q quit";
+
+#[derive(PartialEq)]
+enum Focus {
+    Commits,
+    Files,
+}
+
+enum Prompt {
+    None,
+    ConfirmRestore,
+    RestoreTo(String),
+}
+
+struct FileEntry {
+    name: String,
+    is_dir: bool,
+    changed: bool,
+}
+
+/// Files of the commit being browsed. Chunked commits are laid out in a temporary
+/// directory so both formats can be browsed, viewed and diffed the same way.
+struct LoadedCommit {
+    index: usize,
+    data_dir: PathBuf,
+    _temp_dir: Option<TempDir>,
+    changed: HashSet<String>,
+}
+
+struct App<'a> {
+    conf: &'a Config,
+    commits: Vec<Commit>,
+    commit_state: ListState,
+    loaded: Option<LoadedCommit>,
+    dir: String,
+    entries: Vec<FileEntry>,
+    file_state: ListState,
+    focus: Focus,
+    prompt: Prompt,
+    status: String,
+    quit: bool,
+}
+
+pub fn run(conf: &Config) {
+    let mut commits = Commit::get_commits(conf);
+    if commits.is_empty() {
+        eprintln!("No commits to browse");
+        std::process::exit(1);
+    }
+    commits.reverse();
+
+    let terminal = ratatui::try_init();
+    if terminal.is_err() {
+        eprintln!("Unable to start the terminal UI");
+        std::process::exit(1);
+    }
+    let mut terminal = terminal.unwrap();
+
+    let mut app = App {
+        conf,
+        commits,
+        commit_state: ListState::default().with_selected(Some(0)),
+        loaded: None,
+        dir: String::new(),
+        entries: Vec::new(),
+        file_state: ListState::default(),
+        focus: Focus::Commits,
+        prompt: Prompt::None,
+        status: String::new(),
+        quit: false,
+    };
+
+    while !app.quit {
+        if terminal.draw(|frame| app.draw(frame)).is_err() {
+            break;
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => app.handle_key(key, &mut terminal),
+            Ok(_) => {},
+            Err(_) => break,
+        }
+    }
+
+    ratatui::restore();
+}
+
+impl<'a> App<'a> {
+    fn selected_commit(&self) -> &Commit {
+        &self.commits[self.commit_state.selected().unwrap_or(0)]
+    }
+
+    fn selected_entry(&self) -> Option<&FileEntry> {
+        self.file_state.selected().and_then(|index| self.entries.get(index))
+    }
+
+    /// Path of the selected entry relative to the root of the commit
+    fn selected_path(&self) -> Option<String> {
+        self.selected_entry().map(|entry| join_path(&self.dir, &entry.name))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(frame.area());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(35), Constraint::Percentage(65)])
+            .split(rows[0]);
+
+        self.draw_commits(frame, columns[0]);
+        let is_loaded = self.loaded.as_ref().map(|loaded| Some(loaded.index) == self.commit_state.selected());
+        if is_loaded.unwrap_or(false) {
+            self.draw_files(frame, columns[1]);
+        } else {
+            self.draw_details(frame, columns[1]);
+        }
+
+        let status_line = match &self.prompt {
+            Prompt::ConfirmRestore => format!("Restore '{}' over the live copy? (y/n)", self.selected_path().unwrap_or_default()),
+            Prompt::RestoreTo(input) => format!("Restore to directory: {}", input),
+            Prompt::None if !self.status.is_empty() => self.status.clone(),
+            Prompt::None if self.focus == Focus::Files => FILES_HELP.to_string(),
+            Prompt::None => COMMITS_HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(status_line), rows[1]);
+    }
+
+    fn draw_commits(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self.commits.iter().map(|commit| {
+            let tags = commit.get_tags();
+            let mut label = commit.get_name();
+            if !tags.is_empty() {
+                label += &format!(" ({})", tags.join(", "));
+            }
+            label += &format!("  {}", commit.get_message().lines().next().unwrap_or(""));
+            ListItem::new(label)
+        }).collect();
+
+        let list = List::new(items)
+            .block(pane_block("Commits", self.focus == Focus::Commits))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.commit_state);
+    }
+
+    fn draw_details(&self, frame: &mut Frame, area: Rect) {
+        let commit = self.selected_commit();
+        let date = Local.timestamp_opt(commit.get_timestamp(), 0).unwrap();
+        let mut lines = vec![
+            Line::from(format!("Date: {}", date.format("%Y-%m-%d %H:%M:%S"))),
+            Line::from(format!("Format: {}", if commit.is_chunked() { "chunked" } else { "rsync" })),
+        ];
+        let tags = commit.get_tags();
+        if !tags.is_empty() {
+            lines.push(Line::from(format!("Tags: {}", tags.join(", "))));
+        }
+        if commit.is_pinned() {
+            lines.push(Line::from("Pinned: yes"));
+        }
+        if let Some(stats) = commit.get_stats() {
+            lines.push(Line::from(format!("Files: {} ({} bytes)", stats.files_total, stats.bytes_total)));
+            lines.push(Line::from(format!("Changed: {} ({} bytes)", stats.files_changed, stats.bytes_changed)));
+        }
+        lines.push(Line::from(""));
+        for message_line in commit.get_message().lines() {
+            lines.push(Line::from(format!("    {}", message_line)));
+        }
+        lines.push(Line::from(""));
+        lines.push(Line::from("Press enter to browse the files of this commit"));
+
+        let details = Paragraph::new(lines).block(pane_block(&commit.get_name(), false));
+        frame.render_widget(details, area);
+    }
+
+    fn draw_files(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self.entries.iter().map(|entry| {
+            let label = if entry.is_dir { format!("{}/", entry.name) } else { entry.name.clone() };
+            let style = if entry.changed {
+                Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            ListItem::new(label).style(style)
+        }).collect();
+
+        let title = format!("{}:/{}", self.selected_commit().get_name(), self.dir);
+        let list = List::new(items)
+            .block(pane_block(&title, self.focus == Focus::Files))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.file_state);
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, terminal: &mut DefaultTerminal) {
+        self.status.clear();
+        match &mut self.prompt {
+            Prompt::ConfirmRestore => {
+                self.prompt = Prompt::None;
+                if key.code == KeyCode::Char('y') {
+                    let backup_dir = self.conf.get_backup_location().to_path_buf();
+                    self.restore_selection(backup_dir, terminal);
+                }
+                return;
+            },
+            Prompt::RestoreTo(input) => {
+                match key.code {
+                    KeyCode::Char(c) => input.push(c),
+                    KeyCode::Backspace => {
+                        input.pop();
+                    },
+                    KeyCode::Enter => {
+                        let target = PathBuf::from(input.trim());
+                        self.prompt = Prompt::None;
+                        if !target.as_os_str().is_empty() {
+                            self.restore_selection(target, terminal);
+                        }
+                    },
+                    KeyCode::Esc => self.prompt = Prompt::None,
+                    _ => {},
+                }
+                return;
+            },
+            Prompt::None => {},
+        }
+
+        match (&self.focus, key.code) {
+            (_, KeyCode::Char('q')) => self.quit = true,
+            (Focus::Commits, KeyCode::Esc) => self.quit = true,
+            (Focus::Commits, KeyCode::Up) | (Focus::Commits, KeyCode::Char('k')) => self.commit_state.select_previous(),
+            (Focus::Commits, KeyCode::Down) | (Focus::Commits, KeyCode::Char('j')) => {
+                let next = (self.commit_state.selected().unwrap_or(0) + 1).min(self.commits.len() - 1);
+                self.commit_state.select(Some(next));
+            },
+            (Focus::Commits, KeyCode::Enter) | (Focus::Commits, KeyCode::Right) | (Focus::Commits, KeyCode::Tab) => {
+                self.load_selected_commit(terminal);
+                self.focus = Focus::Files;
+            },
+            (Focus::Files, KeyCode::Tab) | (Focus::Files, KeyCode::Esc) => self.focus = Focus::Commits,
+            (Focus::Files, KeyCode::Up) | (Focus::Files, KeyCode::Char('k')) => self.file_state.select_previous(),
+            (Focus::Files, KeyCode::Down) | (Focus::Files, KeyCode::Char('j')) => self.file_state.select_next(),
+            (Focus::Files, KeyCode::Enter) | (Focus::Files, KeyCode::Right) => {
+                match self.selected_entry() {
+                    Some(entry) if entry.is_dir => {
+                        self.dir = join_path(&self.dir, &entry.name);
+                        self.read_dir(None);
+                    },
+                    Some(_) => self.view_selection(terminal),
+                    None => {},
+                }
+            },
+            (Focus::Files, KeyCode::Left) | (Focus::Files, KeyCode::Backspace) => {
+                if self.dir.is_empty() {
+                    self.focus = Focus::Commits;
+                } else {
+                    let child = self.dir.rsplit('/').next().unwrap_or("").to_string();
+                    self.dir = match self.dir.rfind('/') {
+                        Some(index) => self.dir[..index].to_string(),
+                        None => String::new(),
+                    };
+                    self.read_dir(Some(&child));
+                }
+            },
+            (Focus::Files, KeyCode::Char('v')) => self.view_selection(terminal),
+            (Focus::Files, KeyCode::Char('d')) => self.diff_selection(terminal),
+            (Focus::Files, KeyCode::Char('r')) if self.selected_entry().is_some() => self.prompt = Prompt::ConfirmRestore,
+            (Focus::Files, KeyCode::Char('R')) if self.selected_entry().is_some() => self.prompt = Prompt::RestoreTo(String::new()),
+            _ => {},
+        }
+    }
+
+    fn load_selected_commit(&mut self, terminal: &mut DefaultTerminal) {
+        let index = self.commit_state.selected().unwrap_or(0);
+        if self.loaded.as_ref().map(|loaded| loaded.index) == Some(index) {
+            return;
+        }
+
+        // Loading can take a while and exits on errors, which must be readable afterwards
+        let conf = self.conf;
+        let commit = &self.commits[index];
+        let loaded = suspended(terminal, || {
+            println!("Loading commit {}...", commit.get_name());
+            let (data_dir, temp_dir) = if commit.is_chunked() {
+                let temp_dir = TempDir::new("resilient-tui");
+                if temp_dir.is_err() {
+                    eprintln!("Unable to allocate temporary directory");
+                    std::process::exit(1);
+                }
+                let temp_dir = temp_dir.unwrap();
+                if let Err(message) = chunks::restore_to(conf, commit, String::new(), temp_dir.path(), false) {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+                (temp_dir.path().to_path_buf(), Some(temp_dir))
+            } else {
+                (commit.get_folder().join(DATA_FOLDER_NAME), None)
+            };
+
+            let mut changed = HashSet::new();
+            let changes = backend::get_commit_changes_in(conf, commit, &data_dir);
+            if let Err(message) = &changes {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+            for change in changes.unwrap() {
+                // A changed file also marks every directory above it
+                let mut path = change.get_file_name().trim_end_matches('/');
+                loop {
+                    changed.insert(path.to_string());
+                    match path.rfind('/') {
+                        Some(index) => path = &path[..index],
+                        None => break,
+                    }
+                }
+            }
+
+            LoadedCommit {
+                index,
+                data_dir,
+                _temp_dir: temp_dir,
+                changed,
+            }
+        });
+
+        self.loaded = Some(loaded);
+        self.dir = String::new();
+        self.read_dir(None);
+    }
+
+    /// Lists the current directory, directories first, selecting `select` if it's present
+    fn read_dir(&mut self, select: Option<&str>) {
+        self.entries.clear();
+        let loaded = match &self.loaded {
+            Some(loaded) => loaded,
+            None => return,
+        };
+
+        if let Ok(dir_entries) = loaded.data_dir.join(&self.dir).read_dir() {
+            for dir_entry in dir_entries.flatten() {
+                let name = dir_entry.file_name().to_string_lossy().to_string();
+                let is_dir = fs::symlink_metadata(dir_entry.path()).map(|metadata| metadata.is_dir()).unwrap_or(false);
+                let changed = loaded.changed.contains(&join_path(&self.dir, &name));
+                self.entries.push(FileEntry { name, is_dir, changed });
+            }
+        }
+        self.entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        let selected = select.and_then(|name| self.entries.iter().position(|entry| entry.name == name));
+        self.file_state.select(if self.entries.is_empty() { None } else { Some(selected.unwrap_or(0)) });
+    }
+
+    fn view_selection(&mut self, terminal: &mut DefaultTerminal) {
+        let (path, is_dir) = match self.selected_entry() {
+            Some(entry) => (self.selected_path().unwrap(), entry.is_dir),
+            None => return,
+        };
+        if is_dir {
+            self.status = "Select a file to view it".to_string();
+            return;
+        }
+
+        let file = self.loaded.as_ref().unwrap().data_dir.join(&path);
+        let res = suspended(terminal, || {
+            Command::new("sh").arg("-c").arg("${PAGER:-less} \"$1\"").arg("sh").arg(&file).status()
+        });
+        if res.is_err() {
+            self.status = "Unable to spawn pager".to_string();
+        }
+    }
+
+    fn diff_selection(&mut self, terminal: &mut DefaultTerminal) {
+        let path = match self.selected_path() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let live_file = self.conf.get_backup_location().join(&path);
+        if fs::symlink_metadata(&live_file).is_err() {
+            self.status = format!("No live copy of '{}' to compare with", path);
+            return;
+        }
+
+        let file = self.loaded.as_ref().unwrap().data_dir.join(&path);
+        let res = suspended(terminal, || {
+            Command::new("sh").arg("-c").arg("diff -ru \"$1\" \"$2\" | ${PAGER:-less}")
+                .arg("sh").arg(&file).arg(&live_file)
+                .status()
+        });
+        if res.is_err() {
+            self.status = "Unable to spawn diff".to_string();
+        }
+    }
+
+    /// Restores the selection into `backup_dir`, keeping its path relative to the backup root
+    fn restore_selection(&mut self, backup_dir: PathBuf, terminal: &mut DefaultTerminal) {
+        let (mut path, is_dir) = match self.selected_entry() {
+            Some(entry) => (self.selected_path().unwrap(), entry.is_dir),
+            None => return,
+        };
+
+        let target = backup_dir.join(&path);
+        if let Some(parent) = target.parent() {
+            if fs::create_dir_all(parent).is_err() {
+                self.status = format!("Unable to create directory: {}", parent.display());
+                return;
+            }
+        }
+
+        // Without the trailing slash rsync would nest the directory inside its live copy
+        if is_dir {
+            path += "/";
+        }
+
+        let conf = self.conf.with_backup_dir(backup_dir);
+        let commit = self.selected_commit();
+        suspended(terminal, || {
+            println!("Restoring '{}' to {}...", path, target.display());
+            backend::restore(&conf, commit, path.clone(), false);
+        });
+        self.status = format!("Restored {}", target.display());
+    }
+}
+
+/// Leaves the terminal UI while `f` runs so its output and any pager it starts use the normal
+/// screen, then redraws
+fn suspended<T>(terminal: &mut DefaultTerminal, f: impl FnOnce() -> T) -> T {
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen);
+    let result = f();
+    let _ = execute!(io::stdout(), EnterAlternateScreen);
+    let _ = terminal::enable_raw_mode();
+    let _ = terminal.clear();
+    result
+}
+
+fn pane_block(title: &str, focused: bool) -> Block<'static> {
+    let border_style = if focused { Style::default().fg(Color::Cyan) } else { Style::default() };
+    Block::default().borders(Borders::ALL).border_style(border_style).title(format!(" {} ", title))
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
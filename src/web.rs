@@ -0,0 +1,469 @@
+use super::config::Config;
+use super::commit::Commit;
+use super::chunks::{self, EntryKind, Store};
+use super::rsync::DATA_FOLDER_NAME;
+use super::api;
+use super::jobs::Jobs;
+use base64::Engine;
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use tempdir::TempDir;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const MAX_FORM_SIZE: u64 = 64 * 1024;
+const DEFAULT_WEB_MESSAGE: &str = "Created from the web interface";
+const STYLE: &str = "body{font-family:sans-serif;margin:2em auto;max-width:60em;padding:0 1em}\
+    table{border-collapse:collapse;width:100%}td,th{text-align:left;padding:.3em .6em;border-bottom:1px solid #ddd}\
+    .tags{color:#777}form{margin:1.5em 0}";
+
+struct Listing {
+    name: String,
+    is_dir: bool,
+    size: u64,
+}
+
+type HttpResult = Result<ResponseBox, (u16, String)>;
+
+pub fn serve(conf: &Config, listen: &str) {
+    let password = match conf.get_serve_password() {
+        Some(password) if !password.is_empty() => password.to_string(),
+        _ => {
+            eprintln!("Set a password in the [serve] config section before starting the web interface");
+            std::process::exit(1);
+        }
+    };
+
+    let server = Server::http(listen);
+    if let Err(err) = server {
+        eprintln!("Unable to listen on '{}': {}", listen, err);
+        std::process::exit(1);
+    }
+    let server = server.unwrap();
+
+    println!("Serving the web interface on http://{}", listen);
+    let jobs = Jobs::default();
+    let form_token = new_form_token();
+    for mut request in server.incoming_requests() {
+        let response = if !is_authorized(&request, &password) {
+            Response::from_string("Password required")
+                .with_status_code(401)
+                .with_header(header("WWW-Authenticate", "Basic realm=\"resilient\""))
+                .boxed()
+        } else if *request.method() != Method::Get && !is_same_origin(&request) {
+            // Browsers resend the password to any site posting here, so only our own pages may change things
+            Response::from_string("Cross-site request refused")
+                .with_status_code(403)
+                .boxed()
+        } else {
+            handle(conf, &jobs, &form_token, &mut request).unwrap_or_else(|(code, message)| {
+                page(&format!("Error {}", code), &format!("<p>{}</p>", escape(&message)))
+                    .with_status_code(code)
+                    .boxed()
+            })
+        };
+        let _ = request.respond(response);
+    }
+}
+
+fn handle(conf: &Config, jobs: &Jobs, form_token: &str, request: &mut Request) -> HttpResult {
+    let url = request.url().split('?').next().unwrap_or("").to_string();
+    let path = percent_decode(&url).ok_or((400, "Invalid url".to_string()))?;
+    let (route, rest) = match path.trim_start_matches('/').split_once('/') {
+        Some((route, rest)) => (route.to_string(), rest.to_string()),
+        None => (path.trim_start_matches('/').to_string(), String::new()),
+    };
+
+    match (request.method(), route.as_str()) {
+        (Method::Get, "") => index(conf, form_token),
+        (Method::Get, "browse") => {
+            let (commit, path) = split_commit_path(conf, &rest)?;
+            browse(conf, &commit, &path)
+        },
+        (Method::Get, "download") => {
+            let (commit, path) = split_commit_path(conf, &rest)?;
+            download(conf, &commit, &path)
+        },
+        (Method::Get, "zip") => {
+            let (commit, path) = split_commit_path(conf, &rest)?;
+            download_zip(conf, &commit, &path)
+        },
+        (Method::Post, "create") => create(conf, jobs, form_token, request),
+        (_, "api") => Ok(api::handle(conf, jobs, request, &rest)),
+        _ => Err((404, "Page not found".to_string())),
+    }
+}
+
+fn index(conf: &Config, form_token: &str) -> HttpResult {
+    let mut body = format!("<form method=\"post\" action=\"/create\"><input type=\"hidden\" name=\"token\" value=\"{}\">\
+        <input name=\"message\" size=\"50\" placeholder=\"Commit message\"> <button>Back up now</button></form>", form_token);
+    body += "<table><tr><th>Commit</th><th>Date</th><th>Message</th></tr>";
+    let commits = Commit::try_get_commits(conf).map_err(|message| (500, message))?;
+    for commit in commits.iter().rev() {
+        let name = commit.get_name();
+        let date = Local.timestamp_opt(commit.get_timestamp(), 0).unwrap();
+        let tags = commit.get_tags();
+        let tags = if tags.is_empty() { String::new() } else { format!(" <span class=\"tags\">({})</span>", escape(&tags.join(", "))) };
+        body += &format!(
+            "<tr><td><a href=\"/browse/{}/\">{}</a>{}</td><td>{}</td><td>{}</td></tr>",
+            percent_encode(&name), escape(&name), tags, date.format("%Y-%m-%d %H:%M:%S"),
+            escape(commit.get_message().lines().next().unwrap_or("")),
+        );
+    }
+    body += "</table>";
+    Ok(page("Commits", &body).boxed())
+}
+
+fn browse(conf: &Config, commit: &Commit, path: &str) -> HttpResult {
+    let name = commit.get_name();
+    let entries = match list_dir(conf, commit, path)? {
+        Some(entries) => entries,
+        None => return download(conf, commit, path),
+    };
+
+    let mut body = format!("<p><a href=\"/\">All commits</a> / {}", breadcrumbs(&name, path));
+    body += &format!(" &mdash; <a href=\"/zip/{}\">Download as zip</a></p>", percent_encode(&join_path(&name, path)));
+    body += "<table><tr><th>Name</th><th>Size</th></tr>";
+    if !path.is_empty() {
+        body += "<tr><td><a href=\"../\">..</a></td><td></td></tr>";
+    }
+    for entry in entries {
+        let target = percent_encode(&join_path(&name, &join_path(path, &entry.name)));
+        if entry.is_dir {
+            body += &format!("<tr><td><a href=\"/browse/{}/\">{}/</a></td><td></td></tr>", target, escape(&entry.name));
+        } else {
+            body += &format!("<tr><td><a href=\"/download/{}\">{}</a></td><td>{}</td></tr>", target, escape(&entry.name), entry.size);
+        }
+    }
+    body += "</table>";
+    Ok(page(&format!("{}:/{}", name, path), &body).boxed())
+}
+
+fn download(conf: &Config, commit: &Commit, path: &str) -> HttpResult {
+    let (dir, _temp_dir) = materialize(conf, commit, path)?;
+    let (file_path, metadata) = resolve_in_commit(&dir, commit, path)?;
+    if !metadata.is_file() {
+        return Err((404, format!("'{}' isn't a file in commit: {}", path, commit.get_name())));
+    }
+
+    let file = File::open(&file_path).map_err(|_| (500, format!("Unable to open file: {}", path)))?;
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    Ok(Response::from_file(file)
+        .with_header(header("Content-Type", "application/octet-stream"))
+        .with_header(header("Content-Disposition", &attachment(file_name)))
+        .boxed())
+}
+
+fn download_zip(conf: &Config, commit: &Commit, path: &str) -> HttpResult {
+    let (dir, _temp_dir) = materialize(conf, commit, path)?;
+    let (source, metadata) = resolve_in_commit(&dir, commit, path)?;
+    if !metadata.is_dir() {
+        return Err((404, format!("'{}' isn't a folder in commit: {}", path, commit.get_name())));
+    }
+
+    let zip_dir = TempDir::new("resilient-zip").map_err(|_| (500, "Unable to allocate temporary directory".to_string()))?;
+    let zip_path = zip_dir.path().join("download.zip");
+    let zip_res = File::create(&zip_path).map_err(|err| err.to_string()).and_then(|file| {
+        let mut zip = ZipWriter::new(file);
+        add_dir_to_zip(&mut zip, &source, "")?;
+        zip.finish().map_err(|err| err.to_string())?;
+        Ok(())
+    });
+    if let Err(message) = zip_res {
+        return Err((500, format!("Unable to build zip: {}", message)));
+    }
+
+    // The open file stays readable after the temporary directory is removed
+    let file = File::open(&zip_path).map_err(|_| (500, "Unable to open zip".to_string()))?;
+    let base_name = if path.is_empty() { commit.get_name() } else { path.rsplit('/').next().unwrap_or(path).to_string() };
+    Ok(Response::from_file(file)
+        .with_header(header("Content-Type", "application/zip"))
+        .with_header(header("Content-Disposition", &attachment(&format!("{}.zip", base_name))))
+        .boxed())
+}
+
+fn create(conf: &Config, jobs: &Jobs, form_token: &str, request: &mut Request) -> HttpResult {
+    let mut form = String::new();
+    let read_res = request.as_reader().take(MAX_FORM_SIZE).read_to_string(&mut form);
+    if read_res.is_err() {
+        return Err((400, "Unable to read form".to_string()));
+    }
+
+    if form_field(&form, "token").as_deref() != Some(form_token) {
+        return Err((403, "The form has expired, reload the page and try again".to_string()));
+    }
+
+    let message = form_field(&form, "message")
+        .map(|message| message.trim().to_string())
+        .filter(|message| !message.is_empty())
+        .unwrap_or_else(|| DEFAULT_WEB_MESSAGE.to_string());
+
+    if jobs.is_busy() {
+        return Err((409, "A backup is already running".to_string()));
+    }
+    jobs.start_create(conf, &message).map_err(|message| (500, message))?;
+
+    Ok(page("Backup started", "<p>The backup is running. It shows up on the \
+        <a href=\"/\">list of commits</a> once it's finished.</p>").boxed())
+}
+
+/// Lists a directory of the commit, or returns None if `path` isn't a directory
+fn list_dir(conf: &Config, commit: &Commit, path: &str) -> Result<Option<Vec<Listing>>, (u16, String)> {
+    let mut listing = Vec::new();
+    if commit.is_chunked() {
+        let store = Store::try_open(conf).map_err(|message| (500, message))?;
+        let entry = chunks::find_entry(&store, commit, path).map_err(|message| (404, message))?;
+        if entry.kind != EntryKind::Directory {
+            return Ok(None);
+        }
+        let tree = store.read_tree(entry.tree.as_ref().unwrap()).map_err(|message| (500, message))?;
+        for entry in tree.entries {
+            listing.push(Listing { name: entry.name, is_dir: entry.kind == EntryKind::Directory, size: entry.size });
+        }
+    } else {
+        let (dir, metadata) = resolve_in_commit(&commit.get_folder().join(DATA_FOLDER_NAME), commit, path)?;
+        if !metadata.is_dir() {
+            return Ok(None);
+        }
+        let dir_entries = dir.read_dir().map_err(|_| (500, format!("Unable to read directory: {}", path)))?;
+        for dir_entry in dir_entries.flatten() {
+            let metadata = match fs::symlink_metadata(dir_entry.path()) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            listing.push(Listing {
+                name: dir_entry.file_name().to_string_lossy().to_string(),
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+            });
+        }
+    }
+
+    listing.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(Some(listing))
+}
+
+/// Directory containing the commit's files at `path`. Chunked commits only have the requested
+/// path laid out, in a temporary directory that lives as long as the returned handle.
+fn materialize(conf: &Config, commit: &Commit, path: &str) -> Result<(PathBuf, Option<TempDir>), (u16, String)> {
+    if !commit.is_chunked() {
+        return Ok((commit.get_folder().join(DATA_FOLDER_NAME), None));
+    }
+
+    let store = Store::try_open(conf).map_err(|message| (500, message))?;
+    chunks::find_entry(&store, commit, path).map_err(|message| (404, message))?;
+    let temp_dir = TempDir::new("resilient-web").map_err(|_| (500, "Unable to allocate temporary directory".to_string()))?;
+    chunks::restore_to(conf, commit, path.to_string(), temp_dir.path(), false).map_err(|message| (500, message))?;
+    Ok((temp_dir.path().to_path_buf(), Some(temp_dir)))
+}
+
+/// Joins `path` onto the commit's files in `dir` one component at a time, refusing symlinks so
+/// links stored in a commit can't be followed out of it on the host
+fn resolve_in_commit(dir: &Path, commit: &Commit, path: &str) -> Result<(PathBuf, fs::Metadata), (u16, String)> {
+    let not_found = || (404, format!("Cannot find path '{}' in commit: {}", path, commit.get_name()));
+    let mut resolved = dir.to_path_buf();
+    let mut metadata = fs::symlink_metadata(&resolved).map_err(|_| not_found())?;
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        if !metadata.is_dir() {
+            return Err(not_found());
+        }
+        resolved.push(component);
+        metadata = fs::symlink_metadata(&resolved).map_err(|_| not_found())?;
+        if metadata.file_type().is_symlink() {
+            return Err((403, format!("'{}' is a symbolic link in commit: {}", path, commit.get_name())));
+        }
+    }
+    Ok((resolved, metadata))
+}
+
+/// Symlinks are stored as links rather than followed, like every other entry of the commit
+fn add_dir_to_zip(zip: &mut ZipWriter<File>, dir: &Path, prefix: &str) -> Result<(), String> {
+    let mut dir_entries: Vec<_> = dir.read_dir().map_err(|err| err.to_string())?.flatten().collect();
+    dir_entries.sort_by_key(|dir_entry| dir_entry.file_name());
+
+    for dir_entry in dir_entries {
+        let name = format!("{}{}", prefix, dir_entry.file_name().to_string_lossy());
+        let metadata = fs::symlink_metadata(dir_entry.path()).map_err(|err| err.to_string())?;
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(metadata.permissions().mode() & 0o7777)
+            .large_file(metadata.len() >= u32::MAX as u64)
+            .last_modified_time(zip_time(metadata.mtime()));
+
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(dir_entry.path()).map_err(|err| err.to_string())?;
+            zip.add_symlink(name, target.to_string_lossy(), options).map_err(|err| err.to_string())?;
+        } else if metadata.is_dir() {
+            zip.add_directory(format!("{}/", name), options).map_err(|err| err.to_string())?;
+            add_dir_to_zip(zip, &dir_entry.path(), &format!("{}/", name))?;
+        } else if metadata.is_file() {
+            zip.start_file(name, options).map_err(|err| err.to_string())?;
+            let mut file = File::open(dir_entry.path()).map_err(|err| err.to_string())?;
+            io::copy(&mut file, zip).map_err(|err| err.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Zip stores local time, and can't go before 1980
+fn zip_time(timestamp: i64) -> zip::DateTime {
+    let time = Local.timestamp_opt(timestamp, 0).single().unwrap_or_else(Local::now);
+    zip::DateTime::from_date_and_time(
+        time.year() as u16, time.month() as u8, time.day() as u8,
+        time.hour() as u8, time.minute() as u8, time.second() as u8,
+    ).unwrap_or_default()
+}
+
+/// Splits `<commit>/<path>` from a url, refusing names and paths that could escape the commit
+fn split_commit_path(conf: &Config, rest: &str) -> Result<(Commit, String), (u16, String)> {
+    let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let mut name_components = Path::new(name).components();
+    if !matches!((name_components.next(), name_components.next()), (Some(Component::Normal(_)), None)) {
+        return Err((400, format!("Invalid commit name: {}", name)));
+    }
+
+    let path = path.trim_matches('/');
+    let is_safe = Path::new(path).components().all(|component| matches!(component, Component::Normal(_)));
+    if !is_safe {
+        return Err((400, format!("Invalid path: {}", path)));
+    }
+
+    let commit = match Commit::try_find(conf, name).map_err(|message| (500, message))? {
+        Some(commit) => Some(commit),
+        None => Commit::try_find_by_tag(conf, name).map_err(|message| (500, message))?,
+    };
+    let commit = commit.ok_or_else(|| (404, format!("Cannot find commit: {}", name)))?;
+    Ok((commit, path.to_string()))
+}
+
+fn form_field(form: &str, name: &str) -> Option<String> {
+    form.split('&')
+        .filter_map(|field| field.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(&value.replace('+', " ")))
+}
+
+/// A random value the form has to send back, which other sites can't read from our pages
+fn new_form_token() -> String {
+    let mut bytes = [0u8; 16];
+    if File::open("/dev/urandom").and_then(|mut urandom| urandom.read_exact(&mut bytes)).is_err() {
+        eprintln!("Unable to read /dev/urandom");
+        std::process::exit(1);
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Whether the Origin or Referer of a request, when given, names the host it was sent to.
+/// Browsers send at least one of them on cross-site posts, other clients usually neither.
+fn is_same_origin(request: &Request) -> bool {
+    let get_header = |name: &'static str| request.headers().iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().to_string());
+
+    let source = match get_header("Origin").or_else(|| get_header("Referer")) {
+        Some(source) => source,
+        None => return true,
+    };
+    let source_host = source.strip_prefix("http://")
+        .or_else(|| source.strip_prefix("https://"))
+        .map(|rest| rest.split('/').next().unwrap_or(""));
+    source_host.is_some() && source_host.map(str::to_string) == get_header("Host")
+}
+
+fn is_authorized(request: &Request, password: &str) -> bool {
+    let credentials = request.headers().iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Basic ").map(|value| value.trim().to_string()))
+        .and_then(|value| base64::engine::general_purpose::STANDARD.decode(value).ok());
+
+    // Any user name is accepted, only the password matters
+    let given = match &credentials {
+        Some(credentials) => match credentials.iter().position(|byte| *byte == b':') {
+            Some(index) => &credentials[index + 1..],
+            None => return false,
+        },
+        None => return false,
+    };
+
+    let expected = password.as_bytes();
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn breadcrumbs(name: &str, path: &str) -> String {
+    let mut crumbs = format!("<a href=\"/browse/{}/\">{}</a>", percent_encode(name), escape(name));
+    let mut current = String::new();
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        current = join_path(&current, component);
+        crumbs += &format!(" / <a href=\"/browse/{}/\">{}</a>", percent_encode(&join_path(name, &current)), escape(component));
+    }
+    crumbs
+}
+
+fn page(title: &str, body: &str) -> Response<io::Cursor<Vec<u8>>> {
+    let html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{} - resilient</title><style>{}</style></head>\
+        <body><h1>{}</h1>{}</body></html>",
+        escape(title), STYLE, escape(title), body,
+    );
+    Response::from_string(html).with_header(header("Content-Type", "text/html; charset=utf-8"))
+}
+
+pub fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn attachment(file_name: &str) -> String {
+    let quoted: String = file_name.chars().map(|c| if c == '"' || c == '\\' || c.is_control() { '_' } else { c }).collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", quoted, percent_encode(file_name))
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded += &format!("%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = text.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}